@@ -34,7 +34,10 @@ use flate2::read::GzDecoder;
 use openssl::{stack::Stack, x509::X509};
 use relayd::data::node::NodesList;
 use relayd::{
-    configuration::{main::DatabaseConfig, Secret},
+    configuration::{
+        main::{DatabaseConfig, RunlogValidation},
+        Secret,
+    },
     data::{report::QueryableReport, RunInfo, RunLog},
     input::signature,
     output::database::{schema::ruddersysevents::dsl::*, *},
@@ -85,6 +88,41 @@ fn bench_parse_runlog(c: &mut Criterion) {
     });
 }
 
+// Compares memory-bounded parsing by chunks with whole parsing on a large runlog
+fn bench_parse_large_runlog(c: &mut Criterion) {
+    let runlog = read_to_string(
+        "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+    )
+    .unwrap();
+    // Repeat the reports following the start control report
+    let mut lines = runlog.splitn(2, '\n');
+    let start = lines.next().unwrap();
+    let large_runlog = format!("{}\n{}", start, lines.next().unwrap().repeat(100));
+    let info =
+        RunInfo::from_str("2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log")
+            .unwrap();
+
+    let whole_runlog = large_runlog.clone();
+    let whole_info = info.clone();
+    c.bench_function("parse large runlog", move |b| {
+        b.iter(|| black_box(RunLog::try_from((whole_info.clone(), whole_runlog.as_ref())).unwrap()))
+    });
+    c.bench_function("parse large runlog by chunks", move |b| {
+        b.iter(|| {
+            black_box(
+                RunLog::chunks(
+                    info.clone(),
+                    large_runlog.as_bytes(),
+                    1_000,
+                    RunlogValidation::Keep,
+                )
+                .map(|chunk| chunk.unwrap().reports.len())
+                .sum::<usize>(),
+            )
+        })
+    });
+}
+
 fn bench_signature_runlog(c: &mut Criterion) {
     let data = read("tests/files/smime/normal.signed").unwrap();
 
@@ -96,10 +134,11 @@ fn bench_signature_runlog(c: &mut Criterion) {
     .unwrap();
     let mut certs = Stack::new().unwrap();
     certs.push(x509).unwrap();
+    let allowed_digests = ["sha256".to_string()].iter().cloned().collect();
 
     c.bench_function("verify runlog signature", move |b| {
         b.iter(|| {
-            black_box(signature(&data, &certs).unwrap());
+            black_box(signature(&data, &certs, &allowed_digests).unwrap());
         })
     });
 }
@@ -123,6 +162,8 @@ pub fn db() -> PgPool {
         url: "postgres://rudderreports@127.0.0.1/rudder".to_string(),
         password: Secret::new("PASSWORD".to_string()),
         max_pool_size: 10,
+        max_batch_size: 100,
+        batch_window: 500,
     };
     pg_pool(&db_config).unwrap()
 }
@@ -168,6 +209,7 @@ criterion_group!(
     bench_uncompress_runlog,
     bench_signature_runlog,
     bench_parse_runlog,
+    bench_parse_large_runlog,
     bench_insert_runlog
 );
 criterion_main!(benches);
@@ -28,36 +28,45 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
+mod failed;
+mod https;
 mod remote_run;
 mod shared_files;
 mod shared_folder;
 mod system;
+mod upload;
 
 use crate::{
     api::{
-        remote_run::{RemoteRun, RemoteRunTarget},
-        shared_files::{SharedFilesHeadParams, SharedFilesPutParams},
+        remote_run::{cancel_job, get_job, RemoteRun, RemoteRunTarget},
+        shared_files::{SharedFilesHeadParams, SharedFilesPutParams, NODE_ID_HEADER},
         shared_folder::SharedFolderParams,
-        system::{Info, Status},
+        system::{Info, Reload, RetryQueue, Status},
     },
+    configuration::main::HttpsConfig,
+    data::node::NodeId,
     error::Error,
+    processing::{failed::FailedKind, inventory::InventoryType},
     stats::Stats,
     JobConfig,
 };
-use futures::Future;
+use futures::{stream, Future, Stream};
+use prometheus::{Encoder, TextEncoder};
 use serde::Serialize;
 use std::{
     collections::HashMap,
     fmt::Display,
+    io,
     net::SocketAddr,
     path::PathBuf,
     sync::{Arc, RwLock},
 };
-use tracing::{error, info, span, Level};
+use tokio::net::TcpListener;
+use tracing::{debug, error, info, span, warn, Level};
 use warp::{
-    body::{self, FullBody},
-    filters::{method::v2::*, path::Peek},
-    fs,
+    body::{self, BodyStream},
+    filters::{method::v2::*, path::Peek, BoxedFilter},
+    fs, header,
     http::StatusCode,
     path, query,
     reject::custom,
@@ -120,6 +129,24 @@ impl<T: Serialize> ApiResponse<T> {
     }
 }
 
+/// Client of the API
+#[derive(Clone, Debug)]
+enum Caller {
+    /// Behind the reverse proxy, which authenticates nodes, passes their id in a
+    /// header and restricts access to remote run
+    Proxied(Option<NodeId>),
+    /// On the HTTPS listener, identified by its client certificate
+    Authenticated(Option<NodeId>),
+}
+
+impl Caller {
+    fn node_id(self) -> Option<NodeId> {
+        match self {
+            Caller::Proxied(id) | Caller::Authenticated(id) => id,
+        }
+    }
+}
+
 pub fn run(
     listen: SocketAddr,
     job_config: Arc<JobConfig>,
@@ -128,8 +155,70 @@ pub fn run(
     let span = span!(Level::TRACE, "api");
     let _enter = span.enter();
 
-    // WARNING: Not stable, will be replaced soon
-    // Kept for testing mainly
+    let routes = routes(job_config.clone(), stats);
+
+    info!("Starting API on {}", listen);
+    // Stop accepting connections on shutdown, and let pending requests complete
+    let (_addr, server) =
+        warp::serve(routes).bind_with_graceful_shutdown(listen, job_config.shutdown.signal());
+    server
+}
+
+/// Serves the API over HTTPS, identifying nodes by their client certificate
+///
+/// The returned future accepts connections until it is dropped, running ones
+/// are tracked for graceful shutdown.
+pub fn run_https(
+    cfg: HttpsConfig,
+    job_config: Arc<JobConfig>,
+) -> Result<impl Future<Item = (), Error = ()>, Error> {
+    let span = span!(Level::TRACE, "api");
+    let _enter = span.enter();
+
+    let acceptor = Arc::new(https::acceptor(&cfg, job_config.clone())?);
+    let listener = TcpListener::bind(&cfg.listen)?;
+
+    info!("Starting HTTPS API on {}", cfg.listen);
+    Ok(listener
+        .incoming()
+        // Keep accepting connections after an error
+        .then(|res| -> Result<_, ()> { Ok(res.map_err(|e| warn!("connection error: {}", e)).ok()) })
+        .filter_map(|socket| socket)
+        .for_each(move |socket| {
+            let job_config_connection = job_config.clone();
+            let connection = https::accept(acceptor.clone(), socket)
+                .map_err(|e| debug!("TLS handshake failed: {}", e))
+                .and_then(move |stream| {
+                    let caller = Caller::Authenticated(stream.node_id());
+                    // Only what nodes need, the rest of the API stays behind the reverse proxy
+                    let routes = path("rudder")
+                        .and(path("relay-api"))
+                        .and(path("1"))
+                        .and(node_routes(
+                            job_config_connection,
+                            warp::any().map(move || caller.clone()).boxed(),
+                        ))
+                        .recover(customize_error)
+                        .with(warp::log("relayd::relay-api"));
+                    // A server per connection, so that routes know the client identity
+                    warp::serve(routes).serve_incoming(stream::once(Ok::<_, io::Error>(stream)))
+                });
+            tokio::spawn(job_config.shutdown.track(connection));
+            Ok(())
+        }))
+}
+
+/// Whole API, served behind the reverse proxy
+fn routes(
+    job_config: Arc<JobConfig>,
+    stats: Arc<RwLock<Stats>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static {
+    let caller = header::optional::<String>(NODE_ID_HEADER)
+        .map(Caller::Proxied)
+        .boxed();
+
+    // Deprecated, use /metrics instead
+    // Kept for compatibility
     let stats = get()
         .and(path("stats"))
         .map(move || reply::json(&(*stats.clone().read().expect("open stats database"))));
@@ -141,9 +230,9 @@ pub fn run(
 
     let job_config0 = job_config.clone();
     let reload = post().and(path("reload")).map(move || {
-        ApiResponse::<()>::new::<Error>(
+        ApiResponse::new::<Error>(
             "reloadConfiguration",
-            job_config0.clone().reload().map(|_| None),
+            job_config0.clone().reload().map(|r| Some(Reload::new(r))),
             None,
         )
         .reply()
@@ -159,6 +248,65 @@ pub fn run(
         .reply()
     });
 
+    let job_config8 = job_config.clone();
+    let retry_queue = get().and(path("retry-queue")).map(move || {
+        ApiResponse::new::<Error>(
+            "getRetryQueue",
+            RetryQueue::poll(job_config8.clone()).map(Some),
+            None,
+        )
+        .reply()
+    });
+
+    // Files that could not be processed
+    let job_config14 = job_config.clone();
+    let failed_list = get()
+        .and(path::param::<FailedKind>())
+        .and(path::end())
+        .map(move |kind| failed::list(kind, job_config14.clone()).reply());
+
+    let job_config15 = job_config.clone();
+    let failed_get = get()
+        .and(path::param::<FailedKind>())
+        .and(path::param::<String>())
+        .and(path::end())
+        .and_then(move |kind, name| {
+            failed::get(kind, name, job_config15.clone()).map_err(|e| {
+                error!("{}", e);
+                warp::reject::custom(e)
+            })
+        });
+
+    let job_config16 = job_config.clone();
+    let failed_delete = delete()
+        .and(path::param::<FailedKind>())
+        .and(path::param::<String>())
+        .and(path::end())
+        .map(move |kind, name| failed::delete(kind, name, job_config16.clone()).reply());
+
+    let job_config17 = job_config.clone();
+    let failed_requeue = post()
+        .and(path::param::<FailedKind>())
+        .and(path::param::<String>())
+        .and(path("requeue"))
+        .and(path::end())
+        .map(move |kind, name| failed::requeue(kind, name, job_config17.clone()).reply());
+
+    // Prometheus metrics, outside of the versioned API
+    let job_config9 = job_config.clone();
+    let metrics = get().and(path("metrics")).and(path::end()).map(move || {
+        match job_config9.metrics.render(&job_config9) {
+            Ok(text) => reply::with_status(
+                reply::with_header(text, "content-type", TextEncoder::new().format_type()),
+                StatusCode::OK,
+            ),
+            Err(e) => reply::with_status(
+                reply::with_header(e.to_string(), "content-type", "text/plain"),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        }
+    });
+
     // Old compatible endpoints
 
     let job_config2 = job_config.clone();
@@ -210,29 +358,83 @@ pub fn run(
         },
     );
 
+    let job_config18 = job_config.clone();
+    let remote_run_job = get()
+        .and(path("jobs"))
+        .and(path::param::<String>())
+        .and(path::end())
+        .map(move |id: String| get_job(&id, job_config18.clone()).reply());
+
+    let job_config19 = job_config.clone();
+    let remote_run_job_cancel = delete()
+        .and(path("jobs"))
+        .and(path::param::<String>())
+        .and(path::end())
+        .map(move |id: String| cancel_job(&id, job_config19.clone()).reply());
+
+    // Routing
+    // // /api/ for public API, /relay-api/ for internal relay API
+    let base = path("rudder").and(path("relay-api"));
+    let failed_files = path("failed").and(
+        failed_list
+            .or(failed_get)
+            .or(failed_delete)
+            .or(failed_requeue),
+    );
+    let system = path("system").and(
+        stats
+            .or(status)
+            .or(reload)
+            .or(info)
+            .or(retry_queue)
+            .or(failed_files),
+    );
+    let remote_run = path("remote-run").and(
+        nodes
+            .or(all)
+            .or(node_id)
+            .or(remote_run_job)
+            .or(remote_run_job_cancel),
+    );
+
+    // Global route for /1/ and /metrics
+    base.and(path("1"))
+        .and(system.or(remote_run).or(node_routes(job_config, caller)))
+        .or(metrics)
+        .recover(customize_error)
+        .with(warp::log("relayd::relay-api"))
+}
+
+/// Routes used by nodes, the only ones served on the HTTPS listener
+fn node_routes(
+    job_config: Arc<JobConfig>,
+    caller: BoxedFilter<(Caller,)>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static {
     let job_config5 = job_config.clone();
     let shared_files_put = put()
         .and(path::param::<String>())
         .and(path::param::<String>())
         .and(path::param::<String>())
         .and(query::<SharedFilesPutParams>())
-        .and(body::concat())
-        .map(
-            move |target_id, source_id, file_id, params: SharedFilesPutParams, buf: FullBody| {
-                reply::with_status(
-                    "".to_string(),
-                    match shared_files::put(
-                        target_id,
-                        source_id,
-                        file_id,
-                        params,
-                        job_config5.clone(),
-                        buf,
-                    ) {
-                        Ok(x) => x,
-                        Err(_x) => StatusCode::INTERNAL_SERVER_ERROR,
-                    },
+        .and(caller.clone())
+        .and(body::stream())
+        .and_then(
+            move |target_id,
+                  source_id,
+                  file_id,
+                  params: SharedFilesPutParams,
+                  caller: Caller,
+                  body: BodyStream| {
+                shared_files::put(
+                    target_id,
+                    source_id,
+                    file_id,
+                    params,
+                    caller.node_id(),
+                    job_config5.clone(),
+                    body.map_err(Error::from),
                 )
+                .then(reply_status)
             },
         );
 
@@ -253,6 +455,80 @@ pub fn run(
             )
         });
 
+    let job_config10 = job_config.clone();
+    let shared_files_get = get()
+        .and(path::param::<String>())
+        .and(path::param::<String>())
+        .and(path::param::<String>())
+        .and(caller.clone())
+        .and_then(move |target_id, source_id, file_id, caller: Caller| {
+            shared_files::get(
+                target_id,
+                source_id,
+                file_id,
+                caller.node_id(),
+                job_config10.clone(),
+            )
+            .map_err(|e| {
+                error!("{}", e);
+                warp::reject::custom(e)
+            })
+        });
+
+    // Reports and inventories, replacing WebDAV
+    let job_config11 = job_config.clone();
+    let reports_put = put()
+        .and(path("reports"))
+        .and(path::param::<String>())
+        .and(path::end())
+        .and(caller.clone())
+        .and(body::stream())
+        .and_then(move |file_name, caller: Caller, body: BodyStream| {
+            upload::put_report(
+                file_name,
+                caller.node_id(),
+                job_config11.clone(),
+                body.map_err(Error::from),
+            )
+            .then(reply_status)
+        });
+
+    let job_config12 = job_config.clone();
+    let inventories_put = put()
+        .and(path("inventories"))
+        .and(path::param::<String>())
+        .and(path::end())
+        .and(caller.clone())
+        .and(body::stream())
+        .and_then(move |file_name, caller: Caller, body: BodyStream| {
+            upload::put_inventory(
+                InventoryType::New,
+                file_name,
+                caller.node_id(),
+                job_config12.clone(),
+                body.map_err(Error::from),
+            )
+            .then(reply_status)
+        });
+
+    let job_config13 = job_config.clone();
+    let inventory_updates_put = put()
+        .and(path("inventory-updates"))
+        .and(path::param::<String>())
+        .and(path::end())
+        .and(caller.clone())
+        .and(body::stream())
+        .and_then(move |file_name, caller: Caller, body: BodyStream| {
+            upload::put_inventory(
+                InventoryType::Update,
+                file_name,
+                caller.node_id(),
+                job_config13.clone(),
+                body.map_err(Error::from),
+            )
+            .then(reply_status)
+        });
+
     let job_config7 = job_config.clone();
     let shared_folder_head = head()
         .and(path::peek())
@@ -265,26 +541,28 @@ pub fn run(
                     warp::reject::custom(e)
                 })
         });
-    let shared_folder_get = fs::dir(job_config.cfg.shared_folder.path.clone());
+    let shared_folder_get = fs::dir(job_config.cfg().shared_folder.path.clone());
 
-    // Routing
-    // // /api/ for public API, /relay-api/ for internal relay API
-    let base = path("rudder").and(path("relay-api"));
-    let system = path("system").and(stats.or(status).or(reload).or(info));
-    let remote_run = path("remote-run").and(nodes.or(all).or(node_id));
-    let shared_files = path("shared-files").and((shared_files_put).or(shared_files_head));
+    let shared_files =
+        path("shared-files").and(shared_files_put.or(shared_files_head).or(shared_files_get));
     let shared_folder = path("shared-folder").and(shared_folder_head.or(shared_folder_get));
+    let uploads = reports_put.or(inventories_put).or(inventory_updates_put);
 
-    // Global route for /1/
-    let routes_1 = base
-        .and(path("1"))
-        .and(system.or(remote_run).or(shared_files).or(shared_folder))
-        .recover(customize_error)
-        .with(warp::log("relayd::relay-api"));
+    shared_files.or(shared_folder).or(uploads)
+}
 
-    info!("Starting API on {}", listen);
-    // TODO graceful shutdown
-    warp::serve(routes_1).bind(listen)
+/// Replies with an empty body, errors are logged and hidden to the client
+fn reply_status(res: Result<StatusCode, Error>) -> Result<impl Reply, Rejection> {
+    Ok(reply::with_status(
+        "".to_string(),
+        match res {
+            Ok(x) => x,
+            Err(e) => {
+                error!("{}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        },
+    ))
 }
 
 fn customize_error(reject: Rejection) -> Result<impl Reply, Rejection> {
@@ -0,0 +1,104 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+use crate::{
+    api::ApiResponse,
+    error::Error,
+    processing::failed::{self, FailedFile, FailedKind},
+    JobConfig,
+};
+use bytes::BytesMut;
+use futures::{future, Future, Stream};
+use hyper::Body;
+use std::{io, sync::Arc};
+use tokio::codec::{BytesCodec, FramedRead};
+use warp::http::{Response, StatusCode};
+
+/// Unknown files are not found, other errors are server errors
+fn status<T>(res: &Result<T, Error>) -> Option<StatusCode> {
+    match res {
+        Err(Error::UnknownFailedFile(_)) => Some(StatusCode::NOT_FOUND),
+        _ => None,
+    }
+}
+
+pub fn list(kind: FailedKind, job_config: Arc<JobConfig>) -> ApiResponse<Vec<FailedFile>> {
+    ApiResponse::new(
+        "listFailedFiles",
+        failed::list(&kind.directory(&job_config.cfg()), kind).map(Some),
+        None,
+    )
+}
+
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
+        .expect("could not build response")
+}
+
+pub fn get(
+    kind: FailedKind,
+    name: String,
+    job_config: Arc<JobConfig>,
+) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+    let file = match failed::path(&kind.directory(&job_config.cfg()), kind, &name) {
+        Ok(file) => file,
+        Err(Error::UnknownFailedFile(_)) => return Box::new(future::ok(not_found())),
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    Box::new(tokio::fs::File::open(file).then(|res| {
+        match res {
+            Ok(file) => Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", "application/octet-stream")
+                .body(Body::wrap_stream(
+                    FramedRead::new(file, BytesCodec::new()).map(BytesMut::freeze),
+                ))
+                .expect("could not build response")),
+            // Removed since the check
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(not_found()),
+            Err(e) => Err(e.into()),
+        }
+    }))
+}
+
+pub fn delete(kind: FailedKind, name: String, job_config: Arc<JobConfig>) -> ApiResponse<()> {
+    let res = failed::delete(&kind.directory(&job_config.cfg()), kind, &name).map(|_| None);
+    let status = status(&res);
+    ApiResponse::new("deleteFailedFile", res, status)
+}
+
+/// Sends the file back to processing, in the directory it was received in
+pub fn requeue(kind: FailedKind, name: String, job_config: Arc<JobConfig>) -> ApiResponse<()> {
+    let res = failed::requeue(&kind.directory(&job_config.cfg()), kind, &name).map(|_| None);
+    let status = status(&res);
+    ApiResponse::new("requeueFailedFile", res, status)
+}
@@ -0,0 +1,249 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+//! TLS on top of non-blocking sockets, for the HTTPS listener of the API
+
+use crate::{
+    configuration::main::HttpsConfig,
+    data::node::{NodeId, NodesList},
+    error::Error,
+    JobConfig,
+};
+use futures::{Async, Future, Poll};
+use openssl::ssl::{
+    ErrorCode, HandshakeError, MidHandshakeSslStream, SslAcceptor, SslFiletype, SslMethod,
+    SslStream, SslVerifyMode,
+};
+use std::{
+    io::{self, Read, Write},
+    mem,
+    sync::Arc,
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tracing::debug;
+
+/// Builds the TLS configuration of the listener
+///
+/// Client certificates are checked against the current nodes list, so that
+/// reloading it is enough to take new nodes into account.
+pub fn acceptor(cfg: &HttpsConfig, job_config: Arc<JobConfig>) -> Result<SslAcceptor, Error> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_certificate_chain_file(&cfg.certificate)?;
+    builder.set_private_key_file(&cfg.private_key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+
+    let mode = if cfg.require_client_certificate {
+        SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT
+    } else {
+        SslVerifyMode::PEER
+    };
+    builder.set_verify_callback(mode, move |_preverify, context| {
+        // Node certificates are self-signed, so the chain verification done by
+        // openssl is not relevant, only the peer certificate is checked
+        if context.error_depth() != 0 {
+            return true;
+        }
+        let known = context
+            .current_cert()
+            .map(|cert| {
+                job_config
+                    .nodes
+                    .read()
+                    .expect("Cannot read nodes list")
+                    .is_known_certificate(cert)
+            })
+            .unwrap_or(false);
+        if !known {
+            debug!("Refusing unknown client certificate");
+        }
+        known
+    });
+    Ok(builder.build())
+}
+
+/// Starts the server side of the handshake on the given connection
+pub fn accept<S: Read + Write>(acceptor: Arc<SslAcceptor>, stream: S) -> Handshake<S> {
+    Handshake {
+        acceptor,
+        state: HandshakeState::Start(stream),
+    }
+}
+
+enum HandshakeState<S> {
+    Start(S),
+    InProgress(MidHandshakeSslStream<S>),
+    Done,
+}
+
+pub struct Handshake<S> {
+    acceptor: Arc<SslAcceptor>,
+    state: HandshakeState<S>,
+}
+
+impl<S: Read + Write> Future for Handshake<S> {
+    type Item = TlsStream<S>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let res = match mem::replace(&mut self.state, HandshakeState::Done) {
+            HandshakeState::Start(stream) => self.acceptor.accept(stream),
+            HandshakeState::InProgress(stream) => stream.handshake(),
+            HandshakeState::Done => panic!("handshake polled after completion"),
+        };
+        match res {
+            Ok(stream) => Ok(Async::Ready(TlsStream(stream))),
+            // The underlying socket registered the task for wakeup
+            Err(HandshakeError::WouldBlock(stream)) => {
+                self.state = HandshakeState::InProgress(stream);
+                Ok(Async::NotReady)
+            }
+            Err(HandshakeError::Failure(stream)) => {
+                Err(io::Error::new(io::ErrorKind::Other, stream.into_error()))
+            }
+            Err(HandshakeError::SetupFailure(e)) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    }
+}
+
+/// Established TLS connection
+pub struct TlsStream<S>(SslStream<S>);
+
+impl<S> TlsStream<S> {
+    /// Node authenticated by its client certificate, if any
+    pub fn node_id(&self) -> Option<NodeId> {
+        self.0
+            .ssl()
+            .peer_certificate()
+            .and_then(|cert| NodesList::id_from_cert(&cert).ok())
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.0.shutdown() {
+            Ok(_) => (),
+            // Peer already closed the TLS session
+            Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => (),
+            Err(e) => {
+                return match e.into_io_error() {
+                    Ok(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+                    Ok(e) => Err(e),
+                    Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+                };
+            }
+        }
+        self.0.get_mut().shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        asn1::Asn1Time,
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::PKey,
+        rsa::Rsa,
+        ssl::SslConnector,
+        x509::{X509NameBuilder, X509},
+    };
+    use std::{os::unix::net::UnixStream as StdUnixStream, thread};
+    use tokio::{io, net::UnixStream, reactor::Handle};
+
+    #[test]
+    fn it_serves_tls_connections() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "localhost")
+            .unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert.build()).unwrap();
+        let acceptor = Arc::new(acceptor.build());
+
+        let (server, client) = StdUnixStream::pair().unwrap();
+        let server = UnixStream::from_std(server, &Handle::default()).unwrap();
+
+        let client = thread::spawn(move || {
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            let mut stream = connector.build().connect("localhost", client).unwrap();
+            stream.write_all(b"ping").unwrap();
+            let mut response = vec![];
+            stream.read_to_end(&mut response).unwrap();
+            response
+        });
+
+        tokio::run(
+            futures::future::lazy(move || accept(acceptor, server))
+                .and_then(|stream| {
+                    assert_eq!(stream.node_id(), None);
+                    io::read_exact(stream, [0; 4])
+                })
+                .and_then(|(stream, request)| {
+                    assert_eq!(&request, b"ping");
+                    io::write_all(stream, b"pong")
+                })
+                .and_then(|(stream, _)| io::shutdown(stream))
+                .map(|_| ())
+                .map_err(|e| panic!("{}", e)),
+        );
+        assert_eq!(client.join().unwrap(), b"pong");
+    }
+}
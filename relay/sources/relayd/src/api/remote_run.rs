@@ -29,9 +29,18 @@
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{
-    configuration::main::RemoteRun as RemoteRunCfg, data::node::Host, error::Error, JobConfig,
+    api::ApiResponse,
+    configuration::main::RemoteRun as RemoteRunCfg,
+    data::{
+        node::Host,
+        remote_run::{Job, JobId, Jobs},
+    },
+    error::Error,
+    output::upstream::with_request_timeout,
+    JobConfig,
 };
-use futures::{Future, Stream};
+use chrono::{Duration, Utc};
+use futures::{sync::oneshot, Future, Stream};
 use hyper::{Body, Chunk};
 use regex::Regex;
 use reqwest::r#async::multipart::Form;
@@ -43,7 +52,11 @@ use std::{
     sync::Arc,
 };
 use tokio_process::{Child, CommandExt};
-use tracing::{debug, error, span, trace, Level};
+use tracing::{debug, error, span, trace, warn, Level};
+use warp::http::StatusCode;
+
+/// Id of the job created for each remote run request, to poll its results
+pub const JOB_ID_HEADER: &str = "X-Rudder-Remote-Run-Job-Id";
 
 // From futures_stream_select_all crate (https://github.com/swizard0/futures-stream-select-all)
 // Will be in future versions of futures
@@ -131,6 +144,19 @@ impl RemoteRun {
             "Starting remote run (asynchronous: {}, keep_output: {})",
             self.run_parameters.asynchronous, self.run_parameters.keep_output
         );
+        let neighbors = self.target.neighbors(job_config.clone());
+        let next_hops = self.target.next_hops(job_config.clone());
+        job_config.metrics.remote_run(
+            neighbors
+                .iter()
+                .chain(next_hops.iter().map(|(relay, _)| relay)),
+        );
+
+        let job_id = job_config
+            .remote_runs
+            .create(job_ttl(&job_config), Utc::now());
+        debug!("Remote run job: {}", job_id);
+
         match (
             self.run_parameters.asynchronous,
             self.run_parameters.keep_output,
@@ -139,32 +165,43 @@ impl RemoteRun {
             (true, true) => Ok(warp::reply::html(Body::wrap_stream(
                 self.run_parameters
                     .remote_run(
-                        &job_config.cfg.remote_run,
-                        self.target.neighbors(job_config.clone()),
+                        &job_config.cfg().remote_run,
+                        neighbors,
                         self.run_parameters.asynchronous,
+                        &job_config.remote_runs,
+                        &job_id,
                     )
-                    .select(select_all(
-                        self.target
-                            .next_hops(job_config.clone())
-                            .iter()
-                            .map(|(relay, target)| {
-                                self.forward_call(job_config.clone(), relay.clone(), target.clone())
-                            }),
-                    )),
+                    .select(select_all(next_hops.iter().map(|(relay, target)| {
+                        self.forward_call(
+                            job_config.clone(),
+                            relay.clone(),
+                            target.clone(),
+                            &job_id,
+                        )
+                    }))),
             ))),
             // Async and no output -> spawn in background and return early
             (true, false) => {
-                for (relay, target) in self.target.next_hops(job_config.clone()) {
-                    tokio::spawn(RemoteRun::consume(self.forward_call(
-                        job_config.clone(),
-                        relay,
-                        target,
-                    )));
+                for (relay, target) in next_hops {
+                    tokio::spawn(
+                        job_config
+                            .shutdown
+                            .track(RemoteRun::consume(self.forward_call(
+                                job_config.clone(),
+                                relay,
+                                target,
+                                &job_id,
+                            ))),
+                    );
                 }
-                tokio::spawn(RemoteRun::consume(self.run_parameters.remote_run(
-                    &job_config.cfg.remote_run,
-                    self.target.neighbors(job_config.clone()),
-                    self.run_parameters.asynchronous,
+                tokio::spawn(job_config.shutdown.track(RemoteRun::consume(
+                    self.run_parameters.remote_run(
+                        &job_config.cfg().remote_run,
+                        neighbors,
+                        self.run_parameters.asynchronous,
+                        &job_config.remote_runs,
+                        &job_id,
+                    ),
                 )));
 
                 Ok(warp::reply::html(Body::empty()))
@@ -173,38 +210,47 @@ impl RemoteRun {
             (false, false) => Ok(warp::reply::html(Body::wrap_stream(
                 self.run_parameters
                     .remote_run(
-                        &job_config.cfg.remote_run,
-                        self.target.neighbors(job_config.clone()),
+                        &job_config.cfg().remote_run,
+                        neighbors,
                         self.run_parameters.asynchronous,
+                        &job_config.remote_runs,
+                        &job_id,
                     )
                     .map(|_| Chunk::from(""))
-                    .select(select_all(
-                        self.target
-                            .next_hops(job_config.clone())
-                            .iter()
-                            .map(|(relay, target)| {
-                                self.forward_call(job_config.clone(), relay.clone(), target.clone())
-                            }),
-                    )),
+                    .select(select_all(next_hops.iter().map(|(relay, target)| {
+                        self.forward_call(
+                            job_config.clone(),
+                            relay.clone(),
+                            target.clone(),
+                            &job_id,
+                        )
+                    }))),
             ))),
             // Sync and output -> wait until the end and return output
             (false, true) => Ok(warp::reply::html(Body::wrap_stream(
                 self.run_parameters
                     .remote_run(
-                        &job_config.cfg.remote_run,
-                        self.target.neighbors(job_config.clone()),
+                        &job_config.cfg().remote_run,
+                        neighbors,
                         self.run_parameters.asynchronous,
+                        &job_config.remote_runs,
+                        &job_id,
                     )
-                    .select(select_all(
-                        self.target
-                            .next_hops(job_config.clone())
-                            .iter()
-                            .map(|(relay, target)| {
-                                self.forward_call(job_config.clone(), relay.clone(), target.clone())
-                            }),
-                    )),
+                    .select(select_all(next_hops.iter().map(|(relay, target)| {
+                        self.forward_call(
+                            job_config.clone(),
+                            relay.clone(),
+                            target.clone(),
+                            &job_id,
+                        )
+                    }))),
             ))),
         }
+        .map(|reply| {
+            // All runs were added while building the reply
+            job_config.remote_runs.close(&job_id);
+            warp::reply::with_header(reply, JOB_ID_HEADER, job_id.as_str())
+        })
     }
 
     fn forward_call(
@@ -213,7 +259,8 @@ impl RemoteRun {
         node: Host,
         // Target for the sub relay
         target: RemoteRunTarget,
-    ) -> impl Stream<Item = Chunk, Error = Error> + Send + 'static {
+        job_id: &str,
+    ) -> Box<dyn Stream<Item = Chunk, Error = Error> + Send + 'static> {
         let report_span = span!(Level::TRACE, "upstream");
         let _report_enter = report_span.enter();
 
@@ -237,32 +284,147 @@ impl RemoteRun {
             form = form.text("nodes", nodes.join(","))
         }
 
-        job_config
-            .client
-            .clone()
-            .post(&format!(
-                "{}/rudder/relay-api/{}",
-                node,
-                match target {
-                    RemoteRunTarget::All => "all",
-                    RemoteRunTarget::Nodes(_) => "nodes",
-                },
-            ))
-            .multipart(form)
-            .send()
-            .map(|response| response.into_body())
+        let run = match job_config.remote_runs.add_run(
+            job_id,
+            Some(node.clone()),
+            match &target {
+                RemoteRunTarget::All => vec![],
+                RemoteRunTarget::Nodes(nodes) => nodes.clone(),
+            },
+            Utc::now(),
+        ) {
+            Ok(run) => run,
+            Err(e) => return Box::new(futures::stream::once(Err(e))),
+        };
+        let run_status = run.clone();
+        let run_error = run.clone();
+        let job_config_cancel = job_config.clone();
+
+        // Spawned to get the sub-relay job id even if the job is cancelled
+        // before the response, the body is dropped along with the receiver
+        let (response_tx, response_rx) = oneshot::channel();
+        tokio::spawn(
+            job_config
+                .client()
+                .post(&format!(
+                    "{}/rudder/relay-api/{}",
+                    node,
+                    match target {
+                        RemoteRunTarget::All => "all",
+                        RemoteRunTarget::Nodes(_) => "nodes",
+                    },
+                ))
+                .multipart(form)
+                .send()
+                .map(move |response| {
+                    run_status.set_http_status(response.status().as_u16());
+                    let relay_job_id = response
+                        .headers()
+                        .get(JOB_ID_HEADER)
+                        .and_then(|id| id.to_str().ok());
+                    if let Some(relay_job_id) = relay_job_id {
+                        if run_status.set_relay_job_id(relay_job_id.to_string()) {
+                            tokio::spawn(cancel_relay_job(
+                                &job_config_cancel,
+                                node,
+                                relay_job_id.to_string(),
+                            ));
+                        }
+                    }
+                    response
+                })
+                .then(move |res| response_tx.send(res).map_err(|_| ())),
+        );
+
+        let output = response_rx
+            .then(|res| match res {
+                Ok(res) => res.map_err(Error::from),
+                Err(_) => Err(Error::ProcessingStopped),
+            })
+            .map(|response| response.into_body().map_err(Error::from))
             .flatten_stream()
-            .map_err(|e| {
+            .map_err(move |e| {
                 error!("{}", e);
-                e.into()
+                run_error.fail(&e, Utc::now());
+                e
             })
             // Don't fail if a relay is not available,
             // just log it
             .or_else(|_: Error| futures::future::empty())
-            .map(|c| c.into())
+            .map(|c| c.into());
+        Box::new(run.record(output))
+    }
+}
+
+/// Cancels the job created on a sub-relay for a forwarded remote run
+fn cancel_relay_job(
+    job_config: &JobConfig,
+    relay: Host,
+    relay_job_id: JobId,
+) -> impl Future<Item = (), Error = ()> {
+    debug!("Cancelling remote run job {} on {}", relay_job_id, relay);
+    let request = job_config
+        .client()
+        .delete(&format!(
+            "{}/rudder/relay-api/remote-run/jobs/{}",
+            relay, relay_job_id
+        ))
+        .send()
+        .map_err(Error::from);
+    with_request_timeout(job_config, request)
+        .map_err(|e| e.to_string())
+        .and_then(|response| {
+            if response.status().is_success() {
+                Ok(())
+            } else {
+                Err(format!("received {}", response.status()))
+            }
+        })
+        .map_err(move |e| {
+            warn!(
+                "could not cancel remote run job {} on {}: {}",
+                relay_job_id, relay, e
+            )
+        })
+}
+
+fn job_ttl(job_config: &JobConfig) -> Duration {
+    Duration::seconds(job_config.cfg().remote_run.job_ttl as i64)
+}
+
+/// Unknown or expired jobs are not found, other errors are server errors
+fn job_status(res: &Result<Option<Job>, Error>) -> Option<StatusCode> {
+    match res {
+        Err(Error::UnknownRemoteRunJob(_)) => Some(StatusCode::NOT_FOUND),
+        _ => None,
     }
 }
 
+/// Status and results of a remote run job
+pub fn get_job(id: &str, job_config: Arc<JobConfig>) -> ApiResponse<Job> {
+    let res = job_config
+        .remote_runs
+        .get(id, job_ttl(&job_config), Utc::now())
+        .map(Some);
+    let status = job_status(&res);
+    ApiResponse::new("getRemoteRunJob", res, status)
+}
+
+/// Stops the running parts of a remote run job, including the jobs forwarded to sub-relays
+pub fn cancel_job(id: &str, job_config: Arc<JobConfig>) -> ApiResponse<Job> {
+    let res = job_config
+        .remote_runs
+        .cancel(id, Utc::now())
+        .map(|(job, relay_jobs)| {
+            for (relay, relay_job_id) in relay_jobs {
+                tokio::spawn(cancel_relay_job(&job_config, relay, relay_job_id));
+            }
+            Some(job)
+        });
+    let status = job_status(&res);
+    ApiResponse::new("cancelRemoteRunJob", res, status)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RemoteRunTarget {
     All,
@@ -401,6 +563,8 @@ impl RunParameters {
         cfg: &RemoteRunCfg,
         nodes: Vec<String>,
         asynchronous: bool,
+        jobs: &Jobs,
+        job_id: &str,
     ) -> Box<dyn Stream<Item = Chunk, Error = Error> + Send + 'static> {
         trace!("Starting local remote run on {:#?} with {:#?}", nodes, cfg);
 
@@ -409,27 +573,49 @@ impl RunParameters {
             return Box::new(futures::stream::empty());
         }
 
+        let run = match jobs.add_run(job_id, None, nodes.clone(), Utc::now()) {
+            Ok(run) => run,
+            Err(e) => return Box::new(futures::stream::once(Err(e))),
+        };
+        let run_status = run.clone();
         let mut cmd = self.command(cfg, nodes);
         cmd.stdout(Stdio::piped());
 
+        // Dropping the child process kills it, which happens when the job is cancelled
         match (asynchronous, cmd.spawn_async()) {
             (false, Ok(c)) => Box::new(
-                // send output at once
-                c.wait_with_output()
-                    .map(|o| o.stdout)
-                    .map(Chunk::from)
-                    .map_err(|e| e.into())
-                    .into_stream(),
+                run.record(
+                    // send output at once
+                    c.wait_with_output()
+                        .map(move |o| {
+                            run_status.set_exit_code(o.status.code());
+                            o.stdout
+                        })
+                        .map(Chunk::from)
+                        .map_err(Error::from)
+                        .into_stream(),
+                ),
             ),
             (true, Ok(mut c)) => {
                 // stream lines
                 let lines = RunParameters::lines_stream(&mut c);
-                tokio::spawn(c.map(|_| ()).map_err(|_| ()));
-                Box::new(lines)
+                tokio::spawn(
+                    c.map(move |status| {
+                        run_status.set_exit_code(status.code());
+                        run_status.finish(Utc::now());
+                    })
+                    .map_err(|e| error!("Remote run error: {}", e))
+                    .select(run.cancelled())
+                    .map(|_| ())
+                    .map_err(|_| ()),
+                );
+                Box::new(run.record(lines))
             }
             (_, Err(e)) => {
                 error!("Remote run error while running '{:#?}': {}", cmd, e);
-                Box::new(futures::stream::once(Err(e.into())))
+                let e = e.into();
+                run.fail(&e, Utc::now());
+                Box::new(futures::stream::once(Err(e)))
             }
         }
     }
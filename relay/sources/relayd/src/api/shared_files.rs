@@ -28,107 +28,72 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::{error::Error, hashing::HashType, JobConfig};
-use bytes::IntoBuf;
-use chrono::{Duration, Utc};
+use crate::{
+    configuration::main::UpstreamConfig,
+    data::{
+        node::{NodeId, NodeIdRef, NodesList},
+        shared_file::Metadata,
+    },
+    error::Error,
+    hashing::HashType,
+    output::upstream::with_request_timeout,
+    JobConfig,
+};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Duration, Utc};
+use futures::{
+    future::{self, loop_fn, poll_fn, Loop},
+    stream, Future, Stream,
+};
 use hex;
+use hyper::Body;
 use openssl::{
-    error::ErrorStack,
     pkey::{PKey, Public},
-    rsa::Rsa,
     sign::Verifier,
 };
+use rand::{thread_rng, Rng};
 use regex::Regex;
 use serde::Deserialize;
 use std::{
-    fmt, fs,
-    io::{BufRead, BufReader, Read},
+    fs, io,
+    path::{Path, PathBuf},
     str,
     str::FromStr,
     sync::Arc,
 };
-use warp::{body::FullBody, http::StatusCode, Buf};
-
-// TODO use tokio-fs
-
-#[derive(Debug)]
-pub struct Metadata {
-    pub header: String,
-    pub algorithm: HashType,
-    pub digest: String,
-    pub hash_value: String,
-    pub short_pubkey: String,
-    pub hostname: String,
-    pub key_date: String,
-    pub key_id: String,
-}
-
-impl fmt::Display for Metadata {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "header={}", &self.header)?;
-        writeln!(f, "algorithm={}", &self.algorithm)?;
-        writeln!(f, "digest={}", &self.digest)?;
-        writeln!(f, "hash_value={}", &self.hash_value)?;
-        writeln!(f, "short_pubkey={}", &self.short_pubkey)?;
-        writeln!(f, "hostname={}", &self.hostname)?;
-        writeln!(f, "keydate={}", &self.key_date)?;
-        writeln!(f, "keyid={}", &self.key_id)
-    }
-}
-
-impl FromStr for Metadata {
-    type Err = Error;
+use tokio::{
+    codec::{BytesCodec, FramedRead},
+    fs::{create_dir_all, remove_file, rename, write},
+    io::write_all,
+};
+use tokio_threadpool::blocking;
+use tracing::{debug, error, span, Level};
+use warp::{
+    http::{Response, StatusCode},
+    Buf,
+};
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if Metadata::parse_value("header", s).unwrap() != "rudder-signature-v1" {
-            return Err(Error::InvalidHeader);
-        }
-        Ok(Metadata {
-            header: Metadata::parse_value("header", s)?,
-            algorithm: HashType::from_str(s)?,
-            digest: Metadata::parse_value("digest", s)?,
-            hash_value: Metadata::parse_value("hash_value", s)?,
-            short_pubkey: Metadata::parse_value("short_pubkey", s)?,
-            hostname: Metadata::parse_value("hostname", s)?,
-            key_date: Metadata::parse_value("keydate", s)?,
-            key_id: Metadata::parse_value("keyid", s)?,
-        })
-    }
-}
+/// Header containing the id of the requesting node, set by the reverse proxy
+/// from the verified client certificate
+pub const NODE_ID_HEADER: &str = "X-Rudder-Node-Id";
 
-impl Metadata {
-    fn parse_value(key: &str, file: &str) -> Result<String, Error> {
-        let regex_key = Regex::new(&format!(r"{}=(?P<key>[^\n]+)\n", key)).unwrap();
+/// Maximum size of the metadata block at the beginning of uploaded files
+const MAX_HEADER_SIZE: usize = 64 * 1024;
 
-        match regex_key.captures(file) {
-            Some(capture) => match capture.name("key") {
-                Some(x) => Ok(x.as_str().to_string()),
-                _ => Err(Error::InvalidHeader),
-            },
-            None => Err(Error::InvalidHeader),
-        }
-    }
-}
+/// Directory, inside the shared files directory, where files are written
+/// while being received
+const UPLOAD_DIRECTORY: &str = ".tmp";
 
-fn validate_signature(
-    file: &[u8],
-    pubkey: PKey<Public>,
+/// Checks the signature of content read by chunks
+fn verify_signature<R: io::Read>(
     hash_type: HashType,
-    digest: &[u8],
-) -> Result<bool, ErrorStack> {
-    let mut verifier = Verifier::new(hash_type.to_openssl_hash(), &pubkey).unwrap();
-    verifier.update(file).unwrap();
-    verifier.verify(digest)
-}
-
-fn get_pubkey(pubkey: &str) -> Result<PKey<Public>, ErrorStack> {
-    PKey::from_rsa(Rsa::public_key_from_pem_pkcs1(
-        format!(
-            "-----BEGIN RSA PUBLIC KEY-----\n{}\n-----END RSA PUBLIC KEY-----\n",
-            pubkey
-        )
-        .as_bytes(),
-    )?)
+    pubkey: &PKey<Public>,
+    signature: &[u8],
+    mut content: R,
+) -> Result<bool, Error> {
+    let mut verifier = Verifier::new(hash_type.to_openssl_hash(), pubkey)?;
+    io::copy(&mut content, &mut verifier)?;
+    Ok(verifier.verify(signature)?)
 }
 
 #[derive(Deserialize, Debug)]
@@ -175,72 +140,342 @@ impl SharedFilesPutParams {
     }
 }
 
-pub fn put(
-    target_id: String,
-    source_id: String,
-    file_id: String,
-    params: SharedFilesPutParams,
-    job_config: Arc<JobConfig>,
-    body: FullBody,
-) -> Result<StatusCode, Error> {
-    if !job_config
-        .nodes
-        .read()
-        .expect("Cannot read nodes list")
-        .is_subnode(&source_id)
-    {
-        return Ok(StatusCode::NOT_FOUND);
-    }
+/// State of a file being received
+struct Upload {
+    /// Metadata block, until the first empty line
+    header: Vec<u8>,
+    /// Available once the whole metadata block has been received
+    meta: Option<Metadata>,
+    /// Size of the file, without the metadata block
+    size: u64,
+}
 
-    let mut stream = BufReader::new(body.into_buf().reader());
-    let mut raw_meta = String::new();
-    // Here we cannot iterate on lines as the file content may not be valid UTF-8.
-    let mut read = 2;
-    // Let's read while we find an empty line.
-    while read > 1 {
-        read = stream.read_line(&mut raw_meta)?;
+impl Upload {
+    fn new() -> Self {
+        Self {
+            header: vec![],
+            meta: None,
+            size: 0,
+        }
     }
-    let meta = Metadata::from_str(&raw_meta)?;
-    let mut file = vec![];
-    stream.read_to_end(&mut file)?;
 
-    let base_path = job_config
-        .cfg
-        .shared_files
-        .path
-        .join(&target_id)
-        .join(&source_id);
-    let pubkey = get_pubkey(&meta.short_pubkey)?;
+    /// Returns the part of the chunk belonging to the file, or the status to reply
+    /// if the upload has to be stopped
+    fn receive(
+        &mut self,
+        mut chunk: Bytes,
+        max_size: u64,
+    ) -> Result<Result<Bytes, StatusCode>, Error> {
+        if self.meta.is_none() {
+            let start = self.header.len();
+            self.header.extend_from_slice(&chunk);
+            // The metadata block ends with an empty line
+            let end = if self.header.starts_with(b"\n") {
+                Some(1)
+            } else {
+                self.header
+                    .windows(2)
+                    .position(|w| w == b"\n\n")
+                    .map(|p| p + 2)
+            };
+
+            match end {
+                Some(end) => {
+                    let content = chunk.split_off(end - start);
+                    self.header.truncate(end);
+                    self.meta = Some(Metadata::from_str(str::from_utf8(&self.header)?)?);
+                    chunk = content;
+                }
+                None if self.header.len() > MAX_HEADER_SIZE => return Err(Error::InvalidHeader),
+                None => return Ok(Ok(Bytes::new())),
+            }
+        }
 
-    if meta.algorithm.hash(&pubkey.public_key_to_der()?) != meta.digest {
-        return Ok(StatusCode::NOT_FOUND);
+        self.size += chunk.len() as u64;
+        if self.size > max_size {
+            return Ok(Err(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+        Ok(Ok(chunk))
     }
 
-    if !validate_signature(
-        &file,
-        pubkey,
-        meta.algorithm,
-        &hex::decode(&meta.digest).unwrap(),
-    )? {
-        return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+    /// Checks the signature of the complete file, written in `path`
+    fn verify(self, path: &Path) -> Result<Result<(Metadata, Vec<u8>), StatusCode>, Error> {
+        let meta = self.meta.ok_or(Error::InvalidHeader)?;
+        let pubkey = meta.pubkey()?;
+
+        if meta.algorithm.hash(&pubkey.public_key_to_der()?) != meta.digest {
+            return Ok(Err(StatusCode::NOT_FOUND));
+        }
+
+        let valid = match hex::decode(&meta.digest) {
+            Ok(signature) => {
+                verify_signature(meta.algorithm, &pubkey, &signature, fs::File::open(path)?)?
+            }
+            Err(_) => false,
+        };
+        Ok(if valid {
+            Ok((meta, self.header))
+        } else {
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        })
     }
+}
 
-    // Everything is correct, let's store the file
-    fs::create_dir_all(&base_path)?;
-    fs::write(
-        &base_path.join(format!("{}.metadata", file_id)),
+/// Writes the file content into `temp_path` and checks it once complete, without
+/// keeping the whole body in memory
+///
+/// Returns the metadata and the raw metadata block, or the status to reply
+/// if the payload is not valid.
+fn receive<S, B>(
+    body: S,
+    temp_path: PathBuf,
+    max_size: u64,
+) -> impl Future<Item = Result<(Metadata, Vec<u8>), StatusCode>, Error = Error>
+where
+    S: Stream<Item = B, Error = Error> + Send + 'static,
+    B: Buf + 'static,
+{
+    tokio::fs::File::create(temp_path.clone())
+        .map_err(Error::from)
+        .and_then(move |file| {
+            loop_fn(
+                (body, file, Upload::new()),
+                move |(body, file, mut upload)| {
+                    body.into_future()
+                        .map_err(|(e, _)| e)
+                        .and_then(move |(chunk, body)| {
+                            let chunk = match chunk {
+                                Some(chunk) => chunk.collect::<Bytes>(),
+                                None => {
+                                    return Box::new(future::ok(Loop::Break(Ok(upload))))
+                                        as Box<dyn Future<Item = _, Error = Error> + Send>
+                                }
+                            };
+                            match upload.receive(chunk, max_size) {
+                                Ok(Ok(content)) => Box::new(
+                                    write_all(file, content)
+                                        .map(move |(file, _)| Loop::Continue((body, file, upload)))
+                                        .map_err(Error::from),
+                                ),
+                                Ok(Err(status)) => Box::new(future::ok(Loop::Break(Err(status)))),
+                                Err(e) => Box::new(future::err(e)),
+                            }
+                        })
+                },
+            )
+        })
+        .and_then(move |res: Result<Upload, StatusCode>| match res {
+            // Reading the whole file again is blocking
+            Ok(upload) => {
+                let mut upload = Some(upload);
+                future::Either::A(
+                    poll_fn(move || {
+                        blocking(|| {
+                            upload
+                                .take()
+                                .expect("verified only once")
+                                .verify(&temp_path)
+                        })
+                        .map_err(|_| -> Error { panic!("the thread pool shut down") })
+                    })
+                    .flatten(),
+                )
+            }
+            Err(status) => future::Either::B(future::ok(Err(status))),
+        })
+}
+
+/// Sends the signed payload as is to the next relay on the way to the target
+fn forward(
+    job_config: Arc<JobConfig>,
+    relay: String,
+    // target_id/source_id/file_id
+    file_path: String,
+    params: SharedFilesPutParams,
+    header: Vec<u8>,
+    temp_path: PathBuf,
+) -> impl Future<Item = StatusCode, Error = Error> {
+    let report_span = span!(Level::TRACE, "upstream");
+    let _report_enter = report_span.enter();
+
+    debug!("Forwarding shared file {} to {}", file_path, relay);
+
+    tokio::fs::File::open(temp_path)
+        .map_err(Error::from)
+        .and_then(move |file| {
+            let payload: Box<dyn Stream<Item = Bytes, Error = io::Error> + Send> = Box::new(
+                stream::once(Ok(Bytes::from(header)))
+                    .chain(FramedRead::new(file, BytesCodec::new()).map(BytesMut::freeze)),
+            );
+            let request = job_config
+                .client()
+                .put(&forward_url(&relay, &file_path))
+                .query(&[("ttl", params.ttl)])
+                .body(payload)
+                .send()
+                .map(|response| response.status())
+                .map_err(Error::from);
+            with_request_timeout(&job_config, request)
+        })
+}
+
+/// Decides where a received file goes
+///
+/// Returns None if the file is refused, Some(None) if it is for one of our neighbors,
+/// else the relay to forward it to.
+///
+/// Files come either from our sub-nodes, through the relays between them and us,
+/// or from our upstream relay if they target one of our sub-nodes.
+fn next_hop(
+    nodes: &NodesList,
+    upstream: &UpstreamConfig,
+    caller: Option<&NodeIdRef>,
+    source_id: &NodeIdRef,
+    target_id: &NodeIdRef,
+) -> Option<Option<String>> {
+    let caller = caller?;
+    let relay_url = |relay: NodeId| {
         format!(
-            "{}expires={}\n",
-            meta,
-            match params.ttl() {
-                // Removal timestamp = now + ttl
-                Ok(ttl) => Utc::now() + ttl,
-                Err(_x) => return Ok(StatusCode::INTERNAL_SERVER_ERROR),
+            "https://{}",
+            nodes
+                .hostname(&relay)
+                .expect("next hop should be in nodes list")
+        )
+    };
+
+    if nodes.is_subnode(source_id) {
+        if caller != source_id && !nodes.relays(source_id).contains(&caller) {
+            return None;
+        }
+        match nodes.next_hop(target_id) {
+            Ok(next_hop) => Some(next_hop.map(relay_url)),
+            // Unknown nodes can only be reached through our upstream relay
+            Err(()) => Some(Some(upstream.url.clone())),
+        }
+    } else if upstream.node_id.as_ref().map(|s| s.as_str()) == Some(caller) {
+        nodes.next_hop(target_id).ok().map(|n| n.map(relay_url))
+    } else {
+        None
+    }
+}
+
+/// URL to send a shared file to another relay
+///
+/// The relay's reverse proxy adds the API version.
+fn forward_url(relay: &str, file_path: &str) -> String {
+    format!("{}/rudder/relay-api/shared-files/{}", relay, file_path)
+}
+
+pub fn put<S, B>(
+    target_id: String,
+    source_id: String,
+    file_id: String,
+    params: SharedFilesPutParams,
+    node_id: Option<NodeId>,
+    job_config: Arc<JobConfig>,
+    body: S,
+) -> Box<dyn Future<Item = StatusCode, Error = Error> + Send>
+where
+    S: Stream<Item = B, Error = Error> + Send + 'static,
+    B: Buf + 'static,
+{
+    let next_hop = {
+        let nodes = job_config.nodes.read().expect("Cannot read nodes list");
+        match next_hop(
+            &nodes,
+            &job_config.cfg().output.upstream,
+            node_id.as_ref().map(|s| s.as_str()),
+            &source_id,
+            &target_id,
+        ) {
+            Some(next_hop) => next_hop,
+            None => {
+                debug!(
+                    "refusing shared file {} from {} to {} sent by {:?}",
+                    file_id, source_id, target_id, node_id
+                );
+                return Box::new(future::ok(StatusCode::NOT_FOUND));
             }
-        ),
-    )?;
-    fs::write(&base_path.join(file_id), file)?;
-    Ok(StatusCode::OK)
+        }
+    };
+
+    let cfg = job_config.cfg();
+    let upload_path = cfg.shared_files.path.join(UPLOAD_DIRECTORY);
+    let temp_path = upload_path.join(format!("{}-{:08x}", file_id, thread_rng().gen::<u32>()));
+    let base_path = cfg.shared_files.path.join(&target_id).join(&source_id);
+    let max_size = cfg.shared_files.max_size;
+
+    let temp_path_upload = temp_path.clone();
+    let temp_path_cleanup = temp_path.clone();
+    Box::new(
+        create_dir_all(upload_path)
+            .map_err(Error::from)
+            .and_then(move |_| receive(body, temp_path_upload, max_size))
+            .and_then(move |res| {
+                // Check before forwarding to avoid propagating invalid files
+                let (meta, header) = match res {
+                    Ok(res) => res,
+                    Err(status) => {
+                        return Box::new(future::ok(status))
+                            as Box<dyn Future<Item = StatusCode, Error = Error> + Send>
+                    }
+                };
+
+                match next_hop {
+                    Some(relay) => Box::new(forward(
+                        job_config,
+                        relay,
+                        format!("{}/{}/{}", target_id, source_id, file_id),
+                        params,
+                        header,
+                        temp_path,
+                    )),
+                    None => Box::new(store(base_path, file_id, params, meta, temp_path)),
+                }
+            })
+            // Remove what is left of the uploaded file
+            .then(move |res| {
+                remove_file(temp_path_cleanup).then(move |cleanup| {
+                    match cleanup {
+                        Err(ref e) if e.kind() != io::ErrorKind::NotFound => {
+                            error!("could not remove uploaded file: {}", e)
+                        }
+                        _ => (),
+                    }
+                    res
+                })
+            }),
+    )
+}
+
+fn store(
+    base_path: PathBuf,
+    file_id: String,
+    params: SharedFilesPutParams,
+    meta: Metadata,
+    temp_path: PathBuf,
+) -> impl Future<Item = StatusCode, Error = Error> {
+    let expires = match params.ttl() {
+        // Removal timestamp = now + ttl
+        Ok(ttl) => Utc::now() + ttl,
+        Err(_x) => return future::Either::A(future::ok(StatusCode::INTERNAL_SERVER_ERROR)),
+    };
+
+    // Everything is correct, let's store the file
+    let file_path = base_path.join(&file_id);
+    let metadata_path = base_path.join(format!("{}.metadata", file_id));
+    future::Either::B(
+        create_dir_all(base_path)
+            .and_then(move |_| rename(temp_path, file_path))
+            .and_then(move |_| {
+                write(
+                    metadata_path,
+                    format!("{}{}", meta, Metadata::expires_line(expires)).into_bytes(),
+                )
+            })
+            .map(|_| StatusCode::OK)
+            .map_err(Error::from),
+    )
 }
 
 #[derive(Deserialize, Debug)]
@@ -256,7 +491,7 @@ pub fn head(
     job_config: Arc<JobConfig>,
 ) -> Result<StatusCode, Error> {
     let file_path = job_config
-        .cfg
+        .cfg()
         .shared_files
         .path
         .join(&target_id)
@@ -276,28 +511,100 @@ pub fn head(
     )
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use openssl::sign::Signer;
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
+        .expect("could not build response")
+}
 
-    #[test]
-    pub fn it_writes_the_metadata() {
-        let metadata = Metadata {
-            header: "rudder-signature-v1".to_string(),
-            algorithm: HashType::Sha256,
-            digest: "8ca9efc5752e133e2e80e2661c176fa50f".to_string(),
-            hash_value: "a75fda39a7af33eb93ab1c74874dcf66d5761ad30977368cf0c4788cf5bfd34f"
-                .to_string(),
-            short_pubkey: "shortpubkey".to_string(),
-            hostname: "ubuntu-18-04-64".to_string(),
-            key_date: "2018-10-3118:21:43.653257143".to_string(),
-            key_id: "B29D02BB".to_string(),
-        };
+/// Reads the metadata of a shared file, if it exists and has not expired
+fn valid_metadata(
+    metadata_path: &Path,
+    now: DateTime<Utc>,
+) -> Result<Option<(Metadata, DateTime<Utc>)>, Error> {
+    let raw_meta = match fs::read_to_string(metadata_path) {
+        Ok(raw_meta) => raw_meta,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let expires = Metadata::parse_expires(&raw_meta)?;
+    if expires <= now {
+        debug!("{:?} expired on {}", metadata_path, expires);
+        return Ok(None);
+    }
+    Ok(Some((Metadata::from_str(&raw_meta)?, expires)))
+}
+
+/// Signed metadata sent along with the file to allow checking it again
+fn metadata_headers(meta: &Metadata, expires: DateTime<Utc>) -> Vec<(&'static str, String)> {
+    vec![
+        ("X-Rudder-Header", meta.header.clone()),
+        ("X-Rudder-Algorithm", meta.algorithm.to_string()),
+        ("X-Rudder-Digest", meta.digest.clone()),
+        ("X-Rudder-Hash-Value", meta.hash_value.clone()),
+        ("X-Rudder-Short-Pubkey", meta.short_pubkey.clone()),
+        ("X-Rudder-Hostname", meta.hostname.clone()),
+        ("X-Rudder-Keydate", meta.key_date.clone()),
+        ("X-Rudder-Keyid", meta.key_id.clone()),
+        ("X-Rudder-Expires", expires.timestamp().to_string()),
+    ]
+}
 
-        assert_eq!(format!("{}", metadata), "header=rudder-signature-v1\nalgorithm=sha256\ndigest=8ca9efc5752e133e2e80e2661c176fa50f\nhash_value=a75fda39a7af33eb93ab1c74874dcf66d5761ad30977368cf0c4788cf5bfd34f\nshort_pubkey=shortpubkey\nhostname=ubuntu-18-04-64\nkeydate=2018-10-3118:21:43.653257143\nkeyid=B29D02BB\n");
+pub fn get(
+    target_id: String,
+    source_id: String,
+    file_id: String,
+    node_id: Option<String>,
+    job_config: Arc<JobConfig>,
+) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+    // Files are only available to their target
+    if node_id.as_ref() != Some(&target_id) {
+        debug!(
+            "refusing to send file {} to {:?} as it is shared with {}",
+            file_id, node_id, target_id
+        );
+        return Box::new(future::ok(not_found()));
     }
 
+    let base_path = job_config
+        .cfg()
+        .shared_files
+        .path
+        .join(&target_id)
+        .join(&source_id);
+    let (meta, expires) =
+        match valid_metadata(&base_path.join(format!("{}.metadata", file_id)), Utc::now()) {
+            Ok(Some(res)) => res,
+            Ok(None) => return Box::new(future::ok(not_found())),
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+    Box::new(
+        tokio::fs::File::open(base_path.join(&file_id)).then(move |res| match res {
+            Ok(file) => {
+                let mut response = Response::builder();
+                response.status(StatusCode::OK);
+                for (name, value) in metadata_headers(&meta, expires) {
+                    response.header(name, value);
+                }
+                Ok(response
+                    .body(Body::wrap_stream(
+                        FramedRead::new(file, BytesCodec::new()).map(BytesMut::freeze),
+                    ))
+                    .expect("could not build response"))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(not_found()),
+            Err(e) => Err(e.into()),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{rsa::Rsa, sign::Signer};
+
     #[test]
     pub fn it_validates_signatures() {
         // Generate a keypair
@@ -316,7 +623,37 @@ mod tests {
 
         let signature = signer.sign_to_vec().unwrap();
 
-        assert!(validate_signature(data, keypub, HashType::Sha512, &signature).unwrap());
+        let content = io::Read::chain(&data[..5], &data[5..]);
+        assert!(verify_signature(HashType::Sha512, &keypub, &signature, content).unwrap());
+        assert!(
+            !verify_signature(HashType::Sha512, &keypub, &signature, &b"hello, world?"[..])
+                .unwrap()
+        );
+        assert!(!verify_signature(HashType::Sha512, &keypub, b"invalid", &data[..]).unwrap());
+    }
+
+    #[test]
+    pub fn it_receives_files_in_parts() {
+        let header = fs::read("tests/api_shared_files/37817c4d-fbf7-4850-a985-50021f4e8f41/37817c4d-fbf7-4850-a985-50021f4e8f41/file4.metadata").unwrap();
+        let mut payload = header.clone();
+        payload.extend_from_slice(b"\ncontent\n\nof the file\n");
+        let payload = Bytes::from(payload);
+
+        let mut upload = Upload::new();
+        let mut content = vec![];
+        for chunk in payload.chunks(100) {
+            content.extend_from_slice(&upload.receive(Bytes::from(chunk), 1000).unwrap().unwrap());
+        }
+        assert_eq!(content, b"content\n\nof the file\n");
+        assert_eq!(upload.size, content.len() as u64);
+        assert_eq!(upload.header.len(), header.len() + 1);
+        assert_eq!(upload.meta.unwrap().hostname, "ubuntu-18-04-64");
+
+        let mut upload = Upload::new();
+        assert_eq!(
+            upload.receive(payload, 10).unwrap(),
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
     }
 
     #[test]
@@ -341,4 +678,65 @@ mod tests {
         assert!(SharedFilesPutParams::new("913b83").ttl().is_err());
         assert!(SharedFilesPutParams::new("913h 89j").ttl().is_err());
     }
+
+    #[test]
+    fn it_forwards_without_api_version() {
+        assert_eq!(
+            forward_url(
+                "https://node1.rudder.local",
+                "a745a140-40bc-4b86-b6dc-084488fc906b/c745a140-40bc-4b86-b6dc-084488fc906b/file"
+            ),
+            "https://node1.rudder.local/rudder/relay-api/shared-files/a745a140-40bc-4b86-b6dc-084488fc906b/c745a140-40bc-4b86-b6dc-084488fc906b/file"
+        );
+    }
+
+    #[test]
+    fn it_routes_shared_files() {
+        let nodes = NodesList::new("root".to_string(), "tests/files/nodeslist.json", None).unwrap();
+        let mut upstream = crate::configuration::main::Configuration::new("tests/files/config/")
+            .unwrap()
+            .output
+            .upstream;
+        upstream.node_id = Some("upstream".to_string());
+        let node3 = "a745a140-40bc-4b86-b6dc-084488fc906b";
+        let node4 = "b745a140-40bc-4b86-b6dc-084488fc906b";
+        let node5 = "c745a140-40bc-4b86-b6dc-084488fc906b";
+
+        // From a sub-node, through its relays
+        assert_eq!(
+            next_hop(&nodes, &upstream, Some(node5), node5, node4),
+            Some(Some("https://node1.rudder.local".to_string()))
+        );
+        assert_eq!(
+            next_hop(
+                &nodes,
+                &upstream,
+                Some("37817c4d-fbf7-4850-a985-50021f4e8f41"),
+                node5,
+                "unknown"
+            ),
+            Some(Some("https://127.0.0.1:8080".to_string()))
+        );
+        assert_eq!(
+            next_hop(&nodes, &upstream, Some(node5), node5, "root"),
+            Some(None)
+        );
+        // Claiming to be another node
+        assert_eq!(next_hop(&nodes, &upstream, Some(node3), node5, node4), None);
+        assert_eq!(next_hop(&nodes, &upstream, None, node5, node4), None);
+
+        // From outside, only through the upstream relay
+        assert_eq!(
+            next_hop(&nodes, &upstream, Some("upstream"), "other", node4),
+            Some(Some("https://node1.rudder.local".to_string()))
+        );
+        assert_eq!(
+            next_hop(&nodes, &upstream, Some(node4), "other", node4),
+            None
+        );
+        assert_eq!(
+            next_hop(&nodes, &upstream, Some("upstream"), "other", "unknown"),
+            None
+        );
+    }
 }
@@ -54,7 +54,7 @@ pub fn head(
     file: PathBuf,
     job_config: Arc<JobConfig>,
 ) -> impl Future<Item = StatusCode, Error = Error> + Send {
-    let file_path = job_config.cfg.shared_folder.path.join(&file);
+    let file_path = job_config.cfg().shared_folder.path.join(&file);
     debug!(
         "Received request for {:#} ({:#} locally) with the following parameters: {:?}",
         file.display(),
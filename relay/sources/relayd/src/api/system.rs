@@ -28,7 +28,17 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::{api::ApiResult, check_configuration, output::database::ping, Error, JobConfig};
+use crate::{
+    api::ApiResult,
+    check_configuration,
+    configuration::main::{InventoryOutputSelect, ReportingOutputSelect},
+    output::database::ping,
+    processing::{
+        inventory::InventoryType,
+        retry::{queue_depth, retry_directory},
+    },
+    Error, JobConfig,
+};
 use serde::Serialize;
 use std::sync::Arc;
 use structopt::clap::crate_version;
@@ -84,13 +94,67 @@ pub struct Status {
 impl Status {
     pub fn poll(job_config: Arc<JobConfig>) -> Self {
         Self {
-            database: job_config
-                .pool
-                .clone()
-                .map(|p| ping(&p).map_err(|e| e).into()),
+            database: job_config.pool().map(|p| ping(&p).map_err(|e| e).into()),
             configuration: check_configuration(&job_config.cli_cfg.configuration_dir)
                 .map_err(|e| e)
                 .into(),
         }
     }
 }
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Reload {
+    /// Modified settings that will only be applied after a restart
+    restart_required: Vec<&'static str>,
+}
+
+impl Reload {
+    pub fn new(restart_required: Vec<&'static str>) -> Self {
+        Self { restart_required }
+    }
+}
+
+/// Number of files waiting for a new attempt, for each enabled processing
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct RetryQueue {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reporting: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inventory: Option<usize>,
+}
+
+impl RetryQueue {
+    pub fn poll(job_config: Arc<JobConfig>) -> Result<Self, Error> {
+        let cfg = job_config.cfg();
+        let cfg = &cfg.processing;
+
+        let reporting = if cfg.reporting.output != ReportingOutputSelect::Disabled {
+            Some(queue_depth(&retry_directory(
+                &cfg.reporting.directory,
+                "incoming",
+            ))?)
+        } else {
+            None
+        };
+
+        let inventory = if cfg.inventory.output != InventoryOutputSelect::Disabled {
+            Some(
+                queue_depth(&retry_directory(
+                    &cfg.inventory.directory,
+                    InventoryType::New.directory(),
+                ))? + queue_depth(&retry_directory(
+                    &cfg.inventory.directory,
+                    InventoryType::Update.directory(),
+                ))?,
+            )
+        } else {
+            None
+        };
+
+        Ok(Self {
+            reporting,
+            inventory,
+        })
+    }
+}
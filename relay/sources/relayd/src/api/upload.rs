@@ -0,0 +1,204 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+use crate::{
+    data::{
+        node::{NodeIdRef, NodesList},
+        RunInfo,
+    },
+    error::Error,
+    input::upload::{receive, UploadTarget},
+    processing::inventory::{has_inventory_extension, InventoryType},
+    JobConfig,
+};
+use futures::{future, Future, Stream};
+use std::{path::Path, str::FromStr, sync::Arc};
+use tracing::warn;
+use warp::{http::StatusCode, Buf};
+
+/// Maximum size of an uploaded report or inventory
+const MAX_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Only plain file names are accepted, and hidden files are used for uploads in progress
+fn is_valid_name(file_name: &str) -> bool {
+    !file_name.is_empty() && !file_name.starts_with('.') && !file_name.contains('/')
+}
+
+/// Reports are sent by the node itself, or by the relay it is behind
+fn is_allowed_sender(nodes: &NodesList, sender: &NodeIdRef, node_id: &NodeIdRef) -> bool {
+    match nodes.next_hop(node_id) {
+        Ok(Some(relay)) => relay == sender,
+        Ok(None) => node_id == sender,
+        Err(()) => false,
+    }
+}
+
+/// Receives a report, `node_id` is the id of the authenticated sender
+pub fn put_report<S, B>(
+    file_name: String,
+    node_id: Option<String>,
+    job_config: Arc<JobConfig>,
+    body: S,
+) -> Box<dyn Future<Item = StatusCode, Error = Error> + Send>
+where
+    S: Stream<Item = B, Error = Error> + Send + 'static,
+    B: Buf + 'static,
+{
+    let run_info = match RunInfo::from_str(&file_name) {
+        Ok(run_info) if is_valid_name(&file_name) => run_info,
+        _ => return Box::new(future::ok(StatusCode::BAD_REQUEST)),
+    };
+    let allowed = match node_id {
+        Some(ref sender) => is_allowed_sender(
+            &job_config.nodes.read().expect("could not read nodes list"),
+            sender,
+            &run_info.node_id,
+        ),
+        None => false,
+    };
+    if !allowed {
+        warn!(
+            "refused report {} from {}",
+            file_name,
+            node_id.unwrap_or_else(|| "unauthenticated sender".to_string())
+        );
+        return Box::new(future::ok(StatusCode::FORBIDDEN));
+    }
+
+    upload(UploadTarget::Reports, &file_name, &job_config, body)
+}
+
+/// Receives an inventory or its signature, `node_id` is the id of the authenticated sender
+pub fn put_inventory<S, B>(
+    inventory_type: InventoryType,
+    file_name: String,
+    node_id: Option<String>,
+    job_config: Arc<JobConfig>,
+    body: S,
+) -> Box<dyn Future<Item = StatusCode, Error = Error> + Send>
+where
+    S: Stream<Item = B, Error = Error> + Send + 'static,
+    B: Buf + 'static,
+{
+    if !is_valid_name(&file_name) || !has_inventory_extension(Path::new(&file_name)) {
+        return Box::new(future::ok(StatusCode::BAD_REQUEST));
+    }
+    // New nodes are not known yet, the signature of their inventory
+    // is checked during processing.
+    if let InventoryType::Update = inventory_type {
+        let allowed = match node_id {
+            Some(ref sender) => job_config
+                .nodes
+                .read()
+                .expect("could not read nodes list")
+                .is_subnode(sender),
+            None => false,
+        };
+        if !allowed {
+            warn!(
+                "refused inventory {} from {}",
+                file_name,
+                node_id.unwrap_or_else(|| "unauthenticated sender".to_string())
+            );
+            return Box::new(future::ok(StatusCode::FORBIDDEN));
+        }
+    }
+
+    upload(
+        inventory_type.upload_target(),
+        &file_name,
+        &job_config,
+        body,
+    )
+}
+
+fn upload<S, B>(
+    target: UploadTarget,
+    file_name: &str,
+    job_config: &JobConfig,
+    body: S,
+) -> Box<dyn Future<Item = StatusCode, Error = Error> + Send>
+where
+    S: Stream<Item = B, Error = Error> + Send + 'static,
+    B: Buf + 'static,
+{
+    match job_config.uploads.get(target) {
+        Some((directory, tx)) => Box::new(receive(directory, file_name, body, MAX_SIZE, tx).then(
+            |res| match res {
+                Ok(()) => Ok(StatusCode::CREATED),
+                Err(Error::FileTooLarge(_)) => Ok(StatusCode::PAYLOAD_TOO_LARGE),
+                Err(e) => Err(e),
+            },
+        )),
+        // Processing is disabled
+        None => Box::new(future::ok(StatusCode::NOT_FOUND)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_checks_file_names() {
+        assert!(is_valid_name("2018-08-24T15:55:01+00:00@root.log"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name(".."));
+        assert!(!is_valid_name(".report.log-1234abcd"));
+        assert!(!is_valid_name("../report.log"));
+    }
+
+    #[test]
+    fn it_checks_report_senders() {
+        let nodes = NodesList::new("root".to_string(), "tests/files/nodeslist.json", None).unwrap();
+        // Node behind a relay
+        assert!(is_allowed_sender(
+            &nodes,
+            "e745a140-40bc-4b86-b6dc-084488fc906b",
+            "b745a140-40bc-4b86-b6dc-084488fc906b"
+        ));
+        assert!(!is_allowed_sender(
+            &nodes,
+            "b745a140-40bc-4b86-b6dc-084488fc906b",
+            "b745a140-40bc-4b86-b6dc-084488fc906b"
+        ));
+        // Directly connected node
+        assert!(is_allowed_sender(
+            &nodes,
+            "37817c4d-fbf7-4850-a985-50021f4e8f41",
+            "37817c4d-fbf7-4850-a985-50021f4e8f41"
+        ));
+        assert!(!is_allowed_sender(
+            &nodes,
+            "e745a140-40bc-4b86-b6dc-084488fc906b",
+            "37817c4d-fbf7-4850-a985-50021f4e8f41"
+        ));
+        assert!(!is_allowed_sender(&nodes, "unknown", "unknown"));
+    }
+}
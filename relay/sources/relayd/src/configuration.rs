@@ -1,6 +1,7 @@
 pub mod cli;
 pub mod logging;
 pub mod main;
+pub mod routing;
 
 use serde::Deserialize;
 use std::fmt;
@@ -30,7 +30,12 @@
 
 use crate::error::Error;
 use serde::Deserialize;
-use std::{fmt, fs::read_to_string, path::Path, str::FromStr};
+use std::{
+    fmt,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use toml;
 use tracing::debug;
 
@@ -43,7 +48,11 @@ impl FromStr for LogConfig {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(toml::from_str(s)?)
+        let cfg: Self = toml::from_str(s)?;
+        if cfg.general.output == LogOutput::File && cfg.general.file.is_none() {
+            return Err(Error::MissingLogFile);
+        }
+        Ok(cfg)
     }
 }
 
@@ -91,11 +100,52 @@ impl fmt::Display for LogLevel {
     }
 }
 
+#[derive(Copy, Debug, Eq, PartialEq, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, with fields of all spans
+    Full,
+    /// Human readable, with fields of the current span
+    Compact,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Copy, Debug, Eq, PartialEq, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    Stdout,
+    /// Local syslog socket
+    Syslog,
+    File,
+}
+
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct LoggerConfig {
     #[serde(with = "LogLevel")]
     pub level: LogLevel,
     pub filter: String,
+    #[serde(default = "default_format")]
+    pub format: LogFormat,
+    #[serde(default = "default_timestamps")]
+    pub timestamps: bool,
+    #[serde(default = "default_output")]
+    pub output: LogOutput,
+    /// Only used with file output
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+}
+
+fn default_format() -> LogFormat {
+    LogFormat::Full
+}
+
+fn default_timestamps() -> bool {
+    true
+}
+
+fn default_output() -> LogOutput {
+    LogOutput::Stdout
 }
 
 impl fmt::Display for LoggerConfig {
@@ -118,6 +168,10 @@ mod tests {
             general: LoggerConfig {
                 level: LogLevel::Info,
                 filter: "".to_string(),
+                format: LogFormat::Full,
+                timestamps: false,
+                output: LogOutput::Stdout,
+                file: None,
             },
         };
         assert_eq!(&log_reference.to_string(), "info");
@@ -126,6 +180,10 @@ mod tests {
             general: LoggerConfig {
                 level: LogLevel::Info,
                 filter: "[database{node=root}]=trace".to_string(),
+                format: LogFormat::Full,
+                timestamps: false,
+                output: LogOutput::Stdout,
+                file: None,
             },
         };
         assert_eq!(
@@ -141,8 +199,41 @@ mod tests {
             general: LoggerConfig {
                 level: LogLevel::Off,
                 filter: "".to_string(),
+                format: LogFormat::Json,
+                timestamps: true,
+                output: LogOutput::Stdout,
+                file: Some(PathBuf::from("/var/log/rudder/relayd/relayd.log")),
+            },
+        };
+        assert_eq!(log_config.unwrap(), log_reference);
+    }
+
+    #[test]
+    fn it_parses_baseline_logging_configuration() {
+        let log_config = LogConfig::new("tests/files/config/baseline/");
+        let log_reference = LogConfig {
+            general: LoggerConfig {
+                level: LogLevel::Info,
+                filter: "".to_string(),
+                format: LogFormat::Full,
+                timestamps: true,
+                output: LogOutput::Stdout,
+                file: None,
             },
         };
         assert_eq!(log_config.unwrap(), log_reference);
     }
+
+    #[test]
+    fn it_requires_log_file_for_file_output() {
+        let log_config = "[general]
+level = \"info\"
+filter = \"\"
+format = \"full\"
+timestamps = false
+output = \"file\"
+"
+        .parse::<LogConfig>();
+        assert!(log_config.is_err());
+    }
 }
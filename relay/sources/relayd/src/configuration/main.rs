@@ -28,17 +28,28 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::{configuration::Secret, data::node::NodeId, error::Error};
+use crate::{
+    configuration::{
+        routing::{Action, Matcher, RoutingRule},
+        Secret,
+    },
+    data::node::NodeId,
+    error::Error,
+};
+use openssl::{
+    pkey::{PKey, Private},
+    x509::X509,
+};
 use serde::Deserialize;
 use std::{
     collections::HashSet,
-    fs::read_to_string,
+    fs::{read, read_to_string},
     net::SocketAddr,
     path::{Path, PathBuf},
     str::FromStr,
 };
 use toml;
-use tracing::debug;
+use tracing::{debug, warn};
 
 pub type BaseDirectory = PathBuf;
 pub type WatchedDirectory = PathBuf;
@@ -64,13 +75,60 @@ impl Configuration {
         }
         res
     }
+
+    /// Keeps the running value of settings that can only be applied by restarting
+    /// the service and returns the names of the modified ones.
+    pub fn keep_static(&mut self, running: &Self) -> Vec<&'static str> {
+        let mut modified = vec![];
+
+        macro_rules! keep {
+            ($name:expr, $($field:ident).+) => {
+                if self.$($field).+ != running.$($field).+ {
+                    modified.push($name);
+                    self.$($field).+ = running.$($field).+.clone();
+                }
+            };
+        }
+
+        keep!("general.node_id", general.node_id);
+        keep!("general.listen", general.listen);
+        keep!("general.https", general.https);
+        keep!("general.core_threads", general.core_threads);
+        keep!("general.blocking_threads", general.blocking_threads);
+        keep!(
+            "processing.inventory.directory",
+            processing.inventory.directory
+        );
+        keep!("processing.inventory.output", processing.inventory.output);
+        keep!(
+            "processing.reporting.directory",
+            processing.reporting.directory
+        );
+        keep!("processing.reporting.output", processing.reporting.output);
+        keep!(
+            "output.database.max_batch_size",
+            output.database.max_batch_size
+        );
+        keep!("output.database.batch_window", output.database.batch_window);
+        keep!("shared_folder.path", shared_folder.path);
+
+        modified
+    }
 }
 
 impl FromStr for Configuration {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(toml::from_str(s)?)
+        let mut cfg: Self = toml::from_str(s)?;
+        cfg.processing.reporting.convert_skip_event_types();
+        if cfg.processing.reporting.output == ReportingOutputSelect::Upstream
+            && cfg.processing.reporting.modifies_runlogs()
+            && cfg.output.upstream.client_certificate.is_none()
+        {
+            return Err(Error::MissingSigningCertificate);
+        }
+        Ok(cfg)
     }
 }
 
@@ -83,12 +141,86 @@ pub struct GeneralConfig {
     /// None means using the number of available CPUs
     pub core_threads: Option<usize>,
     pub blocking_threads: usize,
+    /// Maximum time to wait for running tasks on shutdown, in seconds
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// None means the API is only served on `listen`, behind the reverse proxy
+    #[serde(default)]
+    pub https: Option<HttpsConfig>,
+}
+
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+/// API listener handling TLS and client authentication itself, only serving
+/// the routes used by nodes
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct HttpsConfig {
+    pub listen: SocketAddr,
+    /// PEM file containing the server certificate chain
+    pub certificate: PathBuf,
+    /// PEM file containing the unencrypted server private key
+    pub private_key: PathBuf,
+    /// Refuse clients without a known node certificate
+    pub require_client_certificate: bool,
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
 pub struct CatchupConfig {
     pub frequency: u64,
     pub limit: u64,
+    /// Minimum age of the files to process, in seconds
+    ///
+    /// Newer files are processed by the watcher as soon as they are written.
+    #[serde(default = "default_min_age")]
+    pub min_age: u64,
+}
+
+fn default_min_age() -> u64 {
+    30
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct RetryConfig {
+    /// Delay before first retry in seconds, doubled for each new attempt
+    pub initial_delay: u64,
+    /// Maximum delay between two attempts in seconds
+    pub max_delay: u64,
+    pub max_attempts: u32,
+    /// Maximum time since first failure before giving up, in seconds
+    pub max_age: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: 60,
+            max_delay: 3600,
+            max_attempts: 10,
+            max_age: 86400,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct FailedConfig {
+    /// Delay between two removals of old failed files, in seconds
+    pub cleanup_frequency: u64,
+    /// Time since failure after which files are removed, in seconds
+    pub max_age: u64,
+    /// Maximum total size of the failed files in bytes, oldest ones are removed first
+    pub max_size: u64,
+}
+
+impl Default for FailedConfig {
+    fn default() -> Self {
+        Self {
+            cleanup_frequency: 3600,
+            max_age: 2_592_000,
+            max_size: 1_073_741_824,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -101,7 +233,22 @@ pub struct ProcessingConfig {
 pub struct InventoryConfig {
     pub directory: BaseDirectory,
     pub output: InventoryOutputSelect,
+    /// Catchup of new inventories
     pub catchup: CatchupConfig,
+    /// Catchup of inventories of accepted nodes, None means using `catchup`
+    #[serde(default)]
+    pub updates_catchup: Option<CatchupConfig>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub failed: FailedConfig,
+    /// Time to wait for the signature of an inventory, in seconds
+    #[serde(default = "default_signature_grace_period")]
+    pub signature_grace_period: u64,
+}
+
+fn default_signature_grace_period() -> u64 {
+    60
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -116,7 +263,112 @@ pub struct ReportingConfig {
     pub directory: BaseDirectory,
     pub output: ReportingOutputSelect,
     pub catchup: CatchupConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub failed: FailedConfig,
+    /// Deprecated, converted into drop rules applied before `rules`
+    #[serde(default)]
     pub skip_event_types: HashSet<String>,
+    /// Applied to reports before insertion or forwarding
+    #[serde(default)]
+    pub rules: Vec<RoutingRule>,
+    #[serde(default = "default_agent_logs")]
+    pub agent_logs: AgentLogOutput,
+    #[serde(default = "default_validation")]
+    pub validation: RunlogValidation,
+    /// Digest algorithms accepted in runlog signatures
+    #[serde(default = "default_allowed_digests")]
+    pub allowed_digests: HashSet<String>,
+    #[serde(default = "default_upstream_check")]
+    pub upstream_check: ReportCheck,
+    /// Accept reports signed by the relays between the node and this server,
+    /// needed when they modify run logs
+    #[serde(default)]
+    pub trust_relay_signatures: bool,
+}
+
+fn default_agent_logs() -> AgentLogOutput {
+    AgentLogOutput::Legacy
+}
+
+fn default_validation() -> RunlogValidation {
+    RunlogValidation::Reject
+}
+
+fn default_allowed_digests() -> HashSet<String> {
+    ["md5", "sha1", "sha224", "sha256", "sha384", "sha512"]
+        .iter()
+        .map(|d| d.to_string())
+        .collect()
+}
+
+fn default_upstream_check() -> ReportCheck {
+    ReportCheck::Disabled
+}
+
+impl ReportingConfig {
+    /// Converts the deprecated `skip_event_types` into drop rules
+    fn convert_skip_event_types(&mut self) {
+        if self.skip_event_types.is_empty() {
+            return;
+        }
+        warn!("skip_event_types is deprecated, use rules instead");
+        let mut event_types: Vec<String> = self.skip_event_types.drain().collect();
+        // Keep the same order across reloads
+        event_types.sort();
+        let rules = event_types.into_iter().map(|event_type| RoutingRule {
+            event_type: Some(Matcher::Exact(event_type)),
+            rule_id: None,
+            directive_id: None,
+            component: None,
+            node_id: None,
+            action: Action::Drop,
+        });
+        self.rules = rules.chain(self.rules.drain(..)).collect();
+    }
+
+    /// Run logs can be modified before insertion or forwarding
+    pub fn modifies_runlogs(&self) -> bool {
+        !self.rules.is_empty()
+            || (self.agent_logs != AgentLogOutput::Store
+                && self.agent_logs != AgentLogOutput::Legacy)
+    }
+}
+
+/// What is done with agent logs found in run logs
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentLogOutput {
+    /// Stored along with reports in `ruddersysevents`, as before having their own table
+    Legacy,
+    /// Stored in the `agent_logs` table
+    Store,
+    /// Keep one log for each report, with the first line and the highest level
+    Summarize,
+    Drop,
+}
+
+/// What is done with inconsistent run logs
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RunlogValidation {
+    Reject,
+    /// Only keep the reports belonging to the run
+    Keep,
+    /// Move the run log to the quarantine directory, with the list of inconsistencies
+    Quarantine,
+}
+
+/// Checks done on reports before forwarding them upstream
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportCheck {
+    Disabled,
+    /// Check the signature
+    Signature,
+    /// Check the signature and parse the run log
+    RunLog,
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -147,11 +399,32 @@ impl OutputSelect for InventoryOutputSelect {
 pub struct RemoteRun {
     pub command: PathBuf,
     pub use_sudo: bool,
+    /// Time results of remote run jobs are kept after their end, in seconds
+    #[serde(default = "default_job_ttl")]
+    pub job_ttl: u64,
+}
+
+fn default_job_ttl() -> u64 {
+    3600
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct SharedFiles {
     pub path: PathBuf,
+    /// Delay between two removals of expired files, in seconds
+    #[serde(default = "default_cleanup_frequency")]
+    pub cleanup_frequency: u64,
+    /// Maximum size of a received file, in bytes
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+}
+
+fn default_cleanup_frequency() -> u64 {
+    600
+}
+
+fn default_max_size() -> u64 {
+    104_857_600
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -171,16 +444,83 @@ pub struct DatabaseConfig {
     pub url: String,
     pub password: Secret,
     pub max_pool_size: u32,
+    /// Maximum number of runlogs inserted in a single transaction
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize,
+    /// Time to wait for other runlogs before inserting a batch, in milliseconds
+    #[serde(default = "default_batch_window")]
+    pub batch_window: u64,
+}
+
+fn default_max_batch_size() -> usize {
+    100
+}
+
+fn default_batch_window() -> u64 {
+    500
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct UpstreamConfig {
     // TODO better URL type
     pub url: String,
+    /// Id of the upstream relay, the only one allowed to send shared files
+    /// coming from outside of our sub-nodes
+    #[serde(default)]
+    pub node_id: Option<NodeId>,
     pub user: String,
     pub password: Secret,
     pub verify_certificates: bool,
-    // TODO timeout?
+    /// PEM file containing certificates trusted in addition to the system ones
+    ///
+    /// It can contain the self-signed certificate of the upstream server.
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    /// Certificate presented to the upstream server
+    #[serde(default)]
+    pub client_certificate: Option<ClientCertificate>,
+    /// In seconds
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// Maximum duration of a file upload, in seconds
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+    /// Proxy URL, credentials can be given in the URL
+    #[serde(default)]
+    pub proxy: Option<String>,
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+fn default_request_timeout() -> u64 {
+    300
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ClientCertificate {
+    /// PEM file
+    pub certificate: PathBuf,
+    /// PEM file, encrypted if a passphrase is given
+    pub private_key: PathBuf,
+    pub passphrase: Option<Secret>,
+}
+
+impl ClientCertificate {
+    /// Reads the certificate and its private key
+    pub fn read(&self) -> Result<(X509, PKey<Private>), Error> {
+        let cert = X509::from_pem(&read(&self.certificate)?)?;
+        // Never prompt for a passphrase, unencrypted keys ignore it
+        let key = PKey::private_key_from_pem_passphrase(
+            &read(&self.private_key)?,
+            self.passphrase
+                .as_ref()
+                .map(|p| p.value().as_bytes())
+                .unwrap_or(b""),
+        )?;
+        Ok((cert, key))
+    }
 }
 
 #[cfg(test)]
@@ -194,6 +534,84 @@ mod tests {
         assert!(config.is_err());
     }
 
+    #[test]
+    fn it_keeps_static_settings() {
+        let running = Configuration::new("tests/files/config/").unwrap();
+        let mut cfg = running.clone();
+        cfg.general.listen = "127.0.0.1:3031".parse().unwrap();
+        cfg.processing.reporting.output = ReportingOutputSelect::Upstream;
+        cfg.processing.reporting.catchup.frequency = 20;
+        cfg.output.database.max_pool_size = 20;
+
+        assert_eq!(
+            cfg.keep_static(&running),
+            vec!["general.listen", "processing.reporting.output"]
+        );
+        assert_eq!(cfg.general, running.general);
+        assert_eq!(
+            cfg.processing.reporting.output,
+            running.processing.reporting.output
+        );
+        assert_eq!(cfg.processing.reporting.catchup.frequency, 20);
+        assert_eq!(cfg.output.database.max_pool_size, 20);
+    }
+
+    #[test]
+    fn it_parses_deprecated_configuration() {
+        let config = read_to_string("tests/files/config/main.conf")
+            .unwrap()
+            .replace("rules = []", "skip_event_types = [\"log_info\"]")
+            .replace("trust_relay_signatures = false\n", "")
+            .parse::<Configuration>()
+            .unwrap();
+
+        assert!(config.processing.reporting.skip_event_types.is_empty());
+        assert_eq!(
+            config.processing.reporting.rules,
+            vec![RoutingRule {
+                event_type: Some(Matcher::Exact("log_info".to_string())),
+                rule_id: None,
+                directive_id: None,
+                component: None,
+                node_id: None,
+                action: Action::Drop,
+            }]
+        );
+        assert!(!config.processing.reporting.trust_relay_signatures);
+    }
+
+    #[test]
+    fn it_parses_baseline_configuration() {
+        let config = Configuration::new("tests/files/config/baseline/").unwrap();
+
+        assert_eq!(config.general.shutdown_timeout, 10);
+        assert_eq!(config.general.https, None);
+        assert_eq!(config.processing.inventory.catchup.min_age, 30);
+        assert_eq!(config.processing.inventory.updates_catchup, None);
+        assert_eq!(config.processing.inventory.retry, RetryConfig::default());
+        assert_eq!(config.processing.inventory.failed, FailedConfig::default());
+        assert_eq!(config.processing.reporting.catchup.min_age, 30);
+        assert!(config.processing.reporting.rules.is_empty());
+        assert_eq!(
+            config.processing.reporting.agent_logs,
+            AgentLogOutput::Legacy
+        );
+        assert_eq!(
+            config.processing.reporting.validation,
+            RunlogValidation::Reject
+        );
+        assert_eq!(config.processing.reporting.allowed_digests.len(), 6);
+        assert_eq!(
+            config.processing.reporting.upstream_check,
+            ReportCheck::Disabled
+        );
+        assert_eq!(config.output.upstream.node_id, None);
+        assert_eq!(config.output.upstream.client_certificate, None);
+        assert_eq!(config.output.database.max_batch_size, 100);
+        assert_eq!(config.remote_run.job_ttl, 3600);
+        assert_eq!(config.shared_files.max_size, 104_857_600);
+    }
+
     #[test]
     fn it_parses_main_configuration() {
         let config = Configuration::new("tests/files/config/");
@@ -206,6 +624,8 @@ mod tests {
                 listen: "127.0.0.1:3030".parse().unwrap(),
                 core_threads: None,
                 blocking_threads: 100,
+                shutdown_timeout: 10,
+                https: None,
             },
             processing: ProcessingConfig {
                 inventory: InventoryConfig {
@@ -214,7 +634,25 @@ mod tests {
                     catchup: CatchupConfig {
                         frequency: 10,
                         limit: 50,
+                        min_age: 30,
                     },
+                    updates_catchup: Some(CatchupConfig {
+                        frequency: 10,
+                        limit: 50,
+                        min_age: 30,
+                    }),
+                    retry: RetryConfig {
+                        initial_delay: 60,
+                        max_delay: 3600,
+                        max_attempts: 10,
+                        max_age: 86400,
+                    },
+                    failed: FailedConfig {
+                        cleanup_frequency: 3600,
+                        max_age: 2592000,
+                        max_size: 1073741824,
+                    },
+                    signature_grace_period: 60,
                 },
                 reporting: ReportingConfig {
                     directory: PathBuf::from("target/tmp/reporting/"),
@@ -222,29 +660,68 @@ mod tests {
                     catchup: CatchupConfig {
                         frequency: 10,
                         limit: 50,
+                        min_age: 30,
+                    },
+                    retry: RetryConfig {
+                        initial_delay: 60,
+                        max_delay: 3600,
+                        max_attempts: 10,
+                        max_age: 86400,
+                    },
+                    failed: FailedConfig {
+                        cleanup_frequency: 3600,
+                        max_age: 2592000,
+                        max_size: 1073741824,
                     },
                     skip_event_types: HashSet::new(),
+                    rules: vec![],
+                    agent_logs: AgentLogOutput::Store,
+                    validation: RunlogValidation::Keep,
+                    allowed_digests: ["sha256", "sha512"].iter().map(|d| d.to_string()).collect(),
+                    upstream_check: ReportCheck::Disabled,
+                    trust_relay_signatures: false,
                 },
             },
             output: OutputConfig {
                 upstream: UpstreamConfig {
                     url: "https://127.0.0.1:8080".to_string(),
+                    node_id: Some("d745a140-40bc-4b86-b6dc-084488fc906b".to_string()),
                     user: "rudder".to_string(),
                     password: Secret::new("password".to_string()),
                     verify_certificates: false,
+                    ca_file: Some(PathBuf::from(
+                        "tests/files/keys/37817c4d-fbf7-4850-a985-50021f4e8f41.cert",
+                    )),
+                    client_certificate: Some(ClientCertificate {
+                        certificate: PathBuf::from(
+                            "tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert",
+                        ),
+                        private_key: PathBuf::from(
+                            "tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.priv",
+                        ),
+                        passphrase: Some(Secret::new("Cfengine passphrase".to_string())),
+                    }),
+                    connect_timeout: 10,
+                    request_timeout: 60,
+                    proxy: None,
                 },
                 database: DatabaseConfig {
                     url: "postgres://rudderreports@127.0.0.1/rudder".to_string(),
                     password: Secret::new("PASSWORD".to_string()),
                     max_pool_size: 5,
+                    max_batch_size: 100,
+                    batch_window: 500,
                 },
             },
             remote_run: RemoteRun {
                 command: PathBuf::from("tests/api_remote_run/fake_agent.sh"),
                 use_sudo: false,
+                job_ttl: 3600,
             },
             shared_files: SharedFiles {
                 path: PathBuf::from("tests/api_shared_files"),
+                cleanup_frequency: 600,
+                max_size: 104_857_600,
             },
             shared_folder: SharedFolder {
                 path: PathBuf::from("tests/api_shared_folder"),
@@ -0,0 +1,314 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Rules applied to reports before insertion or forwarding
+//!
+//! Rules are evaluated in order and the first one matching a report decides
+//! what is done with it. Reports matching no rule are kept unchanged.
+//! Agent logs are matched on their own event type and on the other fields of
+//! the report they are linked to.
+
+use crate::data::{report::AgentLog, Report};
+use regex::Regex;
+use serde::Deserialize;
+use std::{convert::TryFrom, fmt};
+
+/// Replaces the message of redacted reports
+pub const REDACTED: &str = "[redacted]";
+
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pattern(Regex);
+
+impl TryFrom<String> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Regex::new(&pattern).map(Pattern)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0.as_str())
+    }
+}
+
+/// A string for an exact match or `{ regex = "..." }`
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(untagged)]
+pub enum Matcher {
+    Exact(String),
+    Regex { regex: Pattern },
+}
+
+impl Matcher {
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            Matcher::Exact(expected) => expected == value,
+            Matcher::Regex { regex } => regex.0.is_match(value),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Keep,
+    Drop,
+    /// Replace the message
+    Redact,
+    /// Limit the message to the given number of bytes
+    Truncate(usize),
+}
+
+/// Reports and agent logs
+pub trait Routed {
+    fn event_type(&self) -> &str;
+    fn rule_id(&self) -> &str;
+    fn directive_id(&self) -> &str;
+    fn component(&self) -> &str;
+    fn node_id(&self) -> &str;
+    fn msg_mut(&mut self) -> &mut String;
+}
+
+macro_rules! routed {
+    ($type:ty) => {
+        impl Routed for $type {
+            fn event_type(&self) -> &str {
+                &self.event_type
+            }
+            fn rule_id(&self) -> &str {
+                &self.rule_id
+            }
+            fn directive_id(&self) -> &str {
+                &self.directive_id
+            }
+            fn component(&self) -> &str {
+                &self.component
+            }
+            fn node_id(&self) -> &str {
+                &self.node_id
+            }
+            fn msg_mut(&mut self) -> &mut String {
+                &mut self.msg
+            }
+        }
+    };
+}
+
+routed!(Report);
+routed!(AgentLog);
+
+/// All given criteria must match, a rule without criteria matches every report
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+// Prevent a typo in a criterion from silently matching all reports
+#[serde(deny_unknown_fields)]
+pub struct RoutingRule {
+    pub event_type: Option<Matcher>,
+    pub rule_id: Option<Matcher>,
+    pub directive_id: Option<Matcher>,
+    pub component: Option<Matcher>,
+    pub node_id: Option<Matcher>,
+    pub action: Action,
+}
+
+impl RoutingRule {
+    pub fn matches<T: Routed>(&self, report: &T) -> bool {
+        [
+            (&self.event_type, report.event_type()),
+            (&self.rule_id, report.rule_id()),
+            (&self.directive_id, report.directive_id()),
+            (&self.component, report.component()),
+            (&self.node_id, report.node_id()),
+        ]
+        .iter()
+        .all(|(matcher, value)| matcher.as_ref().map_or(true, |m| m.matches(value)))
+    }
+}
+
+/// Applies the first matching rule to the report
+///
+/// Returns `None` if the report is dropped, and whether it was modified.
+/// Control reports are never modified to keep the run log consistent.
+pub fn route<T: Routed>(rules: &[RoutingRule], mut report: T) -> (Option<T>, bool) {
+    if report.event_type() == "control" {
+        return (Some(report), false);
+    }
+
+    match rules.iter().find(|r| r.matches(&report)).map(|r| r.action) {
+        None | Some(Action::Keep) => (Some(report), false),
+        Some(Action::Drop) => (None, true),
+        Some(Action::Redact) => {
+            let msg = report.msg_mut();
+            let modified = msg != REDACTED;
+            *msg = REDACTED.to_string();
+            (Some(report), modified)
+        }
+        Some(Action::Truncate(max_length)) => {
+            let msg = report.msg_mut();
+            let modified = msg.len() > max_length;
+            if modified {
+                let mut end = max_length;
+                while !msg.is_char_boundary(end) {
+                    end -= 1;
+                }
+                msg.truncate(end);
+            }
+            (Some(report), modified)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::RunLog;
+
+    #[derive(Deserialize)]
+    struct Rules {
+        rules: Vec<RoutingRule>,
+    }
+
+    fn rules(toml: &str) -> Vec<RoutingRule> {
+        toml::from_str::<Rules>(toml).unwrap().rules
+    }
+
+    fn report(event_type: &str, component: &str, msg: &str) -> Report {
+        let mut report = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap()
+        .reports
+        .remove(0);
+        report.event_type = event_type.to_string();
+        report.component = component.to_string();
+        report.msg = msg.to_string();
+        report
+    }
+
+    #[test]
+    fn it_parses_rules() {
+        assert_eq!(
+            rules(
+                r#"rules = [
+                    { event_type = "log_debug", action = "drop" },
+                    { component = { regex = "^Command" }, node_id = "root", action = { truncate = 10 } },
+                ]"#
+            ),
+            vec![
+                RoutingRule {
+                    event_type: Some(Matcher::Exact("log_debug".to_string())),
+                    rule_id: None,
+                    directive_id: None,
+                    component: None,
+                    node_id: None,
+                    action: Action::Drop,
+                },
+                RoutingRule {
+                    event_type: None,
+                    rule_id: None,
+                    directive_id: None,
+                    component: Some(Matcher::Regex {
+                        regex: Pattern::try_from("^Command".to_string()).unwrap()
+                    }),
+                    node_id: Some(Matcher::Exact("root".to_string())),
+                    action: Action::Truncate(10),
+                }
+            ]
+        );
+        assert!(
+            toml::from_str::<Rules>(r#"rules = [{ componnt = "Cron", action = "drop" }]"#).is_err()
+        );
+        assert!(toml::from_str::<Rules>(
+            r#"rules = [{ component = { regex = "(" }, action = "drop" }]"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn it_routes_reports() {
+        let rules = rules(
+            r#"rules = [
+                { event_type = "log_info", component = "Users", action = "keep" },
+                { event_type = "log_info", action = "drop" },
+                { component = { regex = "^Command" }, action = { truncate = 5 } },
+                { component = "Password", action = "redact" },
+                { action = "drop" },
+            ]"#,
+        );
+
+        assert_eq!(
+            route(&rules, report("log_info", "Users", "msg")),
+            (Some(report("log_info", "Users", "msg")), false)
+        );
+        assert_eq!(
+            route(&rules, report("log_info", "Cron", "msg")),
+            (None, true)
+        );
+        assert_eq!(
+            route(
+                &rules,
+                report("result_success", "Command execution", "déjà vu")
+            ),
+            (
+                Some(report("result_success", "Command execution", "déj")),
+                true
+            )
+        );
+        assert_eq!(
+            route(&rules, report("result_success", "Command execution", "ok")),
+            (
+                Some(report("result_success", "Command execution", "ok")),
+                false
+            )
+        );
+        assert_eq!(
+            route(&rules, report("result_success", "Password", "secret")),
+            (Some(report("result_success", "Password", REDACTED)), true)
+        );
+        assert_eq!(
+            route(&rules, report("result_success", "Cron", "msg")),
+            (None, true)
+        );
+        assert_eq!(
+            route(&rules, report("control", "start", "msg")),
+            (Some(report("control", "start", "msg")), false)
+        );
+    }
+}
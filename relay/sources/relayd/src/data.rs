@@ -29,9 +29,11 @@
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
 pub mod node;
+pub mod remote_run;
 pub mod report;
 pub mod runinfo;
 pub mod runlog;
+pub mod shared_file;
 
 pub use report::Report;
 pub use runinfo::RunInfo;
@@ -29,7 +29,10 @@
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::error::Error;
-use openssl::{stack::Stack, x509::X509};
+use openssl::{
+    stack::Stack,
+    x509::{X509Ref, X509},
+};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::{
@@ -170,7 +173,22 @@ impl NodesList {
             .and_then(|node| node.certificates.as_ref())
     }
 
-    fn id_from_cert(cert: &X509) -> Result<NodeId, Error> {
+    /// Certificates are self-signed, a client certificate is trusted when it
+    /// is one of the known certificates of the node it identifies.
+    pub fn is_known_certificate(&self, cert: &X509Ref) -> bool {
+        let id = match Self::id_from_cert(cert) {
+            Ok(id) => id,
+            Err(_) => return false,
+        };
+        match (self.certs(&id), cert.to_der()) {
+            (Some(certs), Ok(der)) => certs
+                .iter()
+                .any(|known| known.to_der().map(|k| k == der).unwrap_or(false)),
+            _ => false,
+        }
+    }
+
+    pub fn id_from_cert(cert: &X509Ref) -> Result<NodeId, Error> {
         Ok(cert
             .subject_name()
             .entries()
@@ -183,7 +201,7 @@ impl NodesList {
     }
 
     /// Some(Next hop) if any, None if directly connected, error if not found
-    fn next_hop(&self, node_id: &NodeIdRef) -> Result<Option<NodeId>, ()> {
+    pub fn next_hop(&self, node_id: &NodeIdRef) -> Result<Option<NodeId>, ()> {
         // nodeslist should not contain loops but just in case
         // 20 levels of relays should be more than enough
         const MAX_RELAY_LEVELS: u8 = 20;
@@ -215,6 +233,25 @@ impl NodesList {
         next_hop
     }
 
+    /// Relays between the node and this node, starting from the node
+    pub fn relays(&self, node_id: &NodeIdRef) -> Vec<&NodeIdRef> {
+        // nodeslist should not contain loops but just in case
+        const MAX_RELAY_LEVELS: u8 = 20;
+
+        let mut relays = vec![];
+        let mut current = self.list.data.get(node_id);
+        for _ in 0..MAX_RELAY_LEVELS {
+            match current {
+                Some(node) if node.policy_server != self.my_id => {
+                    relays.push(node.policy_server.as_str());
+                    current = self.list.data.get(&node.policy_server);
+                }
+                _ => break,
+            }
+        }
+        relays
+    }
+
     // NOTE: Following methods could be made faster by pre-computing a graph in cache
 
     pub fn my_neighbors(&self) -> Vec<Host> {
@@ -360,6 +397,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_checks_known_certificates() {
+        use openssl::{hash::MessageDigest, nid::Nid, pkey::PKey, rsa::Rsa, x509::X509NameBuilder};
+
+        let nodeslist = NodesList::new(
+            "root".to_string(),
+            "tests/files/nodeslist.json",
+            Some("tests/files/keys/nodescerts.pem"),
+        )
+        .unwrap();
+
+        let known = X509::from_pem(
+            &read("tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert").unwrap(),
+        )
+        .unwrap();
+        assert!(nodeslist.is_known_certificate(&known));
+
+        // Same node id, other key
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::USERID, "e745a140-40bc-4b86-b6dc-084488fc906b")
+            .unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let unknown = builder.build();
+        assert_eq!(
+            NodesList::id_from_cert(&unknown).unwrap(),
+            "e745a140-40bc-4b86-b6dc-084488fc906b"
+        );
+        assert!(!nodeslist.is_known_certificate(&unknown));
+    }
+
     #[test]
     fn if_gets_subrelays() {
         assert!(
@@ -447,6 +520,36 @@ mod tests {
         assert_eq!(reference, actual);
     }
 
+    #[test]
+    fn it_gets_next_hop() {
+        let nodes = NodesList::new("root".to_string(), "tests/files/nodeslist.json", None).unwrap();
+        assert_eq!(
+            nodes.next_hop("37817c4d-fbf7-4850-a985-50021f4e8f41"),
+            Ok(None)
+        );
+        assert_eq!(
+            nodes.next_hop("b745a140-40bc-4b86-b6dc-084488fc906b"),
+            Ok(Some("e745a140-40bc-4b86-b6dc-084488fc906b".to_string()))
+        );
+        assert_eq!(nodes.next_hop("unknown"), Err(()));
+    }
+
+    #[test]
+    fn it_gets_relays() {
+        let nodes = NodesList::new("root".to_string(), "tests/files/nodeslist.json", None).unwrap();
+        assert!(nodes
+            .relays("37817c4d-fbf7-4850-a985-50021f4e8f41")
+            .is_empty());
+        assert_eq!(
+            nodes.relays("b745a140-40bc-4b86-b6dc-084488fc906b"),
+            vec![
+                "a745a140-40bc-4b86-b6dc-084488fc906b",
+                "e745a140-40bc-4b86-b6dc-084488fc906b"
+            ]
+        );
+        assert!(nodes.relays("unknown").is_empty());
+    }
+
     #[test]
     fn it_filters_sub_relays() {
         let mut reference = vec![(
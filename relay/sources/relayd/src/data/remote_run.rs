@@ -0,0 +1,479 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+use crate::{data::node::Host, error::Error};
+use chrono::{DateTime, Duration, Utc};
+use futures::{
+    future::{self, Either, Future, Shared},
+    sync::oneshot,
+    Async, Poll, Stream,
+};
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+pub type JobId = String;
+
+/// Output kept for each run, the rest is dropped
+const MAX_OUTPUT_SIZE: usize = 1024 * 1024;
+
+#[derive(Serialize, Debug, PartialEq, Eq, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Running,
+    Finished,
+    Cancelled,
+}
+
+/// Remote run on the nodes behind this relay, or forwarded to a sub-relay
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct Run {
+    /// Sub-relay the run was forwarded to, none for local runs
+    pub relay: Option<Host>,
+    /// Hosts of the triggered nodes for local runs, ids of the forwarded
+    /// nodes for sub-relays (all of their nodes if empty)
+    pub nodes: Vec<String>,
+    pub start: DateTime<Utc>,
+    pub end: Option<DateTime<Utc>>,
+    /// Exit code of the agent, for local runs
+    pub exit_code: Option<i32>,
+    /// Status of the sub-relay response
+    pub http_status: Option<u16>,
+    /// Job created on the sub-relay, cancelled along with this one
+    pub relay_job_id: Option<JobId>,
+    pub output: String,
+    /// Output was larger than the kept size
+    pub truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Run {
+    fn new(relay: Option<Host>, nodes: Vec<String>, start: DateTime<Utc>) -> Self {
+        Self {
+            relay,
+            nodes,
+            start,
+            end: None,
+            exit_code: None,
+            http_status: None,
+            relay_job_id: None,
+            output: String::new(),
+            truncated: false,
+            error: None,
+        }
+    }
+
+    fn push_output(&mut self, data: &[u8]) {
+        let available = MAX_OUTPUT_SIZE.saturating_sub(self.output.len());
+        if data.len() > available {
+            self.truncated = true;
+        }
+        self.output
+            .push_str(&String::from_utf8_lossy(&data[..data.len().min(available)]));
+    }
+}
+
+/// Remote run request
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct Job {
+    pub id: JobId,
+    pub status: JobStatus,
+    pub start: DateTime<Utc>,
+    pub end: Option<DateTime<Utc>>,
+    pub runs: Vec<Run>,
+}
+
+struct JobState {
+    job: Job,
+    /// All runs were added, the job ends with them
+    closed: bool,
+    /// Taken when cancelling
+    trigger: Option<oneshot::Sender<()>>,
+    cancelled: Shared<oneshot::Receiver<()>>,
+}
+
+impl JobState {
+    fn update_status(&mut self) {
+        let job = &mut self.job;
+        if job.status == JobStatus::Cancelled {
+            return;
+        }
+        if self.closed && job.runs.iter().all(|run| run.end.is_some()) {
+            job.status = JobStatus::Finished;
+            job.end = job.runs.iter().filter_map(|run| run.end).max();
+        } else {
+            job.status = JobStatus::Running;
+            job.end = None;
+        }
+    }
+
+    fn is_expired(&self, ttl: Duration, now: DateTime<Utc>) -> bool {
+        self.job.end.map(|end| now - end >= ttl).unwrap_or(false)
+    }
+}
+
+/// Registry of remote run jobs, kept until `ttl` after their end
+#[derive(Clone, Default)]
+pub struct Jobs {
+    jobs: Arc<Mutex<HashMap<JobId, JobState>>>,
+}
+
+impl Jobs {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<JobId, JobState>> {
+        self.jobs.lock().expect("could not lock remote run jobs")
+    }
+
+    fn expire(jobs: &mut HashMap<JobId, JobState>, ttl: Duration, now: DateTime<Utc>) {
+        jobs.retain(|_, state| !state.is_expired(ttl, now));
+    }
+
+    /// Creates a running job, which cannot expire before being closed
+    pub fn create(&self, ttl: Duration, now: DateTime<Utc>) -> JobId {
+        let id = format!("{:032x}", thread_rng().gen::<u128>());
+        let (trigger, cancelled) = oneshot::channel();
+        let mut jobs = self.lock();
+        Self::expire(&mut jobs, ttl, now);
+        jobs.insert(
+            id.clone(),
+            JobState {
+                job: Job {
+                    id: id.clone(),
+                    status: JobStatus::Running,
+                    start: now,
+                    end: None,
+                    runs: vec![],
+                },
+                closed: false,
+                trigger: Some(trigger),
+                cancelled: cancelled.shared(),
+            },
+        );
+        id
+    }
+
+    /// Adds a run to a job, returns a handle to record its results
+    pub fn add_run(
+        &self,
+        id: &str,
+        relay: Option<Host>,
+        nodes: Vec<String>,
+        now: DateTime<Utc>,
+    ) -> Result<RunHandle, Error> {
+        let mut jobs = self.lock();
+        let state = jobs
+            .get_mut(id)
+            .ok_or_else(|| Error::UnknownRemoteRunJob(id.to_string()))?;
+        state.job.runs.push(Run::new(relay, nodes, now));
+        state.update_status();
+        Ok(RunHandle {
+            jobs: self.clone(),
+            job: id.to_string(),
+            run: state.job.runs.len() - 1,
+            cancelled: state.cancelled.clone(),
+        })
+    }
+
+    /// Marks that all runs were added, the job finishes once they all end
+    pub fn close(&self, id: &str) {
+        if let Some(state) = self.lock().get_mut(id) {
+            state.closed = true;
+            state.update_status();
+        }
+    }
+
+    pub fn get(&self, id: &str, ttl: Duration, now: DateTime<Utc>) -> Result<Job, Error> {
+        let mut jobs = self.lock();
+        Self::expire(&mut jobs, ttl, now);
+        jobs.get(id)
+            .map(|state| state.job.clone())
+            .ok_or_else(|| Error::UnknownRemoteRunJob(id.to_string()))
+    }
+
+    /// Stops the running parts of a job, which kills local agent runs
+    ///
+    /// Returns the job along with the sub-relay jobs of the stopped runs, to cancel them too.
+    pub fn cancel(&self, id: &str, now: DateTime<Utc>) -> Result<(Job, Vec<(Host, JobId)>), Error> {
+        let mut jobs = self.lock();
+        let state = jobs
+            .get_mut(id)
+            .ok_or_else(|| Error::UnknownRemoteRunJob(id.to_string()))?;
+        let mut relay_jobs = vec![];
+        if state.job.status == JobStatus::Running {
+            if let Some(trigger) = state.trigger.take() {
+                // Receivers are only dropped once the runs are stopped
+                let _ = trigger.send(());
+            }
+            for run in state.job.runs.iter_mut().filter(|run| run.end.is_none()) {
+                run.end = Some(now);
+                if let (Some(relay), Some(relay_job_id)) = (&run.relay, &run.relay_job_id) {
+                    relay_jobs.push((relay.clone(), relay_job_id.clone()));
+                }
+            }
+            state.job.status = JobStatus::Cancelled;
+            state.job.end = Some(now);
+        }
+        Ok((state.job.clone(), relay_jobs))
+    }
+
+    /// Returns whether the job was cancelled
+    fn update<F: FnOnce(&mut Run)>(&self, id: &str, run: usize, f: F) -> bool {
+        match self.lock().get_mut(id) {
+            Some(state) => {
+                if let Some(run) = state.job.runs.get_mut(run) {
+                    f(run);
+                }
+                state.update_status();
+                state.job.status == JobStatus::Cancelled
+            }
+            None => false,
+        }
+    }
+}
+
+/// Records the results of a run
+#[derive(Clone)]
+pub struct RunHandle {
+    jobs: Jobs,
+    job: JobId,
+    run: usize,
+    cancelled: Shared<oneshot::Receiver<()>>,
+}
+
+impl RunHandle {
+    pub fn job_id(&self) -> &str {
+        &self.job
+    }
+
+    /// Resolves when the job is cancelled, never if it expired
+    pub fn cancelled(&self) -> impl Future<Item = (), Error = ()> + Send {
+        self.cancelled.clone().then(|res| match res {
+            Ok(_) => Either::A(future::ok(())),
+            Err(_) => Either::B(future::empty()),
+        })
+    }
+
+    pub fn push_output(&self, data: &[u8]) {
+        self.jobs
+            .update(&self.job, self.run, |run| run.push_output(data));
+    }
+
+    pub fn set_exit_code(&self, exit_code: Option<i32>) {
+        self.jobs
+            .update(&self.job, self.run, |run| run.exit_code = exit_code);
+    }
+
+    pub fn set_http_status(&self, http_status: u16) {
+        self.jobs.update(&self.job, self.run, |run| {
+            run.http_status = Some(http_status)
+        });
+    }
+
+    /// Returns whether the job was already cancelled, in which case the
+    /// sub-relay job has to be cancelled too
+    pub fn set_relay_job_id(&self, relay_job_id: JobId) -> bool {
+        self.jobs.update(&self.job, self.run, |run| {
+            run.relay_job_id = Some(relay_job_id)
+        })
+    }
+
+    /// Marks the run as ended, only the first call is taken into account
+    pub fn finish(&self, now: DateTime<Utc>) {
+        self.jobs.update(&self.job, self.run, |run| {
+            if run.end.is_none() {
+                run.end = Some(now);
+            }
+        });
+    }
+
+    pub fn fail(&self, error: &Error, now: DateTime<Utc>) {
+        self.jobs.update(&self.job, self.run, |run| {
+            run.error = Some(error.to_string());
+            if run.end.is_none() {
+                run.end = Some(now);
+            }
+        });
+    }
+
+    /// Records the stream items in the run output, and ends the stream when the job is cancelled
+    pub fn record<S>(self, stream: S) -> Recorded<S> {
+        Recorded { stream, run: self }
+    }
+}
+
+/// Stream recording its items in the output of a run
+pub struct Recorded<S> {
+    stream: S,
+    run: RunHandle,
+}
+
+impl<S> Stream for Recorded<S>
+where
+    S: Stream,
+    S::Item: AsRef<[u8]>,
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // The trigger is only dropped when the job expired
+        if let Ok(Async::Ready(_)) = self.run.cancelled.poll() {
+            return Ok(Async::Ready(None));
+        }
+        match self.stream.poll()? {
+            Async::Ready(Some(item)) => {
+                self.run.push_output(item.as_ref());
+                Ok(Async::Ready(Some(item)))
+            }
+            Async::Ready(None) => {
+                self.run.finish(Utc::now());
+                Ok(Async::Ready(None))
+            }
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    #[test]
+    fn it_records_runs() {
+        let jobs = Jobs::default();
+        let ttl = Duration::hours(1);
+        let now = Utc::now();
+        let id = jobs.create(ttl, now);
+        // Not expired before all runs are added
+        assert_eq!(
+            jobs.get(&id, ttl, now + Duration::hours(2)).unwrap().status,
+            JobStatus::Running
+        );
+
+        let local = jobs
+            .add_run(&id, None, vec!["node1.rudder.local".to_string()], now)
+            .unwrap();
+        let relay = jobs
+            .add_run(&id, Some("relay1.rudder.local".to_string()), vec![], now)
+            .unwrap();
+        jobs.close(&id);
+        assert_eq!(jobs.get(&id, ttl, now).unwrap().status, JobStatus::Running);
+
+        let output: Vec<&str> = local
+            .clone()
+            .record(stream::iter_ok::<_, ()>(vec!["OK\n", "END\n"]))
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(output, vec!["OK\n", "END\n"]);
+        local.set_exit_code(Some(0));
+        relay.set_http_status(500);
+        relay.fail(&Error::MissingTargetNodes, now + Duration::seconds(2));
+
+        let job = jobs.get(&id, ttl, now).unwrap();
+        assert_eq!(job.status, JobStatus::Finished);
+        assert_eq!(job.end, Some(now + Duration::seconds(2)));
+        assert_eq!(job.runs[0].output, "OK\nEND\n");
+        assert_eq!(job.runs[0].exit_code, Some(0));
+        assert_eq!(job.runs[0].http_status, None);
+        assert_eq!(job.runs[1].exit_code, None);
+        assert_eq!(job.runs[1].http_status, Some(500));
+        assert_eq!(job.runs[1].error, Some("missing target nodes".to_string()));
+
+        // Expired
+        assert!(jobs.get(&id, ttl, now + Duration::hours(2)).is_err());
+        assert!(jobs.add_run(&id, None, vec![], now).is_err());
+        // Expiration is not a cancellation
+        let output: Vec<&str> = local
+            .record(stream::iter_ok::<_, ()>(vec!["OK\n"]))
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(output, vec!["OK\n"]);
+    }
+
+    #[test]
+    fn it_finishes_jobs_without_runs() {
+        let jobs = Jobs::default();
+        let ttl = Duration::hours(1);
+        let now = Utc::now();
+        let id = jobs.create(ttl, now);
+        jobs.close(&id);
+        assert_eq!(jobs.get(&id, ttl, now).unwrap().status, JobStatus::Finished);
+    }
+
+    #[test]
+    fn it_cancels_jobs() {
+        let jobs = Jobs::default();
+        let ttl = Duration::hours(1);
+        let now = Utc::now();
+        let id = jobs.create(ttl, now);
+        let run = jobs.add_run(&id, None, vec![], now).unwrap();
+        let relay = jobs
+            .add_run(&id, Some("relay1.rudder.local".to_string()), vec![], now)
+            .unwrap();
+        assert!(!relay.set_relay_job_id("relay1-job".to_string()));
+        let pending = jobs
+            .add_run(&id, Some("relay2.rudder.local".to_string()), vec![], now)
+            .unwrap();
+        jobs.close(&id);
+
+        let (job, relay_jobs) = jobs.cancel(&id, now).unwrap();
+        assert_eq!(job.status, JobStatus::Cancelled);
+        assert_eq!(job.runs[0].end, Some(now));
+        assert_eq!(
+            relay_jobs,
+            vec![("relay1.rudder.local".to_string(), "relay1-job".to_string())]
+        );
+        // Sub-relay job known after the cancellation
+        assert!(pending.set_relay_job_id("relay2-job".to_string()));
+        assert!(jobs.cancel(&id, now).unwrap().1.is_empty());
+        assert!(run.cancelled().wait().is_ok());
+        let output: Vec<&str> = run
+            .record(stream::iter_ok::<_, ()>(vec!["OK\n"]))
+            .collect()
+            .wait()
+            .unwrap();
+        assert!(output.is_empty());
+        assert!(jobs.cancel("unknown", now).is_err());
+    }
+
+    #[test]
+    fn it_truncates_output() {
+        let mut run = Run::new(None, vec![], Utc::now());
+        run.push_output(&vec![b'a'; MAX_OUTPUT_SIZE - 1]);
+        run.push_output(b"bc");
+        assert!(run.truncated);
+        assert_eq!(run.output.len(), MAX_OUTPUT_SIZE);
+    }
+}
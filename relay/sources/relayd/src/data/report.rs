@@ -28,28 +28,28 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::{data::node::NodeId, output::database::schema::ruddersysevents};
+use crate::{
+    data::node::NodeId,
+    error::Error,
+    output::database::schema::{agent_logs, ruddersysevents},
+};
 use chrono::prelude::*;
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_till, take_until},
-    combinator::{map, map_res, not, opt},
-    multi::{many0, many1},
+    bytes::complete::{tag, take_until},
+    combinator::{map, map_res, not},
+    error::ErrorKind,
     IResult,
 };
 use serde::{Deserialize, Serialize};
-use std::fmt::{self, Display};
+use std::{
+    fmt::{self, Display},
+    io::BufRead,
+};
+use tracing::{debug, warn};
 
 type AgentLogLevel = &'static str;
 
-// A detail log entry
-#[derive(Debug, PartialEq, Eq)]
-struct LogEntry {
-    event_type: AgentLogLevel,
-    msg: String,
-    datetime: DateTime<FixedOffset>,
-}
-
 fn agent_log_level(i: &str) -> IResult<&str, AgentLogLevel> {
     alt((
         // CFEngine logs
@@ -82,60 +82,107 @@ fn non_rudder_report_begin(i: &str) -> IResult<&str, AgentLogLevel> {
     Ok((i, "log_info"))
 }
 
+fn log_begin(i: &str) -> IResult<&str, AgentLogLevel> {
+    let (i, event_type) = agent_log_level(i)?;
+    let (i, _) = tag(" ")(i)?;
+    Ok((i, event_type))
+}
+
 fn rudder_report_begin(i: &str) -> IResult<&str, &str> {
     let (i, _) = tag("R: @@")(i)?;
     // replace "" by ()?
     Ok((i, ""))
 }
 
-// TODO make a cheap version that does not parse the date?
-fn line_timestamp(i: &str) -> IResult<&str, DateTime<FixedOffset>> {
-    let (i, datetime) = map_res(take_until(" "), |d| DateTime::parse_from_str(d, "%+"))(i)?;
-    let (i, _) = tag(" ")(i)?;
-    Ok((i, datetime))
+/// Splits the timestamp starting a line from the rest of the line
+///
+/// Only checks that it looks like a timestamp, as parsing the date
+/// is expensive and only needed at the beginning of entries.
+fn line_timestamp(i: &str) -> Option<(&str, &str)> {
+    let b = i.as_bytes();
+    if b.len() < 20 || b[4] != b'-' || b[7] != b'-' || b[10] != b'T' || b[13] != b':' {
+        return None;
+    }
+    let end = i.find(' ')?;
+    Some((&i[..end], &i[end + 1..]))
 }
 
-fn simpleline(i: &str) -> IResult<&str, &str> {
-    let (i, _) = opt(line_timestamp)(i)?;
-    let (i, _) = not(alt((agent_log_level, map(tag("R: @@"), |_| ""))))(i)?;
-    // All reports should end with \r\n but keeping compatibility with simple
-    // \n for easier testing.
-    let (i, res) = take_till(|c| c == '\n' || c == '\r')(i)?;
-    let (i, _) = opt(tag("\r"))(i)?;
-    let (i, _) = tag("\n")(i)?;
-    Ok((i, res))
+/// Parses dates, reusing the previous result as consecutive lines
+/// generally share their timestamp
+#[derive(Debug, Default)]
+struct DateCache {
+    input: String,
+    datetime: Option<DateTime<FixedOffset>>,
 }
 
-fn multilines(i: &str) -> IResult<&str, Vec<&str>> {
-    let (i, res) = many1(simpleline)(i)?;
-    Ok((i, res))
+impl DateCache {
+    fn parse(&mut self, i: &str, format: &str) -> Option<DateTime<FixedOffset>> {
+        if self.datetime.is_none() || self.input != i {
+            self.datetime = DateTime::parse_from_str(i, format).ok();
+            self.input.clear();
+            self.input.push_str(i);
+        }
+        self.datetime
+    }
 }
 
-fn log_entry(i: &str) -> IResult<&str, LogEntry> {
-    let (i, datetime) = line_timestamp(i)?;
-    let (i, event_type) = agent_log_level(i)?;
-    let (i, _) = tag(" ")(i)?;
-    let (i, msg) = multilines(i)?;
-    Ok((
-        i,
-        LogEntry {
-            event_type,
-            msg: msg.join("\n"),
-            datetime,
-        },
-    ))
+/// All reports should end with \r\n but keeping compatibility with simple
+/// \n for easier testing.
+fn trim_line_ending(i: &str) -> &str {
+    let i = i.trim_end_matches('\n');
+    match i.as_bytes().last() {
+        Some(b'\r') => &i[..i.len() - 1],
+        _ => i,
+    }
 }
 
-fn log_entries(i: &str) -> IResult<&str, Vec<LogEntry>> {
-    many0(log_entry)(i)
+/// A line of a run log, without its line ending
+#[derive(Debug, PartialEq, Eq)]
+enum Line<'a> {
+    /// First line of an agent log
+    Log {
+        datetime: DateTime<FixedOffset>,
+        event_type: AgentLogLevel,
+        msg: &'a str,
+    },
+    /// First line of a Rudder report, starting with its fields
+    Report {
+        datetime: DateTime<FixedOffset>,
+        fields: &'a str,
+    },
+    /// Next line of a multiline entry, without its timestamp
+    Continuation(&'a str),
 }
 
-pub fn report(i: &str) -> IResult<&str, ParsedReport> {
-    let (i, logs) = log_entries(i)?;
-    let (i, execution_datetime) =
-        map_res(take_until(" "), |d| DateTime::parse_from_str(d, "%+"))(i)?;
-    let (i, _) = tag(" ")(i)?;
-    let (i, _) = rudder_report_begin(i)?;
+fn line<'a>(i: &'a str, dates: &mut DateCache) -> Line<'a> {
+    let (timestamp, rest) = match line_timestamp(i) {
+        Some(split) => split,
+        None => return Line::Continuation(i),
+    };
+
+    if let Ok((fields, _)) = rudder_report_begin(rest) {
+        if let Some(datetime) = dates.parse(timestamp, "%+") {
+            return Line::Report { datetime, fields };
+        }
+    } else if let Ok((msg, event_type)) = log_begin(rest) {
+        if let Some(datetime) = dates.parse(timestamp, "%+") {
+            return Line::Log {
+                datetime,
+                event_type,
+                msg,
+            };
+        }
+    }
+    Line::Continuation(rest)
+}
+
+/// Parses the fields of a report, after the "R: @@" tag. The message
+/// is the rest of the input.
+fn report<'a>(
+    i: &'a str,
+    execution_datetime: DateTime<FixedOffset>,
+    dates: &mut DateCache,
+) -> IResult<&'a str, ReportRef<'a>> {
     let (i, policy) = take_until("@@")(i)?;
     let (i, _) = tag("@@")(i)?;
     let (i, event_type) = take_until("@@")(i)?;
@@ -150,81 +197,227 @@ pub fn report(i: &str) -> IResult<&str, ParsedReport> {
     let (i, _) = tag("@@")(i)?;
     let (i, key_value) = take_until("@@")(i)?;
     let (i, _) = tag("@@")(i)?;
-    let (i, start_datetime) = map_res(take_until("##"), |d| {
-        DateTime::parse_from_str(d, "%Y-%m-%d %H:%M:%S%z")
-    })(i)?;
+    let (i, start_datetime) = take_until("##")(i)?;
+    let start_datetime = dates
+        .parse(start_datetime, "%Y-%m-%d %H:%M:%S%z")
+        .ok_or(nom::Err::Error((i, ErrorKind::MapRes)))?;
     let (i, _) = tag("##")(i)?;
     let (i, node_id) = take_until("@#")(i)?;
-    let (i, _) = tag("@#")(i)?;
-    let (i, msg) = multilines(i)?;
+    let (msg, _) = tag("@#")(i)?;
     Ok((
-        i,
-        Ok(RawReport {
-            report: Report {
-                // We could skip parsing it but it would prevent consistency check that cannot
-                // be done once inserted.
-                execution_datetime,
-                node_id: node_id.to_string(),
-                rule_id: rule_id.to_string(),
-                directive_id: directive_id.to_string(),
-                serial,
-                component: component.to_string(),
-                key_value: key_value.to_string(),
-                start_datetime,
-                event_type: event_type.to_string(),
-                msg: msg.join("\n"),
-                policy: policy.to_string(),
-            },
-            logs,
-        }),
+        "",
+        ReportRef {
+            // We could skip parsing it but it would prevent consistency check that cannot
+            // be done once inserted.
+            execution_datetime,
+            node_id,
+            rule_id,
+            directive_id,
+            serial,
+            component,
+            key_value,
+            start_datetime,
+            event_type,
+            msg,
+            policy,
+        },
     ))
 }
 
-// Handle errors
-fn until_next(i: &str) -> IResult<&str, ParsedReport> {
-    // The line looking like a report
-    let (i, first) = take_until("R: @@")(i)?;
-    let (i, tag) = tag("R: @@")(i)?;
-    // The end of the broken report
-    let (i, multi) = multilines(i)?;
-    let mut lines = first.to_string();
-    lines.push_str(tag);
-    for line in multi {
-        lines.push_str(line);
+/// Streaming run log parser
+///
+/// Reports are read one at a time, with the agent logs preceding them. They
+/// borrow their fields from a buffer reused for the next report, so that memory
+/// use does not depend on the size of the run log.
+pub struct ReportReader<R> {
+    reader: R,
+    /// Last read line, kept when it starts the next report
+    line: String,
+    pending: bool,
+    line_number: usize,
+    /// Entries of the current report, without timestamps, with "\n" between lines
+    buffer: String,
+    /// Start of each log in the buffer, each one ends where the next entry starts
+    logs: Vec<(AgentLogLevel, DateTime<FixedOffset>, usize)>,
+    execution_dates: DateCache,
+    start_dates: DateCache,
+}
+
+impl<R: BufRead> ReportReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+            pending: false,
+            line_number: 0,
+            buffer: String::new(),
+            logs: vec![],
+            execution_dates: DateCache::default(),
+            start_dates: DateCache::default(),
+        }
+    }
+
+    /// Next report with the agent logs preceding it, `None` at the end of the run log
+    ///
+    /// An invalid report gives an `InvalidReport` error, and reading can go on
+    /// with the next one. Agent logs at the end of the run log are not linked
+    /// to any report and are ignored.
+    pub fn next_report(&mut self) -> Result<Option<RawReportRef<'_>>, Error> {
+        self.buffer.clear();
+        self.logs.clear();
+        // Line number, execution date and start in the buffer
+        let mut report_start: Option<(usize, DateTime<FixedOffset>, usize)> = None;
+
+        loop {
+            if !self.pending {
+                self.line.clear();
+                if self.reader.read_line(&mut self.line)? == 0 {
+                    break;
+                }
+                self.line_number += 1;
+            }
+            self.pending = false;
+
+            match line(trim_line_ending(&self.line), &mut self.execution_dates) {
+                Line::Log { .. } | Line::Report { .. } if report_start.is_some() => {
+                    self.pending = true;
+                    break;
+                }
+                Line::Log {
+                    datetime,
+                    event_type,
+                    msg,
+                } => {
+                    self.logs.push((event_type, datetime, self.buffer.len()));
+                    self.buffer.push_str(msg);
+                }
+                Line::Report { datetime, fields } => {
+                    report_start = Some((self.line_number, datetime, self.buffer.len()));
+                    self.buffer.push_str(fields);
+                }
+                Line::Continuation(text) if report_start.is_none() && self.logs.is_empty() => {
+                    warn!(
+                        "Ignoring line {} outside of any report: {}",
+                        self.line_number, text
+                    );
+                }
+                Line::Continuation(text) => {
+                    self.buffer.push('\n');
+                    self.buffer.push_str(text);
+                }
+            }
+        }
+
+        let (line, execution_datetime, start) = match report_start {
+            Some(report_start) => report_start,
+            None => {
+                if !self.logs.is_empty() {
+                    debug!(
+                        "Ignoring {} agent logs after the last report",
+                        self.logs.len()
+                    );
+                }
+                return Ok(None);
+            }
+        };
+
+        let buffer = &self.buffer;
+        let report = match report(&buffer[start..], execution_datetime, &mut self.start_dates) {
+            Ok((_, report)) => report,
+            Err(_) => {
+                return Err(Error::InvalidReport {
+                    line,
+                    report: buffer[start..].to_string(),
+                })
+            }
+        };
+        let logs = self
+            .logs
+            .iter()
+            .enumerate()
+            .map(|(index, (event_type, datetime, log_start))| LogRef {
+                event_type,
+                datetime: *datetime,
+                msg: &buffer[*log_start..self.logs.get(index + 1).map_or(start, |l| l.2)],
+            })
+            .collect();
+        Ok(Some(RawReportRef { report, logs, line }))
     }
-    Ok((i, Err(lines)))
 }
 
-fn maybe_report(i: &str) -> IResult<&str, ParsedReport> {
-    alt((report, until_next))(i)
+/// Agent log borrowed from the run log
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct LogRef<'a> {
+    /// `log_warn`, `log_info` or `log_debug`
+    pub event_type: &'static str,
+    pub msg: &'a str,
+    pub datetime: DateTime<FixedOffset>,
 }
 
-pub fn runlog(i: &str) -> IResult<&str, Vec<ParsedReport>> {
-    many1(maybe_report)(i)
+/// Report borrowed from the run log
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ReportRef<'a> {
+    pub start_datetime: DateTime<FixedOffset>,
+    pub rule_id: &'a str,
+    pub directive_id: &'a str,
+    pub component: &'a str,
+    pub key_value: &'a str,
+    pub event_type: &'a str,
+    pub msg: &'a str,
+    pub policy: &'a str,
+    pub node_id: &'a str,
+    pub execution_datetime: DateTime<FixedOffset>,
+    pub serial: i32,
 }
 
-pub type ParsedReport = Result<RawReport, String>;
+impl<'a> ReportRef<'a> {
+    pub fn to_report(&self) -> Report {
+        Report {
+            start_datetime: self.start_datetime,
+            rule_id: self.rule_id.to_string(),
+            directive_id: self.directive_id.to_string(),
+            component: self.component.to_string(),
+            key_value: self.key_value.to_string(),
+            event_type: self.event_type.to_string(),
+            msg: self.msg.to_string(),
+            policy: self.policy.to_string(),
+            node_id: self.node_id.to_string(),
+            execution_datetime: self.execution_datetime,
+            serial: self.serial,
+        }
+    }
+}
 
-// We could make RawReport insertable to avoid copying context to simple logs
+/// Report with the agent logs preceding it
 #[derive(Debug, PartialEq, Eq)]
-pub struct RawReport {
-    report: Report,
-    logs: Vec<LogEntry>,
+pub struct RawReportRef<'a> {
+    pub report: ReportRef<'a>,
+    pub logs: Vec<LogRef<'a>>,
+    /// Line of the report in the run log
+    pub line: usize,
 }
 
-impl RawReport {
-    pub fn into_reports(self) -> Vec<Report> {
-        let mut res = vec![];
-        for log in self.logs {
-            res.push(Report {
+impl<'a> RawReportRef<'a> {
+    /// The report and the agent logs preceding it
+    pub fn into_parts(self) -> (Report, Vec<AgentLog>) {
+        let report = self.report.to_report();
+        let logs = self
+            .logs
+            .into_iter()
+            .map(|log| AgentLog {
+                start_datetime: report.start_datetime,
+                rule_id: report.rule_id.clone(),
+                directive_id: report.directive_id.clone(),
+                component: report.component.clone(),
+                key_value: report.key_value.clone(),
+                serial: report.serial,
+                node_id: report.node_id.clone(),
                 event_type: log.event_type.to_string(),
-                msg: log.msg,
+                msg: log.msg.to_string(),
                 execution_datetime: log.datetime,
-                ..self.report.clone()
             })
-        }
-        res.push(self.report);
-        res
+            .collect();
+        (report, logs)
     }
 }
 
@@ -298,6 +491,65 @@ impl Display for Report {
     }
 }
 
+/// Log entry written by the agent, linked to the report following it
+/// in the run log
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Insertable)]
+#[table_name = "agent_logs"]
+pub struct AgentLog {
+    #[column_name = "execution_timestamp"]
+    pub start_datetime: DateTime<FixedOffset>,
+    pub rule_id: String,
+    pub directive_id: String,
+    pub component: String,
+    pub key_value: String,
+    pub serial: i32,
+    pub node_id: NodeId,
+    /// `log_warn`, `log_info` or `log_debug`
+    pub event_type: String,
+    pub msg: String,
+    #[column_name = "execution_date"]
+    pub execution_datetime: DateTime<FixedOffset>,
+}
+
+impl AgentLog {
+    /// Report row, as logs were stored in `ruddersysevents` before having their
+    /// own table
+    pub fn legacy_report(&self, report: &Report) -> Report {
+        Report {
+            event_type: self.event_type.clone(),
+            msg: self.msg.clone(),
+            execution_datetime: self.execution_datetime,
+            ..report.clone()
+        }
+    }
+
+    /// Higher is more severe
+    pub fn severity(&self) -> u8 {
+        match self.event_type.as_ref() {
+            "log_warn" => 2,
+            "log_info" => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Agent log line, as parsed in run logs
+impl Display for AgentLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.execution_datetime.to_rfc3339(),
+            match self.event_type.as_ref() {
+                "log_warn" => " warning:",
+                "log_debug" => "   debug:",
+                _ => "    info:",
+            },
+            self.msg
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -335,191 +587,130 @@ mod tests {
     }
 
     #[test]
-    fn it_parses_log_level() {
-        assert_eq!(agent_log_level("CRITICAL: toto").unwrap().1, "log_warn")
+    fn it_writes_agent_logs() {
+        let line = "2019-05-09T13:36:46+00:00  warning: toto\ntruc\n";
+        let runlog = format!("{}2018-08-24T15:55:01+00:00 R: @@Common@@result_repaired@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@2018-08-24 15:55:01 +00:00##root@#Cron daemon status was repaired\n", line);
+        let mut reader = ReportReader::new(runlog.as_bytes());
+        let (report, mut logs) = reader.next_report().unwrap().unwrap().into_parts();
+        let log = logs.remove(0);
+        assert_eq!(log.rule_id, report.rule_id);
+        assert_eq!(log.msg, "toto\ntruc");
+        assert_eq!(format!("{}\n", log), line);
     }
 
     #[test]
-    fn it_parses_simpleline() {
-        assert_eq!(simpleline("Thething\n").unwrap().1, "Thething".to_string());
-        assert_eq!(
-            simpleline("Thething\r\n").unwrap().1,
-            "Thething".to_string()
-        );
-        assert_eq!(
-            simpleline("The thing\n").unwrap().1,
-            "The thing".to_string()
-        );
-        assert_eq!(
-            simpleline("2019-05-09T13:36:46+00:00 The thing\n")
-                .unwrap()
-                .1,
-            "The thing".to_string()
-        );
-        assert_eq!(
-            simpleline(
-                "2019-05-09T13:36:46+00:00 The thing\n2019-05-09T13:36:46+00:00 The other thing\n"
-            )
-            .unwrap()
-            .1,
-            "The thing".to_string()
-        );
-        assert_eq!(
-            simpleline("2019-05-09T13:36:46+00:00 The thing\n2019-05-09T13:36:46+00:00 R: report")
-                .unwrap()
-                .1,
-            "The thing".to_string()
-        );
-        assert!(simpleline("2019-05-09T13:36:46+00:00 R: The thing\nreport").is_err());
-        assert!(simpleline("2019-05-09T13:36:46+00:00 CRITICAL: plop\nreport").is_err());
+    fn it_parses_log_level() {
+        assert_eq!(agent_log_level("CRITICAL: toto").unwrap().1, "log_warn")
     }
 
     #[test]
-    fn it_parses_multilines() {
-        assert_eq!(
-            multilines("Thething\n").unwrap().1.join("\n"),
-            "Thething".to_string()
-        );
-        assert_eq!(
-            multilines("The thing\n").unwrap().1.join("\n"),
-            "The thing".to_string()
-        );
-        assert_eq!(
-            multilines("2019-05-09T13:36:46+00:00 The thing\n")
-                .unwrap()
-                .1
-                .join("\n"),
-            "The thing".to_string()
-        );
-        assert_eq!(
-            multilines(
-                "2019-05-09T13:36:46+00:00 The thing\n2019-05-09T13:36:46+00:00 The other thing\n"
-            )
-            .unwrap()
-            .1
-            .join("\n"),
-            "The thing\nThe other thing".to_string()
-        );
-        assert_eq!(
-            multilines("2019-05-09T13:36:46+00:00 The thing\n\n2019-05-09T13:36:46+00:00 The other thing\n")
-            .unwrap().1.join("\n"),
-            "The thing\n\nThe other thing".to_string()
-        );
+    fn it_parses_line() {
+        let datetime = DateTime::parse_from_str("2019-05-09T13:36:46+00:00", "%+").unwrap();
+        let dates = &mut DateCache::default();
+        assert_eq!(line("Thething", dates), Line::Continuation("Thething"));
+        assert_eq!(line("The thing", dates), Line::Continuation("The thing"));
         assert_eq!(
-            multilines("Thething\n2019-05-09T13:36:46+00:00 Theotherthing\n")
-                .unwrap()
-                .1
-                .join("\n"),
-            "Thething\nTheotherthing".to_string()
+            line("2019-05-09T13:36:46+00:00 The thing", dates),
+            Line::Continuation("The thing")
         );
-    }
-
-    #[test]
-    fn it_parses_log_entry() {
+        assert_eq!(line("", dates), Line::Continuation(""));
         assert_eq!(
-            log_entry("2019-05-09T13:36:46+00:00 CRITICAL: toto\n")
-                .unwrap()
-                .1,
-            LogEntry {
+            line("2019-05-09T13:36:46+00:00 CRITICAL: toto", dates),
+            Line::Log {
+                datetime,
                 event_type: "log_warn",
-                msg: "toto".to_string(),
-                datetime: DateTime::parse_from_str("2019-05-09T13:36:46+00:00", "%+").unwrap(),
+                msg: "toto"
             }
         );
         assert_eq!(
-            log_entry("2019-05-09T13:36:46+00:00 CRITICAL: toto\n2019-05-09T13:36:46+00:00 CRITICAL: toto2\n").unwrap().1,
-            LogEntry {
-                event_type: "log_warn",
-                msg: "toto".to_string(),
-                datetime: DateTime::parse_from_str("2019-05-09T13:36:46+00:00", "%+").unwrap(),
+            line("2019-05-09T13:36:46+00:00 R: The thing", dates),
+            Line::Log {
+                datetime,
+                event_type: "log_info",
+                msg: "The thing"
             }
         );
         assert_eq!(
-            log_entry("2019-05-09T13:36:46+00:00 CRITICAL: toto\n2019-05-09T13:36:46+00:00 truc\n")
-                .unwrap()
-                .1,
-            LogEntry {
-                event_type: "log_warn",
-                msg: "toto\ntruc".to_string(),
-                datetime: DateTime::parse_from_str("2019-05-09T13:36:46+00:00", "%+").unwrap(),
+            line("2019-05-09T13:36:46+00:00 R: @@Common@@broken", dates),
+            Line::Report {
+                datetime,
+                fields: "Common@@broken"
             }
         );
         assert_eq!(
-            log_entry("2019-05-09T13:36:46+00:00 CRITICAL: toto\ntruc\n")
-                .unwrap()
-                .1,
-            LogEntry {
-                event_type: "log_warn",
-                msg: "toto\ntruc".to_string(),
-                datetime: DateTime::parse_from_str("2019-05-09T13:36:46+00:00", "%+").unwrap(),
-            }
+            trim_line_ending("2019-05-09T13:36:46+00:00 The thing\r\n"),
+            "2019-05-09T13:36:46+00:00 The thing"
         );
+        assert_eq!(trim_line_ending("Thething\n"), "Thething");
     }
 
     #[test]
-    fn it_parses_log_entries() {
+    fn it_reads_log_entries() {
+        let runlog = "2019-05-09T13:36:46+00:00 CRITICAL: toto\n2018-05-09T13:36:46+00:00 suite\nend\n\n2017-05-09T13:36:46+00:00 CRITICAL: tutu\n2018-08-24T15:55:01+00:00 R: @@Common@@result_repaired@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@2018-08-24 15:55:01 +00:00##root@#Cron daemon status was repaired\n";
+        let mut reader = ReportReader::new(runlog.as_bytes());
+        let raw = reader.next_report().unwrap().unwrap();
         assert_eq!(
-            log_entries("2019-05-09T13:36:46+00:00 CRITICAL: toto\n2018-05-09T13:36:46+00:00 suite\nend\n2017-05-09T13:36:46+00:00 CRITICAL: tutu\n")
-                .unwrap()
-                .1,
+            raw.logs,
             vec![
-                LogEntry {
+                LogRef {
                     event_type: "log_warn",
-                    msg: "toto\nsuite\nend".to_string(),
+                    msg: "toto\nsuite\nend\n",
                     datetime: DateTime::parse_from_str("2019-05-09T13:36:46+00:00", "%+").unwrap(),
                 },
-                LogEntry {
+                LogRef {
                     event_type: "log_warn",
-                    msg: "tutu".to_string(),
+                    msg: "tutu",
                     datetime: DateTime::parse_from_str("2017-05-09T13:36:46+00:00", "%+").unwrap(),
                 }
             ]
-        )
-    }
-
-    #[test]
-    fn it_parses_report() {
-        let report = "2018-08-24T15:55:01+00:00 R: @@Common@@result_repaired@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@2018-08-24 15:55:01 +00:00##root@#Cron daemon status was repaired\r\n";
-        assert_eq!(
-            maybe_report(report).unwrap().1.unwrap(),
-            RawReport {
-                report: Report {
-                    start_datetime: DateTime::parse_from_str(
-                        "2018-08-24 15:55:01+00:00",
-                        "%Y-%m-%d %H:%M:%S%z"
-                    )
-                    .unwrap(),
-                    rule_id: "hasPolicyServer-root".into(),
-                    directive_id: "common-root".into(),
-                    component: "CRON Daemon".into(),
-                    key_value: "None".into(),
-                    event_type: "result_repaired".into(),
-                    msg: "Cron daemon status was repaired".into(),
-                    policy: "Common".into(),
-                    node_id: "root".into(),
-                    serial: 0,
-                    execution_datetime: DateTime::parse_from_str(
-                        "2018-08-24 15:55:01+00:00",
-                        "%Y-%m-%d %H:%M:%S%z"
-                    )
-                    .unwrap(),
-                },
-                logs: vec![],
-            }
-        );
-        let report = "2018-08-24T15:55:01+00:00 R: @@Common@@broken\n";
-        assert_eq!(
-            maybe_report(report).unwrap().1,
-            Err("2018-08-24T15:55:01+00:00 R: @@Common@@broken".to_string())
         );
+        assert_eq!(raw.line, 6);
+        assert_eq!(raw.report.msg, "Cron daemon status was repaired");
+        assert!(reader.next_report().unwrap().is_none());
     }
 
     #[test]
-    fn it_parses_until_next() {
-        let report = "test\n2018-08-24T15:55:01+00:00 R: @@Common@@broken\n";
+    fn it_reads_reports() {
+        let runlog = "test\n2018-08-24T15:55:01+00:00 R: @@Common@@result_repaired@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@2018-08-24 15:55:01 +00:00##root@#Cron daemon status was repaired\r\n2018-08-24T15:55:01+00:00 on two lines\r\n2018-08-24T15:55:01+00:00 R: @@Common@@broken\n2018-08-24T15:55:01+00:00 R: @@Common@@result_success@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@2018-08-24 15:55:01 +00:00##root@#Cron daemon is running\n2019-05-09T13:36:46+00:00 CRITICAL: trailing log\n";
+        let mut reader = ReportReader::new(runlog.as_bytes());
+        let raw = reader.next_report().unwrap().unwrap();
+        assert_eq!(raw.line, 2);
+        assert_eq!(raw.logs, vec![]);
         assert_eq!(
-            until_next(report).unwrap().1,
-            Err("test\n2018-08-24T15:55:01+00:00 R: @@Common@@broken".to_string())
+            raw.report.to_report(),
+            Report {
+                start_datetime: DateTime::parse_from_str(
+                    "2018-08-24 15:55:01+00:00",
+                    "%Y-%m-%d %H:%M:%S%z"
+                )
+                .unwrap(),
+                rule_id: "hasPolicyServer-root".into(),
+                directive_id: "common-root".into(),
+                component: "CRON Daemon".into(),
+                key_value: "None".into(),
+                event_type: "result_repaired".into(),
+                msg: "Cron daemon status was repaired\non two lines".into(),
+                policy: "Common".into(),
+                node_id: "root".into(),
+                serial: 0,
+                execution_datetime: DateTime::parse_from_str(
+                    "2018-08-24 15:55:01+00:00",
+                    "%Y-%m-%d %H:%M:%S%z"
+                )
+                .unwrap(),
+            }
         );
+        match reader.next_report() {
+            Err(Error::InvalidReport { line, report }) => {
+                assert_eq!(line, 4);
+                assert_eq!(report, "Common@@broken");
+            }
+            _ => panic!("should be an invalid report"),
+        }
+        let raw = reader.next_report().unwrap().unwrap();
+        assert_eq!(raw.line, 5);
+        assert_eq!(raw.report.event_type, "result_success");
+        assert!(reader.next_report().unwrap().is_none());
+        assert!(reader.next_report().unwrap().is_none());
     }
 }
@@ -31,6 +31,7 @@
 use crate::{data::node::NodeId, error::Error};
 use chrono::prelude::*;
 use nom::{
+    branch::alt,
     bytes::complete::{tag, take_until},
     combinator::{map_res, opt},
     IResult,
@@ -48,6 +49,12 @@ use tracing::debug;
 pub struct RunInfo {
     pub node_id: NodeId,
     pub timestamp: DateTime<FixedOffset>,
+    /// Given by the reports, once the run log is parsed
+    #[serde(default)]
+    pub serial: Option<i32>,
+    /// Given by the run start report, once the run log is parsed
+    #[serde(default)]
+    pub config_id: Option<String>,
 }
 
 impl Display for RunInfo {
@@ -61,7 +68,7 @@ fn parse_runinfo(i: &str) -> IResult<&str, RunInfo> {
     let (i, _) = tag("@")(i)?;
     let (i, node_id) = take_until(".")(i)?;
     let (i, _) = tag(".log")(i)?;
-    let (i, _) = opt(tag(".gz"))(i)?;
+    let (i, _) = opt(alt((tag(".gz"), tag(".xz"), tag(".zst"))))(i)?;
 
     if node_id.is_empty() {
         Err(nom::Err::Error(("", nom::error::ErrorKind::Many1)))
@@ -71,6 +78,8 @@ fn parse_runinfo(i: &str) -> IResult<&str, RunInfo> {
             RunInfo {
                 timestamp,
                 node_id: node_id.to_string(),
+                serial: None,
+                config_id: None,
             },
         ))
     }
@@ -114,6 +123,8 @@ mod tests {
         let reference = RunInfo {
             timestamp: DateTime::parse_from_str("2018-08-24T15:55:01+00:00", "%+").unwrap(),
             node_id: "root".into(),
+            serial: None,
+            config_id: None,
         };
         assert_eq!(
             RunInfo::from_str("2018-08-24T15:55:01+00:00@root.log").unwrap(),
@@ -123,6 +134,14 @@ mod tests {
             RunInfo::from_str("2018-08-24T15:55:01+00:00@root.log.gz").unwrap(),
             reference
         );
+        assert_eq!(
+            RunInfo::from_str("2018-08-24T15:55:01+00:00@root.log.xz").unwrap(),
+            reference
+        );
+        assert_eq!(
+            RunInfo::from_str("2018-08-24T15:55:01+00:00@root.log.zst").unwrap(),
+            reference
+        );
     }
 
     #[test]
@@ -130,6 +149,8 @@ mod tests {
         let reference = RunInfo {
             timestamp: DateTime::parse_from_str("2018-08-24T15:55:01+00:00", "%+").unwrap(),
             node_id: "e745a140-40bc-4b86-b6dc-084488fc906b".into(),
+            serial: None,
+            config_id: None,
         };
         assert_eq!(
             RunInfo::from_str("2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log")
@@ -155,6 +176,8 @@ mod tests {
         let reference = RunInfo {
             timestamp: DateTime::parse_from_str("2018-08-24T15:55:01+00:00", "%+").unwrap(),
             node_id: "root".into(),
+            serial: None,
+            config_id: None,
         };
         assert_eq!(
             RunInfo::try_from(Path::new("2018-08-24T15:55:01+00:00@root.log")).unwrap(),
@@ -177,6 +200,8 @@ mod tests {
             let reference = RunInfo {
                 timestamp: DateTime::parse_from_str(&format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02}", y, m, d, h, min, s, pm, tmh, tmm), "%+").expect("invalid date"),
                 node_id: id.clone(),
+                serial: None,
+                config_id: None,
             };
 
             let runinfo = RunInfo::from_str(
@@ -29,28 +29,45 @@
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{
+    configuration::{
+        main::{AgentLogOutput, RunlogValidation},
+        routing::{route, RoutingRule},
+    },
     data::{
-        report::{runlog, RawReport},
+        node::NodeId,
+        report::{AgentLog, ReportReader},
         Report, RunInfo,
     },
     error::Error,
 };
+use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
     convert::TryFrom,
     fmt::{self, Display},
-    fs::read_to_string,
+    fs::File,
+    io::{BufRead, BufReader},
     path::Path,
     str::FromStr,
 };
-use tracing::{debug, error, warn};
+use tracing::{debug, warn};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct RunLog {
     pub info: RunInfo,
     // Never empty vec
     pub reports: Vec<Report>,
+    /// In the order of the reports they are linked to
+    pub logs: Vec<LinkedLog>,
+}
+
+/// Agent log, with the report it precedes in the run log
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LinkedLog {
+    /// Index of the report in `reports`
+    pub report: usize,
+    #[serde(flatten)]
+    pub log: AgentLog,
 }
 
 impl Display for RunLog {
@@ -73,86 +90,482 @@ impl RunLog {
                     Error::InvalidRunInfo(path.as_ref().to_str().unwrap_or("").to_string())
                 })?,
         )?;
-        RunLog::try_from((info, read_to_string(path)?.as_ref()))
+        RunLog::from_reader(
+            info,
+            BufReader::new(File::open(path)?),
+            RunlogValidation::Keep,
+        )
     }
 
-    pub fn without_types(&self, types: &HashSet<String>) -> Self {
-        Self {
-            info: self.info.clone(),
-            reports: self
-                .reports
-                .as_slice()
-                .iter()
-                .filter(|r| !types.contains(&r.event_type))
-                .cloned()
-                .collect(),
+    /// Parses a run log while reading it
+    pub fn from_reader<R: BufRead>(
+        info: RunInfo,
+        reader: R,
+        validation: RunlogValidation,
+    ) -> Result<Self, Error> {
+        let mut chunks = RunLog::chunks(info, reader, usize::max_value(), validation);
+        let runlog = chunks.next().unwrap_or(Err(Error::InconsistentRunlog))?;
+        // Inconsistencies are given once the whole run log is read
+        match chunks.next() {
+            Some(Err(e)) => Err(e),
+            _ => Ok(runlog),
         }
     }
+
+    /// Reads a run log by chunks of at most `size` reports with their agent logs,
+    /// to process it without keeping all its reports in memory
+    ///
+    /// Unless inconsistencies are kept, they are given as an error after the last chunk.
+    /// Reports from another node are never kept.
+    pub fn chunks<R: BufRead>(
+        info: RunInfo,
+        reader: R,
+        size: usize,
+        validation: RunlogValidation,
+    ) -> RunLogChunks<R> {
+        RunLogChunks {
+            info,
+            reader: ReportReader::new(reader),
+            size,
+            validation,
+            run: None,
+            previous_execution: None,
+            config_id: None,
+            has_start: false,
+            has_end: false,
+            ended: false,
+            violations: vec![],
+            kept_violations: 0,
+        }
+    }
+
+    /// Applies routing rules to the reports and agent logs, returns whether the
+    /// run log was modified
+    pub fn route(&mut self, rules: &[RoutingRule]) -> Result<bool, Error> {
+        let mut modified = false;
+        // New index of each report, if kept
+        let mut kept = Vec::with_capacity(self.reports.len());
+        let mut reports = Vec::with_capacity(self.reports.len());
+        for report in self.reports.drain(..) {
+            let (report, report_modified) = route(rules, report);
+            modified |= report_modified;
+            kept.push(report.as_ref().map(|_| reports.len()));
+            reports.extend(report);
+        }
+        self.reports = reports;
+        if self.reports.is_empty() {
+            return Err(Error::EmptyRunlog);
+        }
+
+        let logs = self.logs.len();
+        self.logs = self
+            .logs
+            .drain(..)
+            // Logs of dropped reports
+            .filter_map(|log| {
+                let report = kept[log.report]?;
+                let (log, log_modified) = route(rules, log.log);
+                modified |= log_modified;
+                log.map(|log| LinkedLog { report, log })
+            })
+            .collect();
+        Ok(modified || self.logs.len() != logs)
+    }
+
+    /// Applies the agent log output, returns whether the run log was modified
+    pub fn output_logs(&mut self, output: AgentLogOutput) -> bool {
+        if self.logs.is_empty() {
+            return false;
+        }
+        match output {
+            AgentLogOutput::Legacy | AgentLogOutput::Store => false,
+            AgentLogOutput::Drop => {
+                self.logs.clear();
+                true
+            }
+            AgentLogOutput::Summarize => {
+                let logs = self.logs.len();
+                self.logs = self
+                    .logs
+                    .drain(..)
+                    .fold(vec![], |mut summaries: Vec<(LinkedLog, usize)>, log| {
+                        match summaries.last_mut() {
+                            Some((summary, lines)) if summary.report == log.report => {
+                                *lines += log.log.msg.lines().count();
+                                if log.log.severity() > summary.log.severity() {
+                                    summary.log.event_type = log.log.event_type;
+                                }
+                            }
+                            _ => {
+                                let lines = log.log.msg.lines().count();
+                                summaries.push((log, lines))
+                            }
+                        }
+                        summaries
+                    })
+                    .into_iter()
+                    .map(|(mut summary, lines)| {
+                        let first_line = summary.log.msg.lines().next().unwrap_or("").to_string();
+                        summary.log.msg = if lines > 1 {
+                            format!("{} ({} more lines)", first_line, lines - 1)
+                        } else {
+                            first_line
+                        };
+                        summary
+                    })
+                    .collect();
+                self.logs.len() != logs
+            }
+        }
+    }
+
+    /// Moves the agent logs into the reports, before the report they are linked to,
+    /// as they were stored in `ruddersysevents` before having their own table
+    pub fn flatten_logs(&mut self) {
+        if self.logs.is_empty() {
+            return;
+        }
+        let mut reports = Vec::with_capacity(self.reports.len() + self.logs.len());
+        let mut logs = self.logs.drain(..).peekable();
+        for (index, report) in self.reports.drain(..).enumerate() {
+            while logs.peek().map(|log| log.report == index).unwrap_or(false) {
+                let log = logs.next().expect("peeked log");
+                reports.push(log.log.legacy_report(&report));
+            }
+            reports.push(report);
+        }
+        self.reports = reports;
+    }
+
+    /// Run log in the format sent by agents
+    pub fn agent_output(&self) -> String {
+        let mut output = String::new();
+        let mut logs = self.logs.iter().peekable();
+        for (index, report) in self.reports.iter().enumerate() {
+            while let Some(log) = logs.peek() {
+                if log.report != index {
+                    break;
+                }
+                for line in log.log.to_string().lines() {
+                    output.push_str(line);
+                    output.push_str("\r\n");
+                }
+                let _ = logs.next();
+            }
+            output.push_str(&format!(
+                "{} R: {}\r\n",
+                report.execution_datetime.to_rfc3339(),
+                report
+            ));
+        }
+        output
+    }
 }
 
 impl TryFrom<(RunInfo, &str)> for RunLog {
     type Error = Error;
 
     fn try_from(raw_reports: (RunInfo, &str)) -> Result<Self, Self::Error> {
-        match runlog(raw_reports.1) {
-            Ok(raw_runlog) => {
-                debug!("Parsed runlog {:#?}", raw_runlog.1);
-                let (reports, failed): (Vec<_>, Vec<_>) =
-                    raw_runlog.1.into_iter().partition(Result::is_ok);
-                for invalid_report in failed.into_iter().map(Result::unwrap_err) {
-                    warn!("Invalid report: {}", invalid_report);
-                }
+        RunLog::from_reader(
+            raw_reports.0,
+            raw_reports.1.as_bytes(),
+            RunlogValidation::Keep,
+        )
+    }
+}
 
-                let reports: Vec<RawReport> = reports.into_iter().map(Result::unwrap).collect();
-                RunLog::try_from((raw_reports.0, reports))
+/// Inconsistency found in a run log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Violation {
+    /// Line of the report, none for the whole run log
+    pub line: Option<usize>,
+    #[serde(flatten)]
+    pub kind: ViolationKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ViolationKind {
+    InvalidReport {
+        report: String,
+    },
+    WrongNodeId {
+        node_id: NodeId,
+        expected: NodeId,
+    },
+    WrongStartDatetime {
+        start_datetime: DateTime<FixedOffset>,
+        expected: DateTime<FixedOffset>,
+    },
+    WrongSerial {
+        serial: i32,
+        expected: i32,
+    },
+    /// Execution time before the one of the previous report
+    NonMonotonicExecutionDatetime {
+        execution_datetime: DateTime<FixedOffset>,
+        previous: DateTime<FixedOffset>,
+    },
+    /// Run end with a different configuration than the run start
+    WrongConfigId {
+        config_id: String,
+        expected: String,
+    },
+    MissingStart,
+    MissingEnd,
+}
+
+impl ViolationKind {
+    /// The report does not belong to the run
+    fn invalidates_report(&self) -> bool {
+        match self {
+            ViolationKind::InvalidReport { .. }
+            | ViolationKind::WrongNodeId { .. }
+            | ViolationKind::WrongStartDatetime { .. }
+            | ViolationKind::WrongSerial { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// The run log cannot be trusted, whatever the validation setting
+    fn rejects_runlog(&self) -> bool {
+        match self {
+            ViolationKind::WrongNodeId { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        match &self.kind {
+            ViolationKind::InvalidReport { report } => write!(f, "invalid report '{}'", report),
+            ViolationKind::WrongNodeId { node_id, expected } => {
+                write!(f, "node id {} instead of {}", node_id, expected)
             }
-            Err(e) => {
-                warn!("{:?}: could not parse '{}'", e, raw_reports.0);
-                Err(Error::InvalidRunLog(format!("{:?}", e)))
+            ViolationKind::WrongStartDatetime {
+                start_datetime,
+                expected,
+            } => write!(f, "start time {} instead of {}", start_datetime, expected),
+            ViolationKind::WrongSerial { serial, expected } => {
+                write!(f, "serial {} instead of {}", serial, expected)
             }
+            ViolationKind::NonMonotonicExecutionDatetime {
+                execution_datetime,
+                previous,
+            } => write!(
+                f,
+                "execution time {} before previous report at {}",
+                execution_datetime, previous
+            ),
+            ViolationKind::WrongConfigId {
+                config_id,
+                expected,
+            } => write!(
+                f,
+                "configuration id {} instead of {} at run end",
+                config_id, expected
+            ),
+            ViolationKind::MissingStart => write!(f, "missing run start report"),
+            ViolationKind::MissingEnd => write!(f, "missing run end report"),
         }
     }
 }
 
-impl TryFrom<(RunInfo, Vec<RawReport>)> for RunLog {
-    type Error = Error;
+/// Inconsistencies of a run log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Violations(pub Vec<Violation>);
 
-    fn try_from(raw_reports: (RunInfo, Vec<RawReport>)) -> Result<Self, Self::Error> {
-        let reports: Vec<Report> = raw_reports
-            .1
-            .into_iter()
-            .flat_map(RawReport::into_reports)
-            .collect();
-        let info = raw_reports.0;
-        let timestamp = reports
-            .first()
-            .ok_or(Error::InconsistentRunlog)?
-            .start_datetime;
-
-        for report in &reports {
-            if info.node_id != report.node_id {
-                error!(
-                    "Wrong node id in report {:#?}, got {} but should be {}",
-                    report, report.node_id, info.node_id
-                );
-                return Err(Error::InconsistentRunlog);
+impl Violations {
+    /// Contains reports from another node, so the run log can only be rejected
+    pub fn rejects_runlog(&self) -> bool {
+        self.0.iter().any(|v| v.kind.rejects_runlog())
+    }
+}
+
+impl Display for Violations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, violation) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run log read by chunks of reports, checking its consistency
+pub struct RunLogChunks<R> {
+    info: RunInfo,
+    reader: ReportReader<R>,
+    size: usize,
+    validation: RunlogValidation,
+    /// Start time and serial of the run, given by the first report
+    run: Option<(DateTime<FixedOffset>, i32)>,
+    previous_execution: Option<DateTime<FixedOffset>>,
+    /// Given by the run start
+    config_id: Option<String>,
+    has_start: bool,
+    has_end: bool,
+    ended: bool,
+    violations: Vec<Violation>,
+    /// Only counted when inconsistencies are kept, to log them once
+    kept_violations: usize,
+}
+
+impl<R: BufRead> RunLogChunks<R> {
+    fn violation(&mut self, line: Option<usize>, kind: ViolationKind) {
+        let violation = Violation { line, kind };
+        if self.validation == RunlogValidation::Keep && !violation.kind.rejects_runlog() {
+            debug!("Inconsistent run log {}: {}", self.info, violation);
+            self.kept_violations += 1;
+        } else {
+            warn!("Inconsistent run log {}: {}", self.info, violation);
+            self.violations.push(violation);
+        }
+    }
+
+    /// Returns whether the report belongs to the run
+    fn check(&mut self, line: usize, report: &Report) -> bool {
+        let mut kinds = vec![];
+
+        if self.info.node_id != report.node_id {
+            kinds.push(ViolationKind::WrongNodeId {
+                node_id: report.node_id.clone(),
+                expected: self.info.node_id.clone(),
+            });
+        }
+        let (start_datetime, serial) = *self
+            .run
+            .get_or_insert((report.start_datetime, report.serial));
+        if start_datetime != report.start_datetime {
+            kinds.push(ViolationKind::WrongStartDatetime {
+                start_datetime: report.start_datetime,
+                expected: start_datetime,
+            });
+        }
+        if serial != report.serial {
+            kinds.push(ViolationKind::WrongSerial {
+                serial: report.serial,
+                expected: serial,
+            });
+        }
+        if let Some(previous) = self.previous_execution {
+            if report.execution_datetime < previous {
+                kinds.push(ViolationKind::NonMonotonicExecutionDatetime {
+                    execution_datetime: report.execution_datetime,
+                    previous,
+                });
+            }
+        }
+        self.previous_execution = Some(report.execution_datetime);
+        if report.event_type == "control" {
+            match report.component.as_ref() {
+                "start" => {
+                    self.has_start = true;
+                    if self.config_id.is_none() {
+                        self.config_id = Some(report.key_value.clone());
+                    }
+                }
+                "end" => {
+                    self.has_end = true;
+                    match self.config_id {
+                        Some(ref config_id) if *config_id != report.key_value => {
+                            kinds.push(ViolationKind::WrongConfigId {
+                                config_id: report.key_value.clone(),
+                                expected: config_id.clone(),
+                            })
+                        }
+                        _ => (),
+                    }
+                }
+                _ => (),
             }
-            if timestamp != report.start_datetime {
-                warn!(
-                    "Wrong execution timestamp in report {:#?}, got {} but should be {}",
-                    report, report.start_datetime, timestamp
-                );
+        }
+
+        let valid = !kinds.iter().any(ViolationKind::invalidates_report);
+        for kind in kinds {
+            self.violation(Some(line), kind);
+        }
+        valid
+    }
+
+    fn end(&mut self) {
+        self.ended = true;
+        if !self.has_start {
+            self.violation(None, ViolationKind::MissingStart);
+        }
+        if !self.has_end {
+            self.violation(None, ViolationKind::MissingEnd);
+        }
+        if self.kept_violations > 0 {
+            warn!(
+                "Inconsistent run log {}: kept the reports belonging to the run despite {} inconsistencies",
+                self.info, self.kept_violations
+            );
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for RunLogChunks<R> {
+    type Item = Result<RunLog, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut reports = vec![];
+        let mut logs = vec![];
+
+        while !self.ended && reports.len() < self.size {
+            let (line, (report, report_logs)) = match self.reader.next_report() {
+                Ok(Some(raw_report)) => (raw_report.line, raw_report.into_parts()),
+                Ok(None) => {
+                    self.end();
+                    continue;
+                }
+                Err(Error::InvalidReport { line, report }) => {
+                    self.violation(Some(line), ViolationKind::InvalidReport { report });
+                    continue;
+                }
+                Err(e) => return Some(Err(e)),
+            };
+            if self.check(line, &report) {
+                logs.extend(report_logs.into_iter().map(|log| LinkedLog {
+                    report: reports.len(),
+                    log,
+                }));
+                reports.push(report);
             }
         }
-        Ok(Self { info, reports })
+
+        if !reports.is_empty() {
+            Some(Ok(RunLog {
+                info: RunInfo {
+                    serial: self.run.map(|(_, serial)| serial),
+                    config_id: self.config_id.clone(),
+                    ..self.info.clone()
+                },
+                reports,
+                logs,
+            }))
+        } else if !self.violations.is_empty() {
+            Some(Err(Error::RunlogViolations(Violations(
+                self.violations.drain(..).collect(),
+            ))))
+        } else {
+            None
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::configuration::routing::{Action, Matcher};
     use chrono::DateTime;
-    use std::fs::read_dir;
+    use std::fs::{read_dir, read_to_string};
 
     #[test]
     fn it_parses_runlog() {
@@ -173,6 +586,155 @@ mod tests {
         assert!(test_done > 1);
     }
 
+    #[test]
+    fn it_reads_runlog_by_chunks() {
+        let path =
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+        let runlog = RunLog::new(path).unwrap();
+        let chunks: Vec<RunLog> = RunLog::chunks(
+            runlog.info.clone(),
+            read_to_string(path).unwrap().as_bytes(),
+            10,
+            RunlogValidation::Keep,
+        )
+        .collect::<Result<_, _>>()
+        .unwrap();
+        assert_eq!(chunks.len(), 7);
+        assert!(chunks.iter().all(|c| c.reports.len() <= 10));
+        assert_eq!(
+            chunks
+                .iter()
+                .flat_map(|c| c.reports.iter())
+                .collect::<Vec<_>>(),
+            runlog.reports.iter().collect::<Vec<_>>()
+        );
+        // Log links are relative to the reports of their chunk
+        let linked = |r: &RunLog| {
+            r.logs
+                .iter()
+                .map(|l| (r.reports[l.report].clone(), l.log.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            chunks.iter().flat_map(linked).collect::<Vec<_>>(),
+            linked(&runlog)
+        );
+    }
+
+    #[test]
+    fn it_validates_runlog() {
+        let info =
+            RunInfo::from_str("2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log")
+                .unwrap();
+        let runlog = "2018-08-24T15:55:01+00:00 R: @@Common@@control@@rudder@@run@@0@@start@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##e745a140-40bc-4b86-b6dc-084488fc906b@#Start execution
+2018-08-24T15:55:02+00:00 R: @@Common@@result_success@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@2018-08-24 15:55:01+00:00##root@#Cron daemon status was correct
+2018-08-24T15:55:02+00:00 R: @@Common@@broken
+2018-08-24T15:55:01+00:00 R: @@Common@@result_success@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@2018-08-24 15:55:01+00:00##e745a140-40bc-4b86-b6dc-084488fc906b@#Cron daemon status was correct
+2018-08-24T15:55:03+00:00 R: @@Common@@result_success@@hasPolicyServer-root@@common-root@@1@@CRON Daemon@@None@@2018-08-24 15:55:01+00:00##e745a140-40bc-4b86-b6dc-084488fc906b@#Cron daemon status was correct
+2018-08-24T15:55:04+00:00 R: @@Common@@control@@rudder@@run@@0@@end@@20180824-130007-3ad37588@@2018-08-24 15:55:01+00:00##e745a140-40bc-4b86-b6dc-084488fc906b@#End execution
+";
+        let datetime = |d| DateTime::parse_from_str(d, "%+").unwrap();
+
+        // Reports from another node are never kept
+        match RunLog::from_reader(info.clone(), runlog.as_bytes(), RunlogValidation::Keep) {
+            Err(Error::RunlogViolations(violations)) => {
+                assert!(violations.rejects_runlog());
+                assert_eq!(violations.0.len(), 1);
+            }
+            _ => panic!("reports from another node should be rejected"),
+        }
+        let same_node: String = runlog
+            .lines()
+            .filter(|l| !l.ends_with("##root@#Cron daemon status was correct"))
+            .map(|l| format!("{}\n", l))
+            .collect();
+
+        // Only keeps reports belonging to the run
+        let kept = RunLog::from_reader(info.clone(), same_node.as_bytes(), RunlogValidation::Keep)
+            .unwrap();
+        assert_eq!(kept.reports.len(), 3);
+        // Kept inconsistencies are only counted
+        let mut chunks = RunLog::chunks(
+            info.clone(),
+            same_node.as_bytes(),
+            2,
+            RunlogValidation::Keep,
+        );
+        assert_eq!(
+            chunks
+                .by_ref()
+                .map(|chunk| chunk.unwrap().reports.len())
+                .sum::<usize>(),
+            3
+        );
+        assert_eq!(chunks.kept_violations, 3);
+        assert!(chunks.violations.is_empty());
+
+        match RunLog::from_reader(info.clone(), runlog.as_bytes(), RunlogValidation::Reject) {
+            Err(Error::RunlogViolations(violations)) => assert_eq!(
+                violations,
+                Violations(vec![
+                    Violation {
+                        line: Some(2),
+                        kind: ViolationKind::WrongNodeId {
+                            node_id: "root".to_string(),
+                            expected: "e745a140-40bc-4b86-b6dc-084488fc906b".to_string()
+                        }
+                    },
+                    Violation {
+                        line: Some(3),
+                        kind: ViolationKind::InvalidReport {
+                            report: "Common@@broken".to_string()
+                        }
+                    },
+                    Violation {
+                        line: Some(4),
+                        kind: ViolationKind::NonMonotonicExecutionDatetime {
+                            execution_datetime: datetime("2018-08-24T15:55:01+00:00"),
+                            previous: datetime("2018-08-24T15:55:02+00:00")
+                        }
+                    },
+                    Violation {
+                        line: Some(5),
+                        kind: ViolationKind::WrongSerial {
+                            serial: 1,
+                            expected: 0
+                        }
+                    },
+                    Violation {
+                        line: Some(6),
+                        kind: ViolationKind::WrongConfigId {
+                            config_id: "20180824-130007-3ad37588".to_string(),
+                            expected: "20180824-130007-3ad37587".to_string()
+                        }
+                    },
+                ])
+            ),
+            _ => panic!("inconsistencies should be rejected"),
+        }
+        match RunLog::from_reader(info.clone(), same_node.as_bytes(), RunlogValidation::Reject) {
+            Err(Error::RunlogViolations(violations)) => assert!(!violations.rejects_runlog()),
+            _ => panic!("inconsistencies should be rejected"),
+        }
+
+        let violations = match RunLog::from_reader(
+            info.clone(),
+            "2018-08-24T15:55:01+00:00 R: @@Common@@broken\n".as_bytes(),
+            RunlogValidation::Quarantine,
+        ) {
+            Err(Error::RunlogViolations(violations)) => violations,
+            _ => panic!("inconsistencies should be quarantined"),
+        };
+        assert_eq!(
+            violations.to_string(),
+            "line 1: invalid report 'Common@@broken', missing run start report, missing run end report"
+        );
+        assert_eq!(
+            serde_json::to_value(&violations.0[1]).unwrap(),
+            serde_json::json!({ "line": null, "type": "missing_start" })
+        );
+    }
+
     #[test]
     fn it_detect_invalid_node_in_runlog() {
         assert!(
@@ -181,89 +743,140 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_reads_run_info() {
+        let path =
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+        let runlog = RunLog::new(path).unwrap();
+        assert_eq!(runlog.info.serial, Some(0));
+        assert_eq!(
+            runlog.info.config_id,
+            Some("20180824-130007-3ad37587".to_string())
+        );
+
+        // Chunks without the run start still have the information of the run
+        let chunks: Vec<RunLog> = RunLog::chunks(
+            runlog.info.clone(),
+            BufReader::new(File::open(path).unwrap()),
+            10,
+            RunlogValidation::Keep,
+        )
+        .collect::<Result<_, _>>()
+        .unwrap();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.info == runlog.info));
+    }
+
     #[test]
     fn it_removes_logs_in_runlog() {
-        let mut filter = HashSet::new();
-        let _ = filter.insert("log_info".to_string());
+        let rules = vec![RoutingRule {
+            event_type: Some(Matcher::Exact("log_info".to_string())),
+            rule_id: None,
+            directive_id: None,
+            component: None,
+            node_id: None,
+            action: Action::Drop,
+        }];
+        let mut runlog = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+        assert_eq!(runlog.logs.len(), 4);
+        assert!(runlog.route(&rules).unwrap());
+        assert_eq!(runlog.reports.len(), 49);
+        assert_eq!(runlog.logs.len(), 3);
+        assert!(runlog.logs.iter().all(|l| l.log.event_type == "log_warn"));
+        assert!(!runlog.route(&rules).unwrap());
+    }
+
+    #[test]
+    fn it_links_logs_after_routing() {
+        let rules = vec![RoutingRule {
+            event_type: Some(Matcher::Exact("result_success".to_string())),
+            rule_id: None,
+            directive_id: None,
+            component: None,
+            node_id: None,
+            action: Action::Drop,
+        }];
+        let mut runlog =
+            RunLog::new("tests/files/runlogs/2019-12-02T14:24:20+00:00@root.log").unwrap();
+        let logs = runlog.logs.len();
+        assert!(runlog.route(&rules).unwrap());
+        assert!(!runlog.logs.is_empty());
+        assert!(runlog.logs.len() < logs);
+        for linked in &runlog.logs {
+            let report = &runlog.reports[linked.report];
+            assert_eq!(report.component, linked.log.component);
+            assert_eq!(report.key_value, linked.log.key_value);
+            assert_eq!(report.directive_id, linked.log.directive_id);
+        }
+    }
+
+    #[test]
+    fn it_outputs_agent_logs() {
+        let parsed =
+            || RunLog::new("tests/files/runlogs/2019-12-02T14:24:20+00:00@root.log").unwrap();
+        let runlog = parsed();
+        assert_eq!(runlog.logs.len(), 18);
+
+        let mut stored = parsed();
+        assert!(!stored.output_logs(AgentLogOutput::Store));
+        assert_eq!(stored, runlog);
+
+        let mut summarized = parsed();
+        assert!(summarized.output_logs(AgentLogOutput::Summarize));
+        assert_eq!(summarized.reports, runlog.reports);
+        assert_eq!(summarized.logs.len(), 15);
         assert_eq!(
-            RunLog {
-                info: RunInfo::from_str(
-                    "2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log"
-                )
-                .unwrap(),
-                reports: vec![
-                    Report {
-                        start_datetime: DateTime::parse_from_str(
-                            "2018-08-24 15:55:01+00:00",
-                            "%Y-%m-%d %H:%M:%S%z"
-                        )
-                        .unwrap(),
-                        rule_id: "hasPolicyServer-root".into(),
-                        directive_id: "common-root".into(),
-                        component: "CRON Daemon".into(),
-                        key_value: "None".into(),
-                        event_type: "result_repaired".into(),
-                        msg: "Cron daemon status was repaired".into(),
-                        policy: "Common".into(),
-                        node_id: "root".into(),
-                        serial: 0,
-                        execution_datetime: DateTime::parse_from_str(
-                            "2018-08-24 15:55:01+00:00",
-                            "%Y-%m-%d %H:%M:%S%z"
-                        )
-                        .unwrap(),
-                    },
-                    Report {
-                        start_datetime: DateTime::parse_from_str(
-                            "2018-08-24 15:55:01+00:00",
-                            "%Y-%m-%d %H:%M:%S%z"
-                        )
-                        .unwrap(),
-                        rule_id: "hasPolicyServer-root".into(),
-                        directive_id: "common-root".into(),
-                        component: "CRON Daemon".into(),
-                        key_value: "None".into(),
-                        event_type: "log_info".into(),
-                        msg: "Cron daemon status was repaired".into(),
-                        policy: "Common".into(),
-                        node_id: "root".into(),
-                        serial: 0,
-                        execution_datetime: DateTime::parse_from_str(
-                            "2018-08-24 15:55:01+00:00",
-                            "%Y-%m-%d %H:%M:%S%z"
-                        )
-                        .unwrap(),
-                    }
-                ]
-            }
-            .without_types(&filter),
-            RunLog {
-                info: RunInfo::from_str(
-                    "2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log"
-                )
-                .unwrap(),
-                reports: vec![Report {
-                    start_datetime: DateTime::parse_from_str(
-                        "2018-08-24 15:55:01+00:00",
-                        "%Y-%m-%d %H:%M:%S%z"
-                    )
-                    .unwrap(),
-                    rule_id: "hasPolicyServer-root".into(),
-                    directive_id: "common-root".into(),
-                    component: "CRON Daemon".into(),
-                    key_value: "None".into(),
-                    event_type: "result_repaired".into(),
-                    msg: "Cron daemon status was repaired".into(),
-                    policy: "Common".into(),
-                    node_id: "root".into(),
-                    serial: 0,
-                    execution_datetime: DateTime::parse_from_str(
-                        "2018-08-24 15:55:01+00:00",
-                        "%Y-%m-%d %H:%M:%S%z"
-                    )
-                    .unwrap(),
-                }]
-            }
+            summarized.logs[11].log.msg,
+            "package module: ErrorMessage=No provider of 'vim2' found. (2 more lines)"
+        );
+        assert_eq!(summarized.logs[12].log.event_type, "log_warn");
+
+        let mut dropped = parsed();
+        assert!(dropped.output_logs(AgentLogOutput::Drop));
+        assert_eq!(dropped.reports, runlog.reports);
+        assert!(dropped.logs.is_empty());
+    }
+
+    #[test]
+    fn it_writes_agent_output() {
+        let runlog = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+        assert_eq!(
+            RunLog::try_from((runlog.info.clone(), runlog.agent_output().as_ref())).unwrap(),
+            runlog
+        );
+    }
+
+    #[test]
+    fn it_flattens_logs() {
+        let parsed = || {
+            RunLog::new(
+                "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+            )
+            .unwrap()
+        };
+        let runlog = parsed();
+        let mut flattened = parsed();
+        flattened.flatten_logs();
+        assert!(flattened.logs.is_empty());
+        assert_eq!(
+            flattened.reports.len(),
+            runlog.reports.len() + runlog.logs.len()
+        );
+
+        // Each log comes right before its report, with the report's policy
+        let log = runlog.logs[0].report;
+        assert_eq!(flattened.reports[log].event_type, "log_info");
+        assert_eq!(flattened.reports[log].msg, runlog.logs[0].log.msg);
+        assert_eq!(flattened.reports[log + 1], runlog.reports[log]);
+        assert_eq!(
+            flattened.reports[log].policy,
+            flattened.reports[log + 1].policy
         );
     }
 }
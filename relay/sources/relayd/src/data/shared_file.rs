@@ -0,0 +1,186 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{error::Error, hashing::HashType};
+use chrono::prelude::*;
+use openssl::{
+    error::ErrorStack,
+    pkey::{PKey, Public},
+    rsa::Rsa,
+};
+use regex::Regex;
+use std::{fmt, str::FromStr};
+
+/// Signature metadata of a shared file, stored in a `.metadata` file next to it
+#[derive(Debug)]
+pub struct Metadata {
+    pub header: String,
+    pub algorithm: HashType,
+    pub digest: String,
+    pub hash_value: String,
+    pub short_pubkey: String,
+    pub hostname: String,
+    pub key_date: String,
+    pub key_id: String,
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "header={}", &self.header)?;
+        writeln!(f, "algorithm={}", &self.algorithm)?;
+        writeln!(f, "digest={}", &self.digest)?;
+        writeln!(f, "hash_value={}", &self.hash_value)?;
+        writeln!(f, "short_pubkey={}", &self.short_pubkey)?;
+        writeln!(f, "hostname={}", &self.hostname)?;
+        writeln!(f, "keydate={}", &self.key_date)?;
+        writeln!(f, "keyid={}", &self.key_id)
+    }
+}
+
+impl FromStr for Metadata {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if Metadata::parse_value("header", s)? != "rudder-signature-v1" {
+            return Err(Error::InvalidHeader);
+        }
+        Ok(Metadata {
+            header: Metadata::parse_value("header", s)?,
+            algorithm: HashType::from_str(&Metadata::parse_value("algorithm", s)?)?,
+            digest: Metadata::parse_value("digest", s)?,
+            hash_value: Metadata::parse_value("hash_value", s)?,
+            short_pubkey: Metadata::parse_value("short_pubkey", s)?,
+            hostname: Metadata::parse_value("hostname", s)?,
+            key_date: Metadata::parse_value("keydate", s)?,
+            key_id: Metadata::parse_value("keyid", s)?,
+        })
+    }
+}
+
+impl Metadata {
+    pub fn parse_value(key: &str, file: &str) -> Result<String, Error> {
+        let regex_key = Regex::new(&format!(r"{}=(?P<key>[^\n]+)\n", key)).unwrap();
+
+        match regex_key.captures(file) {
+            Some(capture) => match capture.name("key") {
+                Some(x) => Ok(x.as_str().to_string()),
+                _ => Err(Error::InvalidHeader),
+            },
+            None => Err(Error::InvalidHeader),
+        }
+    }
+
+    /// Public key of the signer, stored as a PKCS #1 PEM body
+    pub fn pubkey(&self) -> Result<PKey<Public>, ErrorStack> {
+        PKey::from_rsa(Rsa::public_key_from_pem_pkcs1(
+            format!(
+                "-----BEGIN RSA PUBLIC KEY-----\n{}\n-----END RSA PUBLIC KEY-----\n",
+                self.short_pubkey
+            )
+            .as_bytes(),
+        )?)
+    }
+
+    /// Line appended to the metadata, the expiration date is stored as a Unix timestamp
+    pub fn expires_line(expires: DateTime<Utc>) -> String {
+        format!("expires={}\n", expires.timestamp())
+    }
+
+    /// Reads the expiration date from a metadata file content
+    ///
+    /// Also accepts the date format written by previous versions.
+    pub fn parse_expires(file: &str) -> Result<DateTime<Utc>, Error> {
+        let value = Metadata::parse_value("expires", file)?;
+        value
+            .parse::<i64>()
+            .map(|timestamp| Utc.timestamp(timestamp, 0))
+            .or_else(|_| Utc.datetime_from_str(&value, "%Y-%m-%d %H:%M:%S%.f UTC"))
+            .map_err(|_| Error::InvalidExpiration(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn it_writes_the_metadata() {
+        let metadata = Metadata {
+            header: "rudder-signature-v1".to_string(),
+            algorithm: HashType::Sha256,
+            digest: "8ca9efc5752e133e2e80e2661c176fa50f".to_string(),
+            hash_value: "a75fda39a7af33eb93ab1c74874dcf66d5761ad30977368cf0c4788cf5bfd34f"
+                .to_string(),
+            short_pubkey: "shortpubkey".to_string(),
+            hostname: "ubuntu-18-04-64".to_string(),
+            key_date: "2018-10-3118:21:43.653257143".to_string(),
+            key_id: "B29D02BB".to_string(),
+        };
+
+        assert_eq!(format!("{}", metadata), "header=rudder-signature-v1\nalgorithm=sha256\ndigest=8ca9efc5752e133e2e80e2661c176fa50f\nhash_value=a75fda39a7af33eb93ab1c74874dcf66d5761ad30977368cf0c4788cf5bfd34f\nshort_pubkey=shortpubkey\nhostname=ubuntu-18-04-64\nkeydate=2018-10-3118:21:43.653257143\nkeyid=B29D02BB\n");
+    }
+
+    #[test]
+    pub fn it_parses_the_metadata() {
+        let metadata = Metadata::from_str(
+            &std::fs::read_to_string(
+                "tests/api_shared_files/37817c4d-fbf7-4850-a985-50021f4e8f41/37817c4d-fbf7-4850-a985-50021f4e8f41/file4.metadata",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(metadata.algorithm, HashType::Sha512);
+        assert_eq!(metadata.hostname, "ubuntu-18-04-64");
+        assert_eq!(metadata.key_date, "2018-10-31 18:21:43.653257143 +0000");
+        assert_eq!(metadata.key_id, "B29D02BB");
+
+        assert!(Metadata::from_str("header=rudder-signature-v2\n").is_err());
+        assert!(Metadata::from_str("algorithm=sha256\n").is_err());
+    }
+
+    #[test]
+    pub fn it_parses_expiration() {
+        let expires = Utc.ymd(2019, 8, 16).and_hms(13, 47, 41);
+        assert_eq!(
+            Metadata::parse_expires(&format!(
+                "keyid=B29D02BB\n{}",
+                Metadata::expires_line(expires)
+            ))
+            .unwrap(),
+            expires
+        );
+        assert_eq!(
+            Metadata::parse_expires("expires=2019-08-16 13:47:41.123456 UTC\n").unwrap(),
+            expires + chrono::Duration::microseconds(123_456)
+        );
+        assert!(Metadata::parse_expires("expires=tomorrow\n").is_err());
+        assert!(Metadata::parse_expires("keyid=B29D02BB\n").is_err());
+    }
+}
@@ -28,7 +28,7 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::data::node::NodeId;
+use crate::data::{node::NodeId, runlog::Violations};
 use chrono;
 use diesel;
 use serde_json;
@@ -40,6 +40,8 @@ use toml;
 pub enum Error {
     #[error("invalid run log: {0}")]
     InvalidRunLog(String),
+    #[error("invalid report at line {line}: {report}")]
+    InvalidReport { line: usize, report: String },
     #[error("invalid run info: {0}")]
     InvalidRunInfo(String),
     #[error("file name should be valid unicode")]
@@ -48,14 +50,28 @@ pub enum Error {
     InvalidFile(PathBuf),
     #[error("inconsistent run log")]
     InconsistentRunlog,
+    #[error("inconsistent run log: {0}")]
+    RunlogViolations(Violations),
+    #[error("unknown failed file: {0}")]
+    UnknownFailedFile(String),
     #[error("empty run log")]
     EmptyRunlog,
+    #[error("file is larger than {0} bytes")]
+    FileTooLarge(u64),
+    #[error("processing is stopped")]
+    ProcessingStopped,
+    #[error("invalid inventory: {0}")]
+    InvalidInventory(String),
+    #[error("invalid inventory signature: {0}")]
+    InvalidInventorySignature(String),
     #[error("missing id in certificate")]
     MissingIdInCertificate,
     #[error("certificate for unknown node: {0}")]
     CertificateForUnknownNode(NodeId),
     #[error("missing certificate for node: {0}")]
     MissingCertificateForNode(NodeId),
+    #[error("modifying run logs with upstream output requires a client certificate")]
+    MissingSigningCertificate,
     #[error("database error: {0}")]
     Database(#[from] diesel::result::Error),
     #[error("database connection error: {0}")]
@@ -78,6 +94,8 @@ pub enum Error {
     StrUtf8(#[from] std::str::Utf8Error),
     #[error("SSL error: {0}")]
     Ssl(#[from] openssl::error::ErrorStack),
+    #[error("refused signature digest algorithm: {0}")]
+    RefusedDigest(String),
     #[error("invalid condition: {condition:}, should match {condition_regex:}")]
     InvalidCondition {
         condition: String,
@@ -96,10 +114,16 @@ pub enum Error {
     GlobalLogger(#[from] tracing::dispatcher::SetGlobalDefaultError),
     #[error("logger setting error: {0}")]
     SetLogLogger(#[from] log::SetLoggerError),
+    #[error("missing log file for file output")]
+    MissingLogFile,
     #[error("invalid ttl: {0}")]
     InvalidTtl(String),
+    #[error("invalid expiration date: {0}")]
+    InvalidExpiration(String),
     #[error("missing target nodes")]
     MissingTargetNodes,
+    #[error("unknown remote run job: {0}")]
+    UnknownRemoteRunJob(String),
     #[error("invalid hash type provided {invalid:} (available hash types: {valid:})")]
     InvalidHashType {
         invalid: String,
@@ -111,4 +135,10 @@ pub enum Error {
     InvalidHeader,
     #[error("HTTP error: {0}")]
     HttpClient(#[from] reqwest::Error),
+    #[error("HTTP request timed out after {0}s")]
+    HttpTimeout(u64),
+    #[error("HTTP server error: {0}")]
+    HttpServer(#[from] warp::Error),
+    #[error("metrics error: {0}")]
+    Metrics(#[from] prometheus::Error),
 }
@@ -28,17 +28,101 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
+pub mod inventory;
+pub mod upload;
 pub mod watch;
 
-use crate::error::Error;
+use crate::{error::Error, processing::ReceivedFile, JobConfig};
 use flate2::read::GzDecoder;
+use futures::sync::mpsc;
 use openssl::{
     pkcs7::{Pkcs7, Pkcs7Flags},
     stack::Stack,
     x509::{store::X509StoreBuilder, X509},
 };
-use std::{ffi::OsStr, fs::read, io::Read, path::Path};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    ffi::OsStr,
+    fs::{read, File},
+    io::{self, BufRead, BufReader, Read},
+    mem,
+    path::Path,
+    sync::Arc,
+};
 use tracing::debug;
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// Source of the files to process
+///
+/// Files are written on disk before being sent to processing, whatever the way
+/// they are received, as processing moves them to the failed or retry directories.
+/// All inputs of a processing pipeline share its queue.
+pub trait Input {
+    /// Starts sending received files into the queue
+    fn start(&self, job_config: &Arc<JobConfig>, tx: &mpsc::Sender<ReceivedFile>);
+}
+
+/// Compression formats used by agents
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension().map(OsStr::to_str) {
+            Some(Some("gz")) => Some(Compression::Gzip),
+            Some(Some("xz")) => Some(Compression::Xz),
+            Some(Some("zst")) => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Detects compression from the magic number at the beginning of the data
+    fn from_magic(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if data.starts_with(&[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00]) {
+            Some(Compression::Xz)
+        } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    fn decoder<'a, R: Read + 'a>(self, reader: R) -> Result<Box<dyn Read + 'a>, Error> {
+        Ok(match self {
+            Compression::Gzip => Box::new(GzDecoder::new(reader)),
+            Compression::Xz => Box::new(XzDecoder::new(reader)),
+            Compression::Zstd => Box::new(ZstdDecoder::new(reader)?),
+        })
+    }
+
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut uncompressed_data = vec![];
+        self.decoder(data)?.read_to_end(&mut uncompressed_data)?;
+        Ok(uncompressed_data)
+    }
+}
+
+/// Compression of data read from `path`, based on its extension or content
+fn compression(path: &Path, data: &[u8]) -> Option<Compression> {
+    match Compression::from_extension(path) {
+        Some(compression) => Some(compression),
+        None => {
+            let detected = Compression::from_magic(data);
+            if let Some(compression) = detected {
+                debug!("{:?} looks like {:?} compressed content", path, compression);
+            }
+            detected
+        }
+    }
+}
 
 pub fn read_compressed_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
     let path = path.as_ref();
@@ -46,39 +130,201 @@ pub fn read_compressed_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
     debug!("Reading {:#?} content", path);
     let data = read(path)?;
 
-    Ok(match path.extension().map(OsStr::to_str) {
-        Some(Some("gz")) => {
-            debug!("{:?} has .gz extension, extracting", path);
-            let mut gz = GzDecoder::new(data.as_slice());
-            let mut uncompressed_data = vec![];
-            gz.read_to_end(&mut uncompressed_data)?;
-            uncompressed_data
+    match compression(path, &data) {
+        Some(compression) => {
+            debug!("{:?} is {:?} compressed, extracting", path, compression);
+            compression.decompress(&data)
         }
         // Let's assume everything else is a text file
-        _ => {
-            debug!("{:?} has no gz/xz extension, no extraction needed", path);
-            data
+        None => {
+            debug!("{:?} is not compressed, no extraction needed", path);
+            Ok(data)
+        }
+    }
+}
+
+/// Writes the uncompressed content of `path` into `destination`, by chunks,
+/// without keeping it in memory
+pub fn decompress_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    path: P,
+    destination: Q,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    let destination = destination.as_ref();
+
+    debug!("Extracting {:#?} content into {:#?}", path, destination);
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut decoder = match compression(path, reader.fill_buf()?) {
+        Some(compression) => {
+            debug!("{:?} is {:?} compressed, extracting", path, compression);
+            compression.decoder(reader)?
+        }
+        None => {
+            debug!("{:?} is not compressed, no extraction needed", path);
+            Box::new(reader)
         }
-    })
+    };
+    io::copy(&mut decoder, &mut File::create(destination)?)?;
+    Ok(())
+}
+
+/// File name of the uncompressed content of `path`
+pub fn uncompressed_file_name(path: &Path) -> Option<&OsStr> {
+    if Compression::from_extension(path).is_some() {
+        path.file_stem()
+    } else {
+        path.file_name()
+    }
+}
+
+/// Uncompressed content of data read from `path`, when the raw data is also needed
+pub fn uncompressed<'a>(path: &Path, data: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+    match compression(path, data) {
+        Some(compression) => compression.decompress(data).map(Cow::Owned),
+        None => Ok(Cow::Borrowed(data)),
+    }
+}
+
+/// Reads a DER encoded element, returns its tag, its content and the remaining data
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&first, mut data) = data.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        // Long form
+        let bytes = (first & 0x7f) as usize;
+        if bytes == 0 || bytes > mem::size_of::<usize>() || data.len() < bytes {
+            return None;
+        }
+        let len = data[..bytes]
+            .iter()
+            .fold(0, |len, &b| (len << 8) | b as usize);
+        data = &data[bytes..];
+        len
+    };
+    if data.len() < len {
+        return None;
+    }
+    Some((tag, &data[..len], &data[len..]))
+}
+
+/// Content of all the elements of a DER encoded SEQUENCE or SET
+fn der_children(mut data: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut children = vec![];
+    while !data.is_empty() {
+        let (tag, content, rest) = der_element(data)?;
+        children.push((tag, content));
+        data = rest;
+    }
+    Some(children)
+}
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_SET: u8 = 0x31;
+const DER_OID: u8 = 0x06;
+const DER_CONTEXT_0: u8 = 0xa0;
+
+/// Name of a digest algorithm from its DER encoded OID
+fn digest_name(oid: &[u8]) -> Option<&'static str> {
+    // 2.16.840.1.101.3.4.2
+    const NIST_HASH_ALGS: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02];
+
+    match oid {
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x05] => Some("md5"),
+        [0x2b, 0x0e, 0x03, 0x02, 0x1a] => Some("sha1"),
+        _ if oid.len() == NIST_HASH_ALGS.len() + 1 && oid.starts_with(NIST_HASH_ALGS) => {
+            match oid[NIST_HASH_ALGS.len()] {
+                0x01 => Some("sha256"),
+                0x02 => Some("sha384"),
+                0x03 => Some("sha512"),
+                0x04 => Some("sha224"),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Digest algorithms used by the signers of a DER encoded PKCS #7 signed message
+///
+/// ```text
+/// ContentInfo ::= SEQUENCE { contentType OID, content [0] EXPLICIT SignedData }
+/// SignedData ::= SEQUENCE { version, digestAlgorithms SET, contentInfo SEQUENCE,
+///                           certificates [0] OPTIONAL, crls [1] OPTIONAL, signerInfos SET }
+/// SignerInfo ::= SEQUENCE { version, issuerAndSerialNumber, digestAlgorithm SEQUENCE, ... }
+/// ```
+fn signer_digests(der: &[u8]) -> Option<Vec<Option<&'static str>>> {
+    let (tag, content_info, _) = der_element(der)?;
+    if tag != DER_SEQUENCE {
+        return None;
+    }
+    let (tag, signed_data) = *der_children(content_info)?.get(1)?;
+    if tag != DER_CONTEXT_0 {
+        return None;
+    }
+    let (tag, signed_data, _) = der_element(signed_data)?;
+    if tag != DER_SEQUENCE {
+        return None;
+    }
+    let (tag, signer_infos) = *der_children(signed_data)?.last()?;
+    if tag != DER_SET {
+        return None;
+    }
+
+    let mut digests = vec![];
+    for (tag, signer_info) in der_children(signer_infos)? {
+        if tag != DER_SEQUENCE {
+            return None;
+        }
+        let (tag, algorithm) = *der_children(signer_info)?.get(2)?;
+        if tag != DER_SEQUENCE {
+            return None;
+        }
+        let (tag, oid) = *der_children(algorithm)?.first()?;
+        if tag != DER_OID {
+            return None;
+        }
+        digests.push(digest_name(oid));
+    }
+    Some(digests)
 }
 
 /// Parses an S/MIME message as an UTF-8 string and validates the signature with the given
 /// certificates.
 /// * `input` is the signed content we want to check
 /// * `certs` are the known valid certs for the node we are checking signed content from
+/// * `allowed_digests` are the names of the digest algorithms accepted in signatures
 ///
 /// Signatures use the following openssl options
 /// * `-text` to add a text mime header, as it is not part of agent output
 ///   It also replaces all line endings by CRLF. It is necessary for the runlog
 ///   to be correctly read by this function.
 ///   Note: `-binary` is not valid S/MIME and default is missing the header.
-/// * `-md sha256` to force sha256 hash.
-///   FIXME: refuse weaker hashes in signature verification.
+/// * `-md sha256` to force sha256 hash. Messages using a digest algorithm
+///   outside of `allowed_digests` are refused.
 /// * `-nocerts` to avoid including certs in the signature, as we use the known
 ///   certificate on the server to validate signature and embedded certs are ignored.
-pub fn signature(input: &[u8], certs: &Stack<X509>) -> Result<String, Error> {
+pub fn signature(
+    input: &[u8],
+    certs: &Stack<X509>,
+    allowed_digests: &HashSet<String>,
+) -> Result<String, Error> {
     let (signature, content) = Pkcs7::from_smime(input)?;
 
+    match signer_digests(&signature.to_der()?) {
+        Some(ref digests) if !digests.is_empty() => {
+            for digest in digests {
+                match digest {
+                    Some(digest) if allowed_digests.contains(*digest) => (),
+                    Some(digest) => return Err(Error::RefusedDigest((*digest).to_string())),
+                    None => return Err(Error::RefusedDigest("unknown".to_string())),
+                }
+            }
+        }
+        _ => return Err(Error::RefusedDigest("unknown".to_string())),
+    }
+
     // An empty content is possible in S/MIME, but is it an
     // error in the Rudder context.
     let content = content.ok_or(Error::EmptyRunlog)?;
@@ -106,7 +352,12 @@ pub fn signature(input: &[u8], certs: &Stack<X509>) -> Result<String, Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::read_to_string;
+    use std::fs::{copy, read_to_string};
+    use tempfile::tempdir;
+
+    fn allowed_digests() -> HashSet<String> {
+        ["sha256", "sha512"].iter().map(|d| d.to_string()).collect()
+    }
 
     #[test]
     fn it_reads_gzipped_files() {
@@ -117,6 +368,35 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_reads_xz_files() {
+        let reference = read("tests/files/gz/normal.log").unwrap();
+        assert_eq!(
+            read_compressed_file("tests/files/gz/normal.log.xz").unwrap(),
+            reference
+        );
+    }
+
+    #[test]
+    fn it_reads_zstd_files() {
+        let reference = read("tests/files/gz/normal.log").unwrap();
+        assert_eq!(
+            read_compressed_file("tests/files/gz/normal.log.zst").unwrap(),
+            reference
+        );
+    }
+
+    #[test]
+    fn it_detects_compression_without_extension() {
+        let reference = read("tests/files/gz/normal.log").unwrap();
+        let dir = tempdir().unwrap();
+        for extension in &["gz", "xz", "zst"] {
+            let file = dir.path().join("normal");
+            copy(format!("tests/files/gz/normal.log.{}", extension), &file).unwrap();
+            assert_eq!(read_compressed_file(&file).unwrap(), reference);
+        }
+    }
+
     #[test]
     fn it_reads_plain_files() {
         let reference = read("tests/files/gz/normal.log").unwrap();
@@ -126,6 +406,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_decompresses_files() {
+        let reference = read("tests/files/gz/normal.log").unwrap();
+        let dir = tempdir().unwrap();
+        for source in &["normal.log.gz", "normal.log"] {
+            let file = dir.path().join("normal.log");
+            decompress_file(format!("tests/files/gz/{}", source), &file).unwrap();
+            assert_eq!(read(&file).unwrap(), reference);
+        }
+    }
+
     #[test]
     fn it_reads_signed_content() {
         // unix2dos normal.log
@@ -144,11 +435,57 @@ mod tests {
             // openssl smime -sign -signer ../keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert
             //         -in normal.log -out normal.signed -inkey ../keys/e745a140-40bc-4b86-b6dc-084488fc906b.priv
             //         -passin "pass:Cfengine passphrase" -text -nocerts -md sha256
-            signature(&read("tests/files/smime/normal.signed").unwrap(), &certs,).unwrap(),
+            signature(
+                &read("tests/files/smime/normal.signed").unwrap(),
+                &certs,
+                &allowed_digests()
+            )
+            .unwrap(),
             reference
         );
     }
 
+    #[test]
+    fn it_refuses_weak_digests() {
+        let x509 = X509::from_pem(
+            &read("tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert").unwrap(),
+        )
+        .unwrap();
+        let mut certs = Stack::new().unwrap();
+        certs.push(x509).unwrap();
+
+        // Valid signature, but using sha1
+        match signature(
+            &read("tests/files/smime/normal-sha1.signed").unwrap(),
+            &certs,
+            &allowed_digests(),
+        ) {
+            Err(Error::RefusedDigest(digest)) => assert_eq!(digest, "sha1"),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        let mut sha1 = allowed_digests();
+        sha1.insert("sha1".to_string());
+        assert!(signature(
+            &read("tests/files/smime/normal-sha1.signed").unwrap(),
+            &certs,
+            &sha1,
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn it_parses_signer_digests() {
+        let (signature, _) =
+            Pkcs7::from_smime(&read("tests/files/smime/normal.signed").unwrap()).unwrap();
+        assert_eq!(
+            signer_digests(&signature.to_der().unwrap()),
+            Some(vec![Some("sha256")])
+        );
+        assert_eq!(signer_digests(&[0x30, 0x82, 0x01]), None);
+        assert_eq!(signer_digests(&[]), None);
+    }
+
     #[test]
     fn it_detects_wrong_content() {
         let x509 = X509::from_pem(
@@ -161,6 +498,7 @@ mod tests {
         assert!(signature(
             &read("tests/files/smime/normal-diff.signed").unwrap(),
             &certs,
+            &allowed_digests(),
         )
         .is_err());
     }
@@ -174,6 +512,11 @@ mod tests {
         let mut certs = Stack::new().unwrap();
         certs.push(x509bis).unwrap();
 
-        assert!(signature(&read("tests/files/smime/normal.signed").unwrap(), &certs,).is_err());
+        assert!(signature(
+            &read("tests/files/smime/normal.signed").unwrap(),
+            &certs,
+            &allowed_digests(),
+        )
+        .is_err());
     }
 }
@@ -0,0 +1,221 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+//! Checks on inventories received from nodes, done before forwarding them
+//!
+//! Inventories are XML documents, and agents send a detached signature
+//! (a `.sign` file using the shared files signature format) along with them.
+
+use crate::{
+    data::{
+        node::{NodeId, NodeIdRef, NodesList},
+        shared_file::Metadata,
+    },
+    error::Error,
+    hashing::HashType,
+};
+use openssl::{
+    pkey::{PKey, Public},
+    sign::Verifier,
+};
+use quick_xml::{events::Event, Reader};
+use std::str::FromStr;
+
+/// Checks the inventory is well-formed XML and returns the node id it declares
+pub fn node_id(inventory: &[u8]) -> Result<NodeId, Error> {
+    let mut reader = Reader::from_reader(inventory);
+    reader.trim_text(true);
+    let mut buf = vec![];
+    // Names of the currently open elements
+    let mut path: Vec<Vec<u8>> = vec![];
+    let id_path = [b"RUDDER".to_vec(), b"UUID".to_vec()];
+    let mut has_root = false;
+    let mut node_id = None;
+
+    loop {
+        let event = reader.read_event(&mut buf).map_err(|e| {
+            Error::InvalidInventory(format!("{} at position {}", e, reader.buffer_position()))
+        })?;
+        match event {
+            Event::Start(_) | Event::Empty(_) if has_root && path.is_empty() => {
+                return Err(Error::InvalidInventory("several root elements".to_string()))
+            }
+            Event::Start(e) => {
+                has_root = true;
+                path.push(e.name().to_vec());
+            }
+            Event::Empty(_) => has_root = true,
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Text(ref e) if path.ends_with(&id_path) => {
+                node_id = Some(
+                    e.unescape_and_decode(&reader)
+                        .map_err(|e| Error::InvalidInventory(e.to_string()))?,
+                );
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    if let Some(name) = path.last() {
+        return Err(Error::InvalidInventory(format!(
+            "unclosed element {}",
+            String::from_utf8_lossy(name)
+        )));
+    }
+    if !has_root {
+        return Err(Error::InvalidInventory("missing root element".to_string()));
+    }
+    node_id.ok_or_else(|| Error::InvalidInventory("missing node id".to_string()))
+}
+
+/// Checks the detached signature of an inventory
+///
+/// When a node is given, the signing key also has to be the one we know for it.
+/// New nodes are not known yet, so only the signature itself can be checked.
+pub fn verify(
+    inventory: &[u8],
+    signature: &str,
+    node: Option<(&NodeIdRef, &NodesList)>,
+) -> Result<(), Error> {
+    let metadata = Metadata::from_str(signature)?;
+    let pubkey = metadata.pubkey()?;
+
+    if let Some((id, nodes)) = node {
+        check_key(&pubkey, id, nodes)?;
+    }
+
+    if metadata.algorithm.hash(inventory) != metadata.hash_value {
+        return Err(Error::InvalidInventorySignature(
+            "hash does not match the inventory".to_string(),
+        ));
+    }
+    let signature = hex::decode(&metadata.digest)
+        .map_err(|e| Error::InvalidInventorySignature(e.to_string()))?;
+    let mut verifier = Verifier::new(metadata.algorithm.to_openssl_hash(), &pubkey)?;
+    verifier.update(inventory)?;
+    // An error means the signature has an invalid format
+    if verifier.verify(&signature).unwrap_or(false) {
+        Ok(())
+    } else {
+        Err(Error::InvalidInventorySignature(
+            "signature does not match the inventory".to_string(),
+        ))
+    }
+}
+
+/// Checks the key is the key of the node, using its certificates when available
+fn check_key(pubkey: &PKey<Public>, id: &NodeIdRef, nodes: &NodesList) -> Result<(), Error> {
+    let matches = match (nodes.certs(id), nodes.key_hash(id)) {
+        (Some(certs), _) => {
+            let mut matches = false;
+            for cert in certs {
+                matches |= cert.public_key()?.public_eq(pubkey);
+            }
+            matches
+        }
+        (None, Some(key_hash)) => {
+            let mut parts = key_hash.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(algorithm), Some(hash)) => {
+                    HashType::from_str(algorithm)?.hash(&pubkey.public_key_to_der()?) == hash
+                }
+                _ => false,
+            }
+        }
+        (None, None) => return Err(Error::CertificateForUnknownNode(id.to_string())),
+    };
+
+    if matches {
+        Ok(())
+    } else {
+        Err(Error::InvalidInventorySignature(format!(
+            "signing key is not the key of {}",
+            id
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read, read_to_string};
+
+    fn nodes() -> NodesList {
+        NodesList::new(
+            "root".to_string(),
+            "tests/files/nodeslist.json",
+            Some("tests/files/keys/nodescerts.pem"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_reads_node_id() {
+        assert_eq!(
+            node_id(&read("tests/files/inventories/node1.xml").unwrap()).unwrap(),
+            "e745a140-40bc-4b86-b6dc-084488fc906b"
+        );
+        assert!(node_id(&read("tests/files/inventories/invalid.xml").unwrap()).is_err());
+        assert!(node_id(b"").is_err());
+        assert!(node_id(b"<REQUEST></REQUEST>").is_err());
+        assert!(node_id(b"<REQUEST></REQUEST><REQUEST></REQUEST>").is_err());
+        assert!(node_id(b"<REQUEST><RUDDER><UUID>root</UUID></RUDDER>").is_err());
+        assert_eq!(
+            node_id(b"<REQUEST><RUDDER><UUID>root</UUID></RUDDER></REQUEST>").unwrap(),
+            "root"
+        );
+    }
+
+    #[test]
+    fn it_verifies_signatures() {
+        let nodes = nodes();
+        let check = |name: &str, node: Option<&str>| {
+            verify(
+                &read(format!("tests/files/inventories/{}", name)).unwrap(),
+                &read_to_string(format!("tests/files/inventories/{}.sign", name)).unwrap(),
+                node.map(|id| (id, &nodes)),
+            )
+        };
+
+        assert!(check("node1.xml", None).is_ok());
+        assert!(check("node1.xml", Some("e745a140-40bc-4b86-b6dc-084488fc906b")).is_ok());
+        assert!(check("node1-modified.xml", None).is_err());
+        assert!(check("node1-other.xml", None).is_ok());
+        assert!(check(
+            "node1-other.xml",
+            Some("37817c4d-fbf7-4850-a985-50021f4e8f41")
+        )
+        .is_err());
+        assert!(check("node1.xml", Some("unknown")).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+//! Files uploaded through the API
+//!
+//! They are written into a dedicated directory for each processing pipeline,
+//! which is not watched, and directly sent to processing once complete.
+
+use crate::{
+    error::Error,
+    input::Input,
+    processing::{ReceivedFile, RootDirectory},
+    JobConfig,
+};
+use bytes::{Buf, Bytes};
+use futures::{
+    future::{self, Either},
+    stream::iter_ok,
+    sync::mpsc,
+    Future, Sink, Stream,
+};
+use rand::{thread_rng, Rng};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tokio::{
+    fs::{remove_file, rename, File},
+    io::write_all,
+};
+use tracing::{debug, error, warn};
+
+/// Processing pipelines accepting uploaded files
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum UploadTarget {
+    Reports,
+    NewInventories,
+    InventoryUpdates,
+}
+
+/// Directory receiving uploaded files for each watched directory
+/// (`upload/incoming` for `incoming`)
+pub fn upload_directory(directory: &RootDirectory, watched: &str) -> PathBuf {
+    directory.join("upload").join(watched)
+}
+
+/// Queues of the running processing pipelines, registered by their upload input
+#[derive(Default)]
+pub struct UploadQueues {
+    queues: RwLock<HashMap<UploadTarget, (PathBuf, mpsc::Sender<ReceivedFile>)>>,
+}
+
+impl UploadQueues {
+    fn register(&self, target: UploadTarget, directory: PathBuf, tx: mpsc::Sender<ReceivedFile>) {
+        self.queues
+            .write()
+            .expect("could not write upload queues")
+            .insert(target, (directory, tx));
+    }
+
+    /// Drops the registered queues, so that processing pipelines can end
+    /// once pending files are processed
+    fn close(&self) {
+        self.queues
+            .write()
+            .expect("could not write upload queues")
+            .clear();
+    }
+
+    /// Directory and queue of the target, if its processing is enabled
+    pub fn get(&self, target: UploadTarget) -> Option<(PathBuf, mpsc::Sender<ReceivedFile>)> {
+        self.queues
+            .read()
+            .expect("could not read upload queues")
+            .get(&target)
+            .cloned()
+    }
+}
+
+/// Files uploaded through the API for a processing pipeline
+pub struct Upload {
+    pub target: UploadTarget,
+    pub directory: PathBuf,
+}
+
+impl Input for Upload {
+    fn start(&self, job_config: &Arc<JobConfig>, tx: &mpsc::Sender<ReceivedFile>) {
+        job_config
+            .uploads
+            .register(self.target, self.directory.clone(), tx.clone());
+        let job_config_shutdown = job_config.clone();
+        tokio::spawn(
+            job_config
+                .shutdown
+                .signal()
+                .map(move |_| job_config_shutdown.uploads.close()),
+        );
+
+        // Files received but not processed before last stop
+        let files = match pending_files(&self.directory) {
+            Ok(files) => files,
+            Err(e) => {
+                error!("could not list uploaded files: {}", e);
+                return;
+            }
+        };
+        let tx = tx.clone();
+        tokio::spawn(iter_ok(files).for_each(move |file| {
+            debug!("upload: {:?}", file);
+            tx.clone()
+                .send(file)
+                .map_err(|e| warn!("upload send error: {}", e))
+                .map(|_| ())
+        }));
+    }
+}
+
+/// Complete files of the directory, removing interrupted uploads
+fn pending_files(directory: &Path) -> Result<Vec<ReceivedFile>, Error> {
+    let mut files = vec![];
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if is_temporary(&path) {
+            debug!("removing interrupted upload {:?}", path);
+            fs::remove_file(&path)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Files being received are hidden
+fn is_temporary(path: &Path) -> bool {
+    path.file_name()
+        .map(|n| n.to_string_lossy().starts_with('.'))
+        .unwrap_or(true)
+}
+
+/// Writes an uploaded file into `directory` and sends it to processing once complete
+///
+/// `file_name` has to be a valid file name.
+pub fn receive<S, B>(
+    directory: PathBuf,
+    file_name: &str,
+    body: S,
+    max_size: u64,
+    tx: mpsc::Sender<ReceivedFile>,
+) -> impl Future<Item = (), Error = Error>
+where
+    S: Stream<Item = B, Error = Error> + Send + 'static,
+    B: Buf + 'static,
+{
+    let temp_path = directory.join(format!(".{}-{:08x}", file_name, thread_rng().gen::<u32>()));
+    let path = directory.join(file_name);
+    let temp_path_clone = temp_path.clone();
+
+    File::create(temp_path.clone())
+        .map_err(Error::from)
+        .and_then(move |file| {
+            body.fold((file, 0), move |(file, size), chunk| {
+                let size = size + chunk.remaining() as u64;
+                if size > max_size {
+                    return Either::A(future::err(Error::FileTooLarge(max_size)));
+                }
+                Either::B(
+                    write_all(file, chunk.collect::<Bytes>())
+                        .map(move |(file, _)| (file, size))
+                        .map_err(Error::from),
+                )
+            })
+        })
+        .and_then(move |_| {
+            rename(temp_path, path.clone())
+                .map(|_| path)
+                .map_err(Error::from)
+        })
+        .or_else(move |e| {
+            remove_file(temp_path_clone).then(|res| {
+                if let Err(e) = res {
+                    debug!("could not remove temporary file: {}", e);
+                }
+                Err(e)
+            })
+        })
+        .and_then(move |path| {
+            debug!("uploaded: {:?}", path);
+            tx.send(path)
+                .map(|_| ())
+                .map_err(|_| Error::ProcessingStopped)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn it_receives_files() {
+        let dir = tempdir().unwrap();
+        let (tx, rx) = mpsc::channel(1);
+        let mut runtime = Runtime::new().unwrap();
+        let body = stream::iter_ok::<_, Error>(vec![
+            Cursor::new(Bytes::from("report ")),
+            Cursor::new(Bytes::from("content")),
+        ]);
+
+        runtime
+            .block_on(receive(
+                dir.path().to_path_buf(),
+                "report.log",
+                body,
+                100,
+                tx,
+            ))
+            .unwrap();
+        let received = runtime.block_on(rx.into_future()).ok().unwrap().0;
+        assert_eq!(received, Some(dir.path().join("report.log")));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("report.log")).unwrap(),
+            "report content"
+        );
+        assert_eq!(
+            pending_files(dir.path()).unwrap(),
+            vec![dir.path().join("report.log")]
+        );
+    }
+
+    #[test]
+    fn it_closes_queues() {
+        let queues = UploadQueues::default();
+        let (tx, rx) = mpsc::channel(1);
+        queues.register(UploadTarget::Reports, PathBuf::from("upload"), tx);
+        assert!(queues.get(UploadTarget::Reports).is_some());
+
+        queues.close();
+        assert!(queues.get(UploadTarget::Reports).is_none());
+        // All senders are dropped
+        assert_eq!(rx.wait().count(), 0);
+    }
+
+    #[test]
+    fn it_refuses_large_files() {
+        let dir = tempdir().unwrap();
+        let (tx, _rx) = mpsc::channel(1);
+        let mut runtime = Runtime::new().unwrap();
+        let body = stream::iter_ok::<_, Error>(vec![
+            Cursor::new(Bytes::from("report ")),
+            Cursor::new(Bytes::from("content")),
+        ]);
+
+        match runtime.block_on(receive(
+            dir.path().to_path_buf(),
+            "report.log",
+            body,
+            10,
+            tx,
+        )) {
+            Err(Error::FileTooLarge(10)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -29,12 +29,13 @@
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{
-    configuration::main::{CatchupConfig, WatchedDirectory},
+    configuration::main::{CatchupConfig, Configuration, WatchedDirectory},
+    input::Input,
     processing::ReceivedFile,
     JobConfig,
 };
 use futures::{
-    future::{poll_fn, Future},
+    future::{loop_fn, poll_fn, Future, Loop},
     sync::mpsc,
     Stream,
 };
@@ -44,67 +45,98 @@ use std::{
     sync::Arc,
     time::{Duration, Instant, SystemTime},
 };
-use tokio::{fs::read_dir, prelude::*, timer::Interval};
+use tokio::{fs::read_dir, prelude::*, timer::Delay};
 use tracing::{debug, info, span, warn, Level};
 
+/// Catchup settings of a watched directory in a given configuration
+///
+/// Settings are read again from the current configuration at each iteration,
+/// to apply reloads.
+pub type CatchupSelector = fn(&Configuration) -> CatchupConfig;
+
+/// Directory where files are written by another process
+pub struct Watch {
+    pub path: WatchedDirectory,
+    pub catchup: CatchupSelector,
+}
+
+impl Input for Watch {
+    fn start(&self, job_config: &Arc<JobConfig>, tx: &mpsc::Sender<ReceivedFile>) {
+        watch(&self.path, job_config, tx, self.catchup)
+    }
+}
+
 pub fn watch(
     path: &WatchedDirectory,
     job_config: &Arc<JobConfig>,
     tx: &mpsc::Sender<ReceivedFile>,
+    catchup: CatchupSelector,
 ) {
     info!("Starting file watcher on {:#?}", &path);
     let report_span = span!(Level::TRACE, "watcher");
     let _report_enter = report_span.enter();
-    tokio::spawn(list_files(
+    // Both stop on shutdown, dropping their senders
+    tokio::spawn(job_config.shutdown.until(list_files(
         path.clone(),
-        job_config.cfg.processing.reporting.catchup,
+        job_config.clone(),
         tx.clone(),
-    ));
-    tokio::spawn(watch_files(path.clone(), tx.clone()));
+        catchup,
+    )));
+    tokio::spawn(
+        job_config
+            .shutdown
+            .until(watch_files(path.clone(), tx.clone())),
+    );
 }
 
 fn list_files(
     path: WatchedDirectory,
-    cfg: CatchupConfig,
+    job_config: Arc<JobConfig>,
     tx: mpsc::Sender<ReceivedFile>,
+    catchup: CatchupSelector,
 ) -> impl Future<Item = (), Error = ()> {
-    Interval::new(Instant::now(), Duration::from_secs(cfg.frequency))
-        .map_err(|e| warn!("interval error: {}", e))
-        .for_each(move |_instant| {
-            debug!("listing {:?}", path);
+    loop_fn((), move |_| {
+        // Read at each iteration to apply configuration reloads
+        let cfg = catchup(&job_config.cfg());
+        debug!("listing {:?}", path);
 
-            let tx = tx.clone();
-            let sys_time = SystemTime::now();
+        let tx = tx.clone();
+        let sys_time = SystemTime::now();
 
-            read_dir(path.clone())
-                .flatten_stream()
-                .take(cfg.limit)
-                .map_err(|e| warn!("list error: {}", e))
-                .filter(move |entry| {
-                    poll_fn(move || entry.poll_metadata())
-                        // If metadata can't be fetched, skip it for now
-                        .map(|metadata| metadata.modified().unwrap_or(sys_time))
-                        // An error indicates a file in the future, let's approximate it to now
-                        .map(|modified| {
-                            sys_time
-                                .duration_since(modified)
-                                .unwrap_or_else(|_| Duration::new(0, 0))
-                        })
-                        .map(|duration| duration > Duration::from_secs(30))
-                        .map_err(|e| warn!("list filter error: {}", e))
-                        // TODO async filter (https://github.com/rust-lang-nursery/futures-rs/pull/728)
-                        .wait()
-                        .unwrap_or(false)
-                })
-                .for_each(move |entry| {
-                    let path = entry.path();
-                    debug!("list: {:?}", path);
-                    tx.clone()
-                        .send(path)
-                        .map_err(|e| warn!("list error: {}", e))
-                        .map(|_| ())
-                })
-        })
+        read_dir(path.clone())
+            .flatten_stream()
+            .take(cfg.limit)
+            .map_err(|e| warn!("list error: {}", e))
+            .filter(move |entry| {
+                poll_fn(move || entry.poll_metadata())
+                    // If metadata can't be fetched, skip it for now
+                    .map(|metadata| metadata.modified().unwrap_or(sys_time))
+                    // An error indicates a file in the future, let's approximate it to now
+                    .map(|modified| {
+                        sys_time
+                            .duration_since(modified)
+                            .unwrap_or_else(|_| Duration::new(0, 0))
+                    })
+                    .map(move |duration| duration > Duration::from_secs(cfg.min_age))
+                    .map_err(|e| warn!("list filter error: {}", e))
+                    // TODO async filter (https://github.com/rust-lang-nursery/futures-rs/pull/728)
+                    .wait()
+                    .unwrap_or(false)
+            })
+            .for_each(move |entry| {
+                let path = entry.path();
+                debug!("list: {:?}", path);
+                tx.clone()
+                    .send(path)
+                    .map_err(|e| warn!("list error: {}", e))
+                    .map(|_| ())
+            })
+            .then(move |_| {
+                Delay::new(Instant::now() + Duration::from_secs(cfg.frequency))
+                    .map_err(|e| warn!("timer error: {}", e))
+            })
+            .map(|_| Loop::<(), ()>::Continue(()))
+    })
 }
 
 fn watch_stream<P: AsRef<Path>>(path: P) -> inotify::EventStream<Vec<u8>> {
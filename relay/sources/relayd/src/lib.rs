@@ -39,20 +39,39 @@ pub mod data;
 pub mod error;
 pub mod hashing;
 pub mod input;
+pub mod logging;
+pub mod metrics;
 pub mod output;
 pub mod processing;
+pub mod shutdown;
 pub mod stats;
 
 use crate::{
     configuration::{
         cli::CliConfiguration,
         logging::LogConfig,
-        main::{Configuration, InventoryOutputSelect, OutputSelect, ReportingOutputSelect},
+        main::{
+            ClientCertificate, Configuration, InventoryOutputSelect, OutputSelect,
+            ReportingOutputSelect,
+        },
     },
-    data::node::NodesList,
+    data::{node::NodesList, remote_run::Jobs},
     error::Error,
-    output::database::{pg_pool, PgPool},
-    processing::{inventory, reporting},
+    input::upload::{upload_directory, UploadQueues},
+    logging::{LogHandle, LogSettings},
+    metrics::Metrics,
+    output::{
+        database::{pg_pool, PgPool},
+        upstream::SigningKey,
+    },
+    processing::{
+        failed,
+        inventory::{self, InventoryType},
+        reporting,
+        retry::retry_directory,
+        shared_files,
+    },
+    shutdown::Shutdown,
     stats::Stats,
 };
 use futures::{
@@ -60,27 +79,20 @@ use futures::{
     stream::Stream,
     sync::mpsc,
 };
-use reqwest::r#async::Client;
+use openssl::{pkcs12::Pkcs12, x509::X509};
+use reqwest::{r#async::Client, Certificate, Identity, Proxy};
 use std::{
-    fs::create_dir_all,
+    fs::{create_dir_all, read},
     path::Path,
     process::exit,
     string::ToString,
     sync::{Arc, RwLock},
+    time::Duration,
 };
 use structopt::clap::crate_version;
 use tokio_signal::unix::{Signal, SIGHUP, SIGINT, SIGTERM};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use tracing_log::LogTracer;
-use tracing_subscriber::{
-    filter::EnvFilter,
-    fmt::Subscriber,
-    fmt::{
-        format::{Format, Full, NewRecorder},
-        Formatter,
-    },
-    reload::Handle,
-};
 
 // There are two main phases in execution:
 //
@@ -115,16 +127,13 @@ impl ExitStatus {
     }
 }
 
-type LogHandle =
-    Handle<EnvFilter, Formatter<NewRecorder, Format<Full, ()>, fn() -> std::io::Stdout>>;
-
 pub fn init_logger() -> Result<LogHandle, Error> {
-    let builder = Subscriber::builder()
-        .without_time()
+    let settings = LogSettings::default();
+    let builder = logging::builder(&settings)
         // Until actual config load
         .with_env_filter("error")
         .with_filter_reloading();
-    let reload_handle = builder.reload_handle();
+    let reload_handle = LogHandle::new(builder.reload_handle(), settings);
     let subscriber = builder.finish();
     // Set logger for global context
     tracing::subscriber::set_global_default(subscriber)?;
@@ -145,7 +154,7 @@ pub fn check_configuration(cfg_dir: &Path) -> Result<(), Error> {
 pub fn start(cli_cfg: CliConfiguration, reload_handle: LogHandle) -> Result<(), Error> {
     // Start by setting log config
     let log_cfg = LogConfig::new(&cli_cfg.configuration_dir)?;
-    reload_handle.reload(log_cfg.to_string())?;
+    reload_handle.apply(&log_cfg)?;
 
     info!("Starting rudder-relayd {}", crate_version!());
     debug!("Parsed cli configuration:\n{:#?}", &cli_cfg);
@@ -162,35 +171,51 @@ pub fn start(cli_cfg: CliConfiguration, reload_handle: LogHandle) -> Result<(),
 
     debug!("Setup signal handlers");
 
-    // SIGINT or SIGTERM: immediate shutdown
-    // TODO: graceful shutdown
+    // SIGINT or SIGTERM: graceful shutdown
+    // Stop inputs, wait for running tasks up to the timeout, then exit
+    let job_config_shutdown = job_config.clone();
     let shutdown = Signal::new(SIGINT)
         .flatten_stream()
         .select(Signal::new(SIGTERM).flatten_stream())
         .into_future()
-        .map(|_sig| {
+        .map_err(|e| error!("signal error {}", e.0))
+        .and_then(move |_sig| {
             info!("Signal received: shutdown requested");
-            exit(ExitStatus::Shutdown.code());
+            job_config_shutdown.shutdown.drain(Duration::from_secs(
+                job_config_shutdown.cfg().general.shutdown_timeout,
+            ))
         })
-        .map_err(|e| error!("signal error {}", e.0));
+        .then(|_| -> Result<(), ()> {
+            info!("Shutdown complete");
+            exit(ExitStatus::Shutdown.code())
+        });
 
-    // SIGHUP: reload logging configuration + nodes list
+    // SIGHUP: reload main configuration, logging configuration + nodes list
     let job_config_reload = job_config.clone();
 
     let reload = Signal::new(SIGHUP)
         .flatten_stream()
         .map_err(|e| e.into())
-        .for_each(move |_signal| job_config_reload.reload())
+        .for_each(move |_signal| job_config_reload.reload().map(|_| ()))
         .map_err(|e| error!("signal error {}", e));
 
     // ---- Start server ----
 
+    // Fail early on invalid TLS configuration
+    let https = job_config
+        .cfg()
+        .general
+        .https
+        .clone()
+        .map(|https_cfg| api::run_https(https_cfg, job_config.clone()))
+        .transpose()?;
+
     let mut builder = tokio::runtime::Builder::new();
-    if let Some(threads) = job_config.cfg.general.core_threads {
+    if let Some(threads) = job_config.cfg().general.core_threads {
         builder.core_threads(threads);
     }
     let mut runtime = builder
-        .blocking_threads(job_config.cfg.general.blocking_threads)
+        .blocking_threads(job_config.cfg().general.blocking_threads)
         // TODO check why resume_unwind is not enough
         .panic_handler(|_| exit(ExitStatus::Crash.code()))
         .build()?;
@@ -202,25 +227,35 @@ pub fn start(cli_cfg: CliConfiguration, reload_handle: LogHandle) -> Result<(),
 
         let (tx_stats, rx_stats) = mpsc::channel(1_024);
 
-        tokio::spawn(Stats::receiver(stats.clone(), rx_stats));
-        tokio::spawn(api::run(
-            job_config.cfg.general.listen,
-            job_config.clone(),
+        tokio::spawn(Stats::receiver(
             stats.clone(),
+            job_config.metrics.clone(),
+            rx_stats,
         ));
+        tokio::spawn(job_config.shutdown.track(api::run(
+            job_config.cfg().general.listen,
+            job_config.clone(),
+            stats.clone(),
+        )));
+        if let Some(https) = https {
+            // Stop accepting connections on shutdown, running ones are tracked
+            tokio::spawn(job_config.shutdown.until(https));
+        }
 
-        if job_config.cfg.processing.reporting.output.is_enabled() {
+        if job_config.cfg().processing.reporting.output.is_enabled() {
             reporting::start(&job_config, &tx_stats);
         } else {
             info!("Skipping reporting as it is disabled");
         }
 
-        if job_config.cfg.processing.inventory.output.is_enabled() {
+        if job_config.cfg().processing.inventory.output.is_enabled() {
             inventory::start(&job_config, &tx_stats);
         } else {
             info!("Skipping inventory as it is disabled");
         }
 
+        shared_files::start(&job_config);
+
         info!("Server started");
         Ok(())
     }));
@@ -232,10 +267,18 @@ pub fn start(cli_cfg: CliConfiguration, reload_handle: LogHandle) -> Result<(),
 
 pub struct JobConfig {
     pub cli_cfg: CliConfiguration,
-    pub cfg: Configuration,
+    /// Replaced as a whole on reload
+    cfg: RwLock<Arc<Configuration>>,
     pub nodes: RwLock<NodesList>,
-    pub pool: Option<PgPool>,
-    pub client: Client,
+    pool: RwLock<Option<PgPool>>,
+    client: RwLock<Client>,
+    /// Present when run logs are modified before being forwarded upstream
+    signing_key: RwLock<Option<SigningKey>>,
+    pub shutdown: Shutdown,
+    pub metrics: Metrics,
+    /// Filled when processing starts
+    pub uploads: UploadQueues,
+    pub remote_runs: Jobs,
     handle: LogHandle,
 }
 
@@ -255,10 +298,40 @@ impl JobConfig {
                     .join("accepted-nodes-updates"),
             )?;
             create_dir_all(cfg.processing.inventory.directory.join("failed"))?;
+            create_dir_all(retry_directory(
+                &cfg.processing.inventory.directory,
+                InventoryType::New.directory(),
+            ))?;
+            create_dir_all(retry_directory(
+                &cfg.processing.inventory.directory,
+                InventoryType::Update.directory(),
+            ))?;
+            create_dir_all(upload_directory(
+                &cfg.processing.inventory.directory,
+                InventoryType::New.directory(),
+            ))?;
+            create_dir_all(upload_directory(
+                &cfg.processing.inventory.directory,
+                InventoryType::Update.directory(),
+            ))?;
         }
         if cfg.processing.reporting.output != ReportingOutputSelect::Disabled {
             create_dir_all(cfg.processing.reporting.directory.join("incoming"))?;
             create_dir_all(cfg.processing.reporting.directory.join("failed"))?;
+            create_dir_all(failed::quarantine_directory(
+                &cfg.processing.reporting.directory,
+            ))?;
+            create_dir_all(reporting::parsing_directory(
+                &cfg.processing.reporting.directory,
+            ))?;
+            create_dir_all(retry_directory(
+                &cfg.processing.reporting.directory,
+                "incoming",
+            ))?;
+            create_dir_all(upload_directory(
+                &cfg.processing.reporting.directory,
+                "incoming",
+            ))?;
         }
 
         let pool = if cfg.processing.reporting.output == ReportingOutputSelect::Database {
@@ -267,9 +340,8 @@ impl JobConfig {
             None
         };
 
-        let client = Client::builder()
-            .danger_accept_invalid_certs(!cfg.output.upstream.verify_certificates)
-            .build()?;
+        let client = Self::new_client(&cfg)?;
+        let signing_key = Self::new_signing_key(&cfg)?;
 
         let nodes = RwLock::new(NodesList::new(
             cfg.general.node_id.to_string(),
@@ -279,39 +351,191 @@ impl JobConfig {
 
         Ok(Arc::new(Self {
             cli_cfg,
-            cfg,
+            cfg: RwLock::new(Arc::new(cfg)),
             nodes,
-            pool,
+            pool: RwLock::new(pool),
             handle,
-            client,
+            client: RwLock::new(client),
+            signing_key: RwLock::new(signing_key),
+            shutdown: Shutdown::default(),
+            metrics: Metrics::new()?,
+            uploads: UploadQueues::default(),
+            remote_runs: Jobs::default(),
         }))
     }
 
+    fn new_client(cfg: &Configuration) -> Result<Client, Error> {
+        let upstream = &cfg.output.upstream;
+        let mut builder = Client::builder()
+            .danger_accept_invalid_certs(!upstream.verify_certificates)
+            // No global timeout as remote run streams agent output for
+            // an unknown time, uploads have their own timeout
+            .connect_timeout(Duration::from_secs(upstream.connect_timeout));
+
+        // Added to the system trust store, which cannot be disabled with our HTTP client
+        if let Some(ref ca_file) = upstream.ca_file {
+            for cert in X509::stack_from_pem(&read(ca_file)?)? {
+                builder = builder.add_root_certificate(Certificate::from_der(&cert.to_der()?)?);
+            }
+        }
+        if let Some(ref client_certificate) = upstream.client_certificate {
+            builder = builder.identity(Self::client_identity(client_certificate)?);
+        }
+        if let Some(ref proxy) = upstream.proxy {
+            builder = builder.proxy(Proxy::all(proxy.as_str())?);
+        }
+        Ok(builder.build()?)
+    }
+
+    fn new_signing_key(cfg: &Configuration) -> Result<Option<SigningKey>, Error> {
+        let reporting = &cfg.processing.reporting;
+        if reporting.output != ReportingOutputSelect::Upstream || !reporting.modifies_runlogs() {
+            return Ok(None);
+        }
+        cfg.output
+            .upstream
+            .client_certificate
+            .as_ref()
+            .ok_or(Error::MissingSigningCertificate)
+            .and_then(SigningKey::new)
+            .map(Some)
+    }
+
+    /// The native TLS backend only accepts PKCS#12 identities
+    fn client_identity(cfg: &ClientCertificate) -> Result<Identity, Error> {
+        // Only used to build the in-memory archive
+        const PASSWORD: &str = "relayd";
+
+        let (cert, key) = cfg.read()?;
+        let archive = Pkcs12::builder().build(PASSWORD, "relayd", &key, &cert)?;
+        Ok(Identity::from_pkcs12_der(&archive.to_der()?, PASSWORD)?)
+    }
+
+    /// Current configuration
+    pub fn cfg(&self) -> Arc<Configuration> {
+        self.cfg
+            .read()
+            .expect("could not read configuration")
+            .clone()
+    }
+
+    /// Database pool, present when reports are inserted into the database
+    pub fn pool(&self) -> Option<PgPool> {
+        self.pool
+            .read()
+            .expect("could not read database pool")
+            .clone()
+    }
+
+    /// HTTP client for upstream server
+    pub fn client(&self) -> Client {
+        self.client.read().expect("could not read client").clone()
+    }
+
+    /// Key to sign modified run logs, if they are forwarded upstream
+    pub fn signing_key(&self) -> Option<SigningKey> {
+        self.signing_key
+            .read()
+            .expect("could not read signing key")
+            .clone()
+    }
+
+    /// Reads main configuration again and applies it
+    ///
+    /// Settings that can only be changed by restarting the service keep their
+    /// running value, their names are returned.
+    fn reload_configuration(&self) -> Result<Vec<&'static str>, Error> {
+        let running = self.cfg();
+        let mut cfg = Configuration::new(&self.cli_cfg.configuration_dir)?;
+        let restart_required = cfg.keep_static(&running);
+        for setting in &restart_required {
+            warn!("{} was modified, restart needed to apply it", setting);
+        }
+
+        // Prepare everything before applying the changes
+        let pool = match self.pool() {
+            Some(_) if cfg.output.database != running.output.database => {
+                Some(pg_pool(&cfg.output.database)?)
+            }
+            _ => None,
+        };
+        let client = if cfg.output.upstream != running.output.upstream {
+            Some(Self::new_client(&cfg)?)
+        } else {
+            None
+        };
+        // Always read again to take a renewed key into account
+        let signing_key = Self::new_signing_key(&cfg)?;
+
+        if let Some(pool) = pool {
+            *self.pool.write().expect("could not write database pool") = Some(pool);
+        }
+        if let Some(client) = client {
+            *self.client.write().expect("could not write client") = client;
+        }
+        *self
+            .signing_key
+            .write()
+            .expect("could not write signing key") = signing_key;
+        *self.cfg.write().expect("could not write configuration") = Arc::new(cfg);
+        Ok(restart_required)
+    }
+
     fn reload_nodeslist(&self) -> Result<(), Error> {
+        let cfg = self.cfg();
         let mut nodes = self.nodes.write().expect("could not write nodes list");
         *nodes = NodesList::new(
-            self.cfg.general.node_id.to_string(),
-            &self.cfg.general.nodes_list_file,
-            Some(&self.cfg.general.nodes_certs_file),
+            cfg.general.node_id.to_string(),
+            &cfg.general.nodes_list_file,
+            Some(&cfg.general.nodes_certs_file),
         )?;
         Ok(())
     }
 
-    fn reload_logging(&self) -> Result<(), Error> {
-        LogConfig::new(&self.cli_cfg.configuration_dir).and_then(|log_cfg| {
-            self.handle
-                .reload(EnvFilter::try_new(log_cfg.to_string())?)
-                .map_err(|e| e.into())
-        })
+    /// Returns the modified settings that need a restart to be applied
+    fn reload_logging(&self) -> Result<Vec<&'static str>, Error> {
+        LogConfig::new(&self.cli_cfg.configuration_dir)
+            .and_then(|log_cfg| self.handle.reload(&log_cfg))
     }
 
-    pub fn reload(&self) -> Result<(), Error> {
+    /// Returns the modified settings that need a restart to be applied
+    pub fn reload(&self) -> Result<Vec<&'static str>, Error> {
         info!("Configuration reload requested");
-        self.reload_logging()
-            .and_then(|_| self.reload_nodeslist())
+        self.reload_configuration()
+            .and_then(|mut restart_required| {
+                restart_required.extend(self.reload_logging()?);
+                self.reload_nodeslist()?;
+                Ok(restart_required)
+            })
             .map_err(|e| {
                 error!("reload error {}", e);
                 e
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn it_builds_upstream_client() {
+        let mut cfg = Configuration::new("tests/files/config/").unwrap();
+        assert!(JobConfig::new_client(&cfg).is_ok());
+
+        // The private key is encrypted
+        let mut wrong_passphrase = cfg.clone();
+        wrong_passphrase
+            .output
+            .upstream
+            .client_certificate
+            .as_mut()
+            .unwrap()
+            .passphrase = None;
+        assert!(JobConfig::new_client(&wrong_passphrase).is_err());
+
+        cfg.output.upstream.ca_file = Some(PathBuf::from("tests/files/keys/missing.cert"));
+        assert!(JobConfig::new_client(&cfg).is_err());
+    }
+}
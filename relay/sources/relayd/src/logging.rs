@@ -0,0 +1,470 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Formatting and output of logs
+//!
+//! Level and filters are applied by the reloadable `EnvFilter`, the format and
+//! output come from the settings shared by the field visitor, the event formatter
+//! and the writer.
+
+use crate::{
+    configuration::logging::{LogConfig, LogFormat, LogOutput},
+    error::Error,
+};
+use chrono::{SecondsFormat, Utc};
+use serde_json::Value;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    process,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+use tracing::{
+    field::{Field, Visit},
+    warn, Event, Level,
+};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::{
+    filter::{EnvFilter, LevelFilter},
+    fmt::{
+        format::Format, time::FormatTime, Builder, Context, FormatEvent, Formatter, MakeWriter,
+        NewVisitor, Subscriber,
+    },
+    reload::Handle,
+};
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// daemon
+const SYSLOG_FACILITY: u8 = 3;
+
+#[derive(Debug)]
+enum Output {
+    Stdout,
+    File(File),
+    Syslog(UnixDatagram),
+}
+
+impl Output {
+    fn new(cfg: &LogConfig) -> Result<Self, Error> {
+        Ok(match cfg.general.output {
+            LogOutput::Stdout => Output::Stdout,
+            LogOutput::File => Output::File(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(cfg.general.file.as_ref().ok_or(Error::MissingLogFile)?)?,
+            ),
+            LogOutput::Syslog => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(SYSLOG_SOCKET)?;
+                Output::Syslog(socket)
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Settings {
+    format: LogFormat,
+    timestamps: bool,
+    output: Output,
+}
+
+/// Settings used until the configuration is loaded
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Full,
+            timestamps: false,
+            output: Output::Stdout,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LogSettings(Arc<RwLock<Settings>>);
+
+impl LogSettings {
+    fn read(&self) -> RwLockReadGuard<'_, Settings> {
+        self.0.read().expect("could not read log settings")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Settings> {
+        self.0.write().expect("could not write log settings")
+    }
+}
+
+pub type LogSubscriber = Formatter<NewFieldVisitor, EventFormatter, MakeOutput>;
+
+/// Subscriber builder using the shared settings
+pub fn builder(
+    settings: &LogSettings,
+) -> Builder<NewFieldVisitor, EventFormatter, LevelFilter, MakeOutput> {
+    Subscriber::builder()
+        .with_visitor(NewFieldVisitor(settings.clone()))
+        .on_event(EventFormatter(settings.clone()))
+        .with_writer(MakeOutput(settings.clone()))
+}
+
+pub struct LogHandle {
+    filter: Handle<EnvFilter, LogSubscriber>,
+    settings: LogSettings,
+}
+
+impl LogHandle {
+    pub fn new(filter: Handle<EnvFilter, LogSubscriber>, settings: LogSettings) -> Self {
+        Self { filter, settings }
+    }
+
+    /// Applies the whole configuration, must be called before creating any span
+    pub fn apply(&self, cfg: &LogConfig) -> Result<(), Error> {
+        let output = Output::new(cfg)?;
+        self.filter.reload(EnvFilter::try_new(cfg.to_string())?)?;
+        *self.settings.write() = Settings {
+            format: cfg.general.format,
+            timestamps: cfg.general.timestamps,
+            output,
+        };
+        Ok(())
+    }
+
+    /// Applies a new configuration, except for the format as existing spans have
+    /// their fields already formatted. The output is reopened.
+    ///
+    /// Returns the modified settings that need a restart to be applied
+    pub fn reload(&self, cfg: &LogConfig) -> Result<Vec<&'static str>, Error> {
+        let output = Output::new(cfg)?;
+        self.filter.reload(EnvFilter::try_new(cfg.to_string())?)?;
+        let format_changed = {
+            let mut settings = self.settings.write();
+            settings.timestamps = cfg.general.timestamps;
+            settings.output = output;
+            settings.format != cfg.general.format
+        };
+        if format_changed {
+            warn!("logging.general.format was modified, restart needed to apply it");
+            Ok(vec!["logging.general.format"])
+        } else {
+            Ok(vec![])
+        }
+    }
+}
+
+fn json_string(value: &str) -> Value {
+    Value::from(value)
+}
+
+/// Formats span and event fields, as `key=value` or JSON object members
+pub struct NewFieldVisitor(LogSettings);
+
+impl<'a> NewVisitor<'a> for NewFieldVisitor {
+    type Visitor = FieldVisitor<'a>;
+
+    fn make(&self, writer: &'a mut dyn fmt::Write, is_empty: bool) -> Self::Visitor {
+        FieldVisitor {
+            writer,
+            is_empty,
+            json: self.0.read().format == LogFormat::Json,
+        }
+    }
+}
+
+pub struct FieldVisitor<'a> {
+    writer: &'a mut dyn fmt::Write,
+    is_empty: bool,
+    json: bool,
+}
+
+impl<'a> FieldVisitor<'a> {
+    fn separate(&mut self) {
+        if self.is_empty {
+            self.is_empty = false;
+        } else {
+            let _ = write!(self.writer, "{}", if self.json { "," } else { " " });
+        }
+    }
+
+    /// Writes an already formatted value
+    fn record(&mut self, field: &Field, value: &dyn fmt::Display) {
+        let name = match field.name() {
+            // Log metadata, already handled by the formatter
+            name if name.starts_with("log.") => return,
+            name if name.starts_with("r#") => &name[2..],
+            name => name,
+        };
+        self.separate();
+        let _ = match (self.json, name) {
+            (false, "message") => write!(self.writer, "{}", value),
+            (false, name) => write!(self.writer, "{}={}", name, value),
+            (true, name) => write!(self.writer, "{}:{}", json_string(name), value),
+        };
+    }
+}
+
+impl<'a> Visit for FieldVisitor<'a> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, &value)
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, &value)
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, &value)
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if self.json {
+            self.record(field, &json_string(value))
+        } else if field.name() == "message" {
+            self.record(field, &value)
+        } else {
+            self.record(field, &format_args!("{:?}", value))
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if self.json {
+            self.record(field, &json_string(&format!("{:?}", value)))
+        } else {
+            self.record(field, &format_args!("{:?}", value))
+        }
+    }
+}
+
+/// RFC 3339 timestamp in UTC
+struct Timestamp(bool);
+
+impl FormatTime for Timestamp {
+    fn format_time(&self, writer: &mut dyn fmt::Write) -> fmt::Result {
+        if self.0 {
+            write!(
+                writer,
+                "{} ",
+                Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+            )
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub struct EventFormatter(LogSettings);
+
+impl EventFormatter {
+    fn format_json(
+        ctx: &Context<'_, NewFieldVisitor>,
+        writer: &mut dyn fmt::Write,
+        event: &Event<'_>,
+        timestamps: bool,
+    ) -> fmt::Result {
+        let normalized_meta = event.normalized_metadata();
+        let meta = normalized_meta.as_ref().unwrap_or_else(|| event.metadata());
+
+        write!(writer, "{{")?;
+        if timestamps {
+            write!(
+                writer,
+                "\"timestamp\":\"{}\",",
+                Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+            )?;
+        }
+        write!(
+            writer,
+            "\"level\":\"{}\",\"target\":{},\"fields\":{{",
+            meta.level(),
+            json_string(meta.target())
+        )?;
+        {
+            let mut visitor = ctx.new_visitor(writer, true);
+            event.record(&mut visitor);
+        }
+        write!(writer, "}},\"spans\":[")?;
+        let mut is_empty = true;
+        ctx.visit_spans(|_, span| {
+            if !is_empty {
+                write!(writer, ",")?;
+            }
+            is_empty = false;
+            write!(writer, "{{\"name\":{}", json_string(span.name()))?;
+            if !span.fields().is_empty() {
+                write!(writer, ",{}", span.fields())?;
+            }
+            write!(writer, "}}")
+        })?;
+        writeln!(writer, "]}}")
+    }
+}
+
+impl FormatEvent<NewFieldVisitor> for EventFormatter {
+    fn format_event(
+        &self,
+        ctx: &Context<'_, NewFieldVisitor>,
+        writer: &mut dyn fmt::Write,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let (format, timestamps, syslog) = {
+            let settings = self.0.read();
+            (
+                settings.format,
+                settings.timestamps,
+                match settings.output {
+                    Output::Syslog(_) => true,
+                    _ => false,
+                },
+            )
+        };
+
+        if syslog {
+            let severity = match *event.metadata().level() {
+                Level::ERROR => 3,
+                Level::WARN => 4,
+                Level::INFO => 6,
+                Level::DEBUG | Level::TRACE => 7,
+            };
+            write!(
+                writer,
+                "<{}>rudder-relayd[{}]: ",
+                SYSLOG_FACILITY * 8 + severity,
+                process::id()
+            )?;
+        }
+
+        match format {
+            LogFormat::Full => Format::default()
+                .with_timer(Timestamp(timestamps))
+                .format_event(ctx, writer, event),
+            LogFormat::Compact => Format::default()
+                .compact()
+                .with_timer(Timestamp(timestamps))
+                .format_event(ctx, writer, event),
+            LogFormat::Json => Self::format_json(ctx, writer, event, timestamps),
+        }
+    }
+}
+
+pub struct MakeOutput(LogSettings);
+
+impl MakeWriter for MakeOutput {
+    type Writer = OutputWriter;
+
+    fn make_writer(&self) -> Self::Writer {
+        OutputWriter(self.0.clone())
+    }
+}
+
+/// Writes to the currently configured output
+///
+/// Each event is written at once, which allows sending one datagram per event
+/// to syslog.
+pub struct OutputWriter(LogSettings);
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.read().output {
+            Output::Stdout => io::stdout().write(buf),
+            Output::File(ref file) => {
+                let mut file: &File = file;
+                file.write(buf)
+            }
+            Output::Syslog(ref socket) => {
+                let message = if buf.ends_with(b"\n") {
+                    &buf[..buf.len() - 1]
+                } else {
+                    buf
+                };
+                socket.send(message).map(|_| buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0.read().output {
+            Output::Stdout => io::stdout().flush(),
+            Output::File(ref file) => {
+                let mut file: &File = file;
+                file.flush()
+            }
+            Output::Syslog(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, read_to_string, remove_file};
+    use tracing::{info, span};
+
+    #[test]
+    fn it_formats_json_logs() {
+        let log_file = "target/tmp/logging_json.log";
+        create_dir_all("target/tmp").unwrap();
+        let _ = remove_file(log_file);
+
+        let settings = LogSettings::default();
+        *settings.write() = Settings {
+            format: LogFormat::Json,
+            timestamps: true,
+            output: Output::File(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(log_file)
+                    .unwrap(),
+            ),
+        };
+        let subscriber = builder(&settings).with_env_filter("trace").finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let report = span!(Level::INFO, "report", queue_id = "7a3f");
+            let _report = report.enter();
+            let node = span!(Level::INFO, "node", node_id = "root");
+            let _node = node.enter();
+            info!(inserted = 2, "processed \"{}\"", "runlog");
+        });
+
+        let log: serde_json::Value =
+            serde_json::from_str(&read_to_string(log_file).unwrap()).unwrap();
+        assert!(log["timestamp"].is_string());
+        assert_eq!(log["level"], "INFO");
+        assert_eq!(log["fields"]["message"], "processed \"runlog\"");
+        assert_eq!(log["fields"]["inserted"], 2);
+        assert_eq!(
+            log["spans"],
+            serde_json::json!([
+                {"name": "report", "queue_id": "7a3f"},
+                {"name": "node", "node_id": "root"}
+            ])
+        );
+    }
+}
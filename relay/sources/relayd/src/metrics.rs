@@ -0,0 +1,240 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{
+    configuration::main::{InventoryOutputSelect, ReportingOutputSelect},
+    data::node::Host,
+    error::Error,
+    stats::Event,
+    JobConfig,
+};
+use prometheus::{
+    self, histogram_opts, opts, Encoder, HistogramTimer, HistogramVec, IntCounter, IntCounterVec,
+    IntGaugeVec, Registry, TextEncoder,
+};
+use std::{fs::read_dir, path::Path};
+
+/// Steps of the processing of a file, timed separately
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Step {
+    Parse,
+    Signature,
+    Insert,
+    Upstream,
+}
+
+impl Step {
+    fn as_str(self) -> &'static str {
+        match self {
+            Step::Parse => "parse",
+            Step::Signature => "signature",
+            Step::Insert => "insert",
+            Step::Upstream => "upstream",
+        }
+    }
+}
+
+/// Metrics exposed in Prometheus format on `/metrics`
+///
+/// Counters and histograms are updated as events happen, gauges
+/// are computed when rendering.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    reports: IntCounterVec,
+    inventories: IntCounterVec,
+    processing_duration: HistogramVec,
+    remote_runs: IntCounterVec,
+    shared_files_expired: IntCounter,
+    files: IntGaugeVec,
+    database_connections: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, Error> {
+        let registry = Registry::new_custom(Some("rudder_relayd".to_string()), None)?;
+
+        let reports = IntCounterVec::new(
+            opts!("reports_total", "Number of processed reports"),
+            &["status"],
+        )?;
+        let inventories = IntCounterVec::new(
+            opts!("inventories_total", "Number of processed inventories"),
+            &["status"],
+        )?;
+        let processing_duration = HistogramVec::new(
+            histogram_opts!(
+                "processing_duration_seconds",
+                "Duration of file processing steps"
+            ),
+            &["step"],
+        )?;
+        let remote_runs = IntCounterVec::new(
+            opts!("remote_runs_total", "Number of remote runs triggered"),
+            &["target"],
+        )?;
+        let shared_files_expired = IntCounter::new(
+            "shared_files_expired_total",
+            "Number of expired shared files removed",
+        )?;
+        let files = IntGaugeVec::new(
+            opts!(
+                "directory_files",
+                "Number of files in processing directories"
+            ),
+            &["type", "directory"],
+        )?;
+        let database_connections = IntGaugeVec::new(
+            opts!(
+                "database_pool_connections",
+                "Number of connections in the database pool"
+            ),
+            &["state"],
+        )?;
+
+        registry.register(Box::new(reports.clone()))?;
+        registry.register(Box::new(inventories.clone()))?;
+        registry.register(Box::new(processing_duration.clone()))?;
+        registry.register(Box::new(remote_runs.clone()))?;
+        registry.register(Box::new(shared_files_expired.clone()))?;
+        registry.register(Box::new(files.clone()))?;
+        registry.register(Box::new(database_connections.clone()))?;
+
+        Ok(Self {
+            registry,
+            reports,
+            inventories,
+            processing_duration,
+            remote_runs,
+            shared_files_expired,
+            files,
+            database_connections,
+        })
+    }
+
+    pub fn event(&self, event: Event) {
+        match event {
+            Event::ReportReceived => self.reports.with_label_values(&["received"]).inc(),
+            Event::ReportSent => self.reports.with_label_values(&["sent"]).inc(),
+            Event::ReportInserted => self.reports.with_label_values(&["inserted"]).inc(),
+            Event::ReportRefused => self.reports.with_label_values(&["refused"]).inc(),
+            Event::InventoryReceived => self.inventories.with_label_values(&["received"]).inc(),
+            Event::InventorySent => self.inventories.with_label_values(&["sent"]).inc(),
+            Event::InventoryRefused => self.inventories.with_label_values(&["refused"]).inc(),
+        }
+    }
+
+    /// Starts timing a processing step, the duration is recorded when the timer is dropped
+    pub fn timer(&self, step: Step) -> HistogramTimer {
+        self.processing_duration
+            .with_label_values(&[step.as_str()])
+            .start_timer()
+    }
+
+    pub fn remote_run<'a, I: IntoIterator<Item = &'a Host>>(&self, targets: I) {
+        for target in targets {
+            self.remote_runs.with_label_values(&[target]).inc();
+        }
+    }
+
+    pub fn shared_files_expired(&self, removed: u64) {
+        self.shared_files_expired.inc_by(removed as i64);
+    }
+
+    fn update_files(&self, kind: &str, directory: &Path, watched: &[&str]) {
+        for name in watched.iter().chain(&["failed"]) {
+            // Skip directory if it is not readable, it will be reported elsewhere
+            if let Ok(entries) = read_dir(directory.join(name)) {
+                self.files
+                    .with_label_values(&[kind, name])
+                    .set(entries.count() as i64);
+            }
+        }
+    }
+
+    /// Renders all metrics in Prometheus text format
+    pub fn render(&self, job_config: &JobConfig) -> Result<String, Error> {
+        let cfg = job_config.cfg();
+        let cfg = &cfg.processing;
+        if cfg.reporting.output != ReportingOutputSelect::Disabled {
+            self.update_files("reports", &cfg.reporting.directory, &["incoming"]);
+        }
+        if cfg.inventory.output != InventoryOutputSelect::Disabled {
+            self.update_files(
+                "inventories",
+                &cfg.inventory.directory,
+                &["incoming", "accepted-nodes-updates"],
+            );
+        }
+
+        if let Some(pool) = job_config.pool() {
+            let state = pool.state();
+            self.database_connections
+                .with_label_values(&["idle"])
+                .set(i64::from(state.idle_connections));
+            self.database_connections
+                .with_label_values(&["active"])
+                .set(i64::from(state.connections - state.idle_connections));
+            self.database_connections
+                .with_label_values(&["max"])
+                .set(i64::from(pool.max_size()));
+        }
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_renders_metrics() {
+        let metrics = Metrics::new().unwrap();
+        metrics.event(Event::ReportReceived);
+        metrics.event(Event::ReportReceived);
+        metrics.event(Event::InventorySent);
+        metrics.remote_run(&["node1.rudder.local".to_string()]);
+        drop(metrics.timer(Step::Parse));
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&metrics.registry.gather(), &mut buffer)
+            .unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+
+        assert!(text.contains("rudder_relayd_reports_total{status=\"received\"} 2"));
+        assert!(text.contains("rudder_relayd_inventories_total{status=\"sent\"} 1"));
+        assert!(text.contains("rudder_relayd_remote_runs_total{target=\"node1.rudder.local\"} 1"));
+        assert!(text.contains("rudder_relayd_processing_duration_seconds_count{step=\"parse\"} 1"));
+    }
+}
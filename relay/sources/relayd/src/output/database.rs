@@ -30,16 +30,50 @@
 
 use crate::{
     configuration::main::DatabaseConfig,
-    data::{report::QueryableReport, RunLog},
-    Error,
+    data::{
+        report::{AgentLog, QueryableReport, Report},
+        runlog::LinkedLog,
+        RunLog,
+    },
+    metrics::Step,
+    Error, JobConfig,
 };
 use diesel::{
     insert_into,
     pg::PgConnection,
     prelude::*,
     r2d2::{ConnectionManager, Pool},
+    sql_query,
+    sql_types::{Integer, Nullable, Text, Timestamptz},
 };
-use tracing::{debug, error, span, trace, Level};
+use futures::{
+    future::poll_fn,
+    sync::{mpsc, oneshot},
+    Async, Future, Poll, Sink, Stream,
+};
+use std::{
+    iter, mem,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
+use tokio_threadpool::blocking;
+use tracing::{debug, error, span, trace, warn, Level};
+
+/// PostgreSQL accepts up to 65535 parameters in a statement,
+/// and each report or agent log uses at most 11 of them.
+const MAX_INSERTED_REPORTS: usize = 5_000;
+
+/// Only replaces older runs, as runlogs can be processed out of order
+const UPSERT_LAST_RUN: &str = "INSERT INTO nodes_last_run
+  (node_id, run_timestamp, serial, node_config_id, insertion_date)
+  VALUES ($1, $2, $3, $4, now())
+  ON CONFLICT (node_id) DO UPDATE SET
+    run_timestamp = excluded.run_timestamp,
+    serial = excluded.serial,
+    node_config_id = excluded.node_config_id,
+    insertion_date = excluded.insertion_date
+  WHERE nodes_last_run.run_timestamp <= excluded.run_timestamp";
 
 pub mod schema {
     table! {
@@ -61,6 +95,38 @@ pub mod schema {
             serial -> Integer,
         }
     }
+
+    table! {
+        use diesel::sql_types::*;
+
+        // Needs to be kept in sync with the database schema
+        agent_logs {
+            id -> BigInt,
+            execution_timestamp -> Timestamptz,
+            rule_id -> Text,
+            directive_id -> Text,
+            component -> Text,
+            key_value -> Text,
+            serial -> Integer,
+            node_id -> Text,
+            event_type -> Text,
+            msg -> Text,
+            execution_date -> Timestamptz,
+        }
+    }
+
+    table! {
+        use diesel::sql_types::*;
+
+        // Needs to be kept in sync with the database schema
+        nodes_last_run (node_id) {
+            node_id -> Text,
+            run_timestamp -> Timestamptz,
+            serial -> Integer,
+            node_config_id -> Nullable<Text>,
+            insertion_date -> Timestamptz,
+        }
+    }
 }
 
 pub type PgPool = Pool<ConnectionManager<PgConnection>>;
@@ -114,35 +180,22 @@ pub fn insert_runlog(
         .first()
         .expect("a runlog should never be empty");
 
-    trace!(
-        "Checking if first report {} is in the database",
-        first_report
-    );
     connection.transaction::<_, Error, _>(|| {
-        let new_runlog = ruddersysevents
-            .filter(
-                component
-                    .eq(&first_report.component)
-                    .and(nodeid.eq(&first_report.node_id))
-                    .and(keyvalue.eq(&first_report.key_value))
-                    .and(eventtype.eq(&first_report.event_type))
-                    .and(msg.eq(&first_report.msg))
-                    .and(policy.eq(&first_report.policy))
-                    .and(executiontimestamp.eq(&first_report.start_datetime))
-                    .and(executiondate.eq(&first_report.execution_datetime))
-                    .and(serial.eq(&first_report.serial))
-                    .and(ruleid.eq(&first_report.rule_id))
-                    .and(directiveid.eq(&first_report.directive_id)),
-            )
-            .limit(1)
-            .load::<QueryableReport>(connection)?
-            .is_empty();
+        let new_runlog = !is_in_database(connection, first_report)?;
 
         if behavior == InsertionBehavior::AllowDuplicate || new_runlog {
             trace!("Inserting runlog {:#?}", runlog);
-            insert_into(ruddersysevents)
-                .values(&runlog.reports)
-                .execute(connection)?;
+            for reports in runlog.reports.chunks(MAX_INSERTED_REPORTS) {
+                insert_into(ruddersysevents)
+                    .values(reports)
+                    .execute(connection)?;
+            }
+            for logs in runlog.logs.chunks(MAX_INSERTED_REPORTS) {
+                insert_into(schema::agent_logs::table)
+                    .values(agent_logs(logs))
+                    .execute(connection)?;
+            }
+            upsert_last_run(connection, runlog)?;
             Ok(RunlogInsertion::Inserted)
         } else {
             error!(
@@ -158,20 +211,362 @@ pub fn insert_runlog(
     })
 }
 
+/// Inserts a runlog read by chunks, in a single transaction
+///
+/// Only one chunk is kept in memory at a time, which allows inserting runlogs
+/// too large to be fully parsed.
+pub fn insert_runlog_chunks<I>(
+    pool: &PgPool,
+    chunks: I,
+    behavior: InsertionBehavior,
+) -> Result<RunlogInsertion, Error>
+where
+    I: IntoIterator<Item = Result<RunLog, Error>>,
+{
+    let report_span = span!(Level::TRACE, "database");
+    let _report_enter = report_span.enter();
+
+    let connection = &*pool.get()?;
+
+    connection.transaction::<_, Error, _>(|| {
+        let mut chunks = chunks.into_iter();
+        let first_chunk = chunks.next().unwrap_or(Err(Error::EmptyRunlog))?;
+        let first_report = first_chunk
+            .reports
+            .first()
+            .expect("a runlog should never be empty");
+
+        if behavior == InsertionBehavior::SkipDuplicate && is_in_database(connection, first_report)?
+        {
+            error!(
+                "The {} runlog was already there, skipping insertion",
+                first_chunk.info
+            );
+            debug!(
+                "The report that was already present in database is: {}",
+                first_report
+            );
+            return Ok(RunlogInsertion::AlreadyThere);
+        }
+        upsert_last_run(connection, &first_chunk)?;
+
+        for chunk in iter::once(Ok(first_chunk)).chain(chunks) {
+            let chunk = chunk?;
+            trace!("Inserting a chunk of {} reports", chunk.reports.len());
+            for reports in chunk.reports.chunks(MAX_INSERTED_REPORTS) {
+                insert_into(schema::ruddersysevents::table)
+                    .values(reports)
+                    .execute(connection)?;
+            }
+            for logs in chunk.logs.chunks(MAX_INSERTED_REPORTS) {
+                insert_into(schema::agent_logs::table)
+                    .values(agent_logs(logs))
+                    .execute(connection)?;
+            }
+        }
+        Ok(RunlogInsertion::Inserted)
+    })
+}
+
+fn is_in_database(connection: &PgConnection, report: &Report) -> Result<bool, Error> {
+    use self::schema::ruddersysevents::dsl::*;
+
+    trace!("Checking if first report {} is in the database", report);
+    Ok(!ruddersysevents
+        .filter(
+            component
+                .eq(&report.component)
+                .and(nodeid.eq(&report.node_id))
+                .and(keyvalue.eq(&report.key_value))
+                .and(eventtype.eq(&report.event_type))
+                .and(msg.eq(&report.msg))
+                .and(policy.eq(&report.policy))
+                .and(executiontimestamp.eq(&report.start_datetime))
+                .and(executiondate.eq(&report.execution_datetime))
+                .and(serial.eq(&report.serial))
+                .and(ruleid.eq(&report.rule_id))
+                .and(directiveid.eq(&report.directive_id)),
+        )
+        .limit(1)
+        .load::<QueryableReport>(connection)?
+        .is_empty())
+}
+
+fn agent_logs(logs: &[LinkedLog]) -> Vec<&AgentLog> {
+    logs.iter().map(|linked| &linked.log).collect()
+}
+
+/// Keeps the last run of each node, so that it can be found without
+/// going through all reports
+fn upsert_last_run(connection: &PgConnection, runlog: &RunLog) -> Result<(), Error> {
+    // Known once the run log is parsed, before routing and filtering
+    let run_serial = runlog.info.serial.ok_or(Error::InconsistentRunlog)?;
+
+    trace!("Updating last run of {}", runlog.info.node_id);
+    sql_query(UPSERT_LAST_RUN)
+        .bind::<Text, _>(&runlog.info.node_id)
+        .bind::<Timestamptz, _>(&runlog.info.timestamp)
+        .bind::<Integer, _>(run_serial)
+        .bind::<Nullable<Text>, _>(&runlog.info.config_id)
+        .execute(connection)?;
+    Ok(())
+}
+
+/// Inserts runlogs in a single transaction and gives the outcome for each of them
+///
+/// If the transaction fails, runlogs are inserted one by one so that only
+/// the faulty ones fail.
+pub fn insert_runlogs(
+    pool: &PgPool,
+    runlogs: &[RunLog],
+    behavior: InsertionBehavior,
+) -> Vec<Result<RunlogInsertion, Error>> {
+    let report_span = span!(Level::TRACE, "database");
+    let _report_enter = report_span.enter();
+
+    match insert_batch(pool, runlogs, behavior) {
+        Ok(outcomes) => outcomes.into_iter().map(Ok).collect(),
+        Err(e) if runlogs.len() == 1 => vec![Err(e)],
+        Err(e) => {
+            warn!(
+                "Insertion of {} runlogs failed, inserting them separately: {}",
+                runlogs.len(),
+                e
+            );
+            runlogs
+                .iter()
+                .map(|runlog| insert_runlog(pool, runlog, behavior))
+                .collect()
+        }
+    }
+}
+
+fn insert_batch(
+    pool: &PgPool,
+    runlogs: &[RunLog],
+    behavior: InsertionBehavior,
+) -> Result<Vec<RunlogInsertion>, Error> {
+    use self::schema::ruddersysevents::dsl::*;
+
+    let connection = &*pool.get()?;
+
+    connection.transaction::<_, Error, _>(|| {
+        let mut outcomes = Vec::with_capacity(runlogs.len());
+        let mut reports: Vec<&Report> = vec![];
+        let mut logs: Vec<&AgentLog> = vec![];
+
+        for (index, runlog) in runlogs.iter().enumerate() {
+            let first_report = runlog
+                .reports
+                .first()
+                .expect("a runlog should never be empty");
+
+            let duplicate = behavior == InsertionBehavior::SkipDuplicate
+                // The same runlog can be received twice in a batch
+                && (runlogs[..index]
+                    .iter()
+                    .zip(outcomes.iter())
+                    .any(|(previous, outcome)| {
+                        *outcome == RunlogInsertion::Inserted
+                            && previous.reports.first() == Some(first_report)
+                    })
+                    || is_in_database(connection, first_report)?);
+
+            if duplicate {
+                error!(
+                    "The {} runlog was already there, skipping insertion",
+                    runlog.info
+                );
+                outcomes.push(RunlogInsertion::AlreadyThere);
+            } else {
+                upsert_last_run(connection, runlog)?;
+                reports.extend(runlog.reports.iter());
+                logs.extend(runlog.logs.iter().map(|linked| &linked.log));
+                outcomes.push(RunlogInsertion::Inserted);
+            }
+        }
+
+        trace!("Inserting {} reports", reports.len());
+        for chunk in reports.chunks(MAX_INSERTED_REPORTS) {
+            insert_into(ruddersysevents)
+                .values(chunk.to_vec())
+                .execute(connection)?;
+        }
+        trace!("Inserting {} agent logs", logs.len());
+        for chunk in logs.chunks(MAX_INSERTED_REPORTS) {
+            insert_into(schema::agent_logs::table)
+                .values(chunk.to_vec())
+                .execute(connection)?;
+        }
+        Ok(outcomes)
+    })
+}
+
+/// Runlog waiting for insertion, with the channel to send back its outcome
+pub struct PendingRunlog {
+    runlog: RunLog,
+    outcome: oneshot::Sender<Result<RunlogInsertion, Error>>,
+}
+
+pub type InsertionQueue = mpsc::Sender<PendingRunlog>;
+
+/// Creates the queue of runlogs to insert, and the future inserting them by
+/// batches, which ends once all senders are dropped.
+pub fn insertion_queue(
+    job_config: Arc<JobConfig>,
+) -> (InsertionQueue, impl Future<Item = (), Error = ()>) {
+    let cfg = job_config.cfg();
+    let (queue, receiver) = mpsc::channel::<PendingRunlog>(cfg.output.database.max_batch_size);
+
+    let writer = Batches::new(
+        receiver,
+        cfg.output.database.max_batch_size,
+        Duration::from_millis(cfg.output.database.batch_window),
+    )
+    .for_each(move |batch| {
+        let mut batch = Some(batch);
+        let job_config = job_config.clone();
+        poll_fn(move || {
+            blocking(|| {
+                let (runlogs, senders): (Vec<_>, Vec<_>) = batch
+                    .take()
+                    .expect("batch already inserted")
+                    .into_iter()
+                    .map(|pending| (pending.runlog, pending.outcome))
+                    .unzip();
+
+                debug!("Inserting a batch of {} runlogs", runlogs.len());
+                let timer = job_config.metrics.timer(Step::Insert);
+                let outcomes = insert_runlogs(
+                    &job_config
+                        .pool()
+                        .expect("output uses database but no config provided"),
+                    &runlogs,
+                    InsertionBehavior::SkipDuplicate,
+                );
+                drop(timer);
+
+                for (sender, outcome) in senders.into_iter().zip(outcomes) {
+                    // The file processing does not wait for the outcome anymore
+                    let _ = sender.send(outcome);
+                }
+            })
+            .map_err(|_| panic!("the thread pool shut down"))
+        })
+    });
+    (queue, writer)
+}
+
+/// Queues a runlog for insertion and resolves with its outcome
+pub fn queue_runlog(
+    queue: InsertionQueue,
+    runlog: RunLog,
+) -> impl Future<Item = RunlogInsertion, Error = Error> {
+    let (sender, receiver) = oneshot::channel();
+    queue
+        .send(PendingRunlog {
+            runlog,
+            outcome: sender,
+        })
+        .map_err(|_| Error::ProcessingStopped)
+        .and_then(|_| receiver.map_err(|_| Error::ProcessingStopped))
+        .and_then(|outcome| outcome)
+}
+
+/// Groups items received in a time window, up to a maximum count
+///
+/// The window starts with the first item of each batch.
+struct Batches<S: Stream> {
+    stream: S,
+    items: Vec<S::Item>,
+    max_size: usize,
+    window: Duration,
+    deadline: Option<Delay>,
+}
+
+impl<S: Stream> Batches<S> {
+    fn new(stream: S, max_size: usize, window: Duration) -> Self {
+        Self {
+            stream,
+            items: Vec::with_capacity(max_size),
+            max_size,
+            window,
+            deadline: None,
+        }
+    }
+
+    fn flush(&mut self) -> Option<Vec<S::Item>> {
+        self.deadline = None;
+        if self.items.is_empty() {
+            None
+        } else {
+            Some(mem::replace(
+                &mut self.items,
+                Vec::with_capacity(self.max_size),
+            ))
+        }
+    }
+}
+
+impl<S: Stream> Stream for Batches<S> {
+    type Item = Vec<S::Item>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match self.stream.poll()? {
+                Async::Ready(Some(item)) => {
+                    if self.items.is_empty() {
+                        self.deadline = Some(Delay::new(Instant::now() + self.window));
+                    }
+                    self.items.push(item);
+                    if self.items.len() >= self.max_size {
+                        return Ok(Async::Ready(self.flush()));
+                    }
+                }
+                // Send what remains before ending
+                Async::Ready(None) => return Ok(Async::Ready(self.flush())),
+                Async::NotReady => break,
+            }
+        }
+
+        let expired = match self.deadline {
+            Some(ref mut deadline) => match deadline.poll() {
+                Ok(Async::NotReady) => false,
+                Ok(Async::Ready(_)) => true,
+                Err(e) => {
+                    error!("timer error: {}", e);
+                    true
+                }
+            },
+            None => false,
+        };
+        if expired {
+            Ok(Async::Ready(self.flush()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
-        configuration::Secret, data::report::QueryableReport,
-        output::database::schema::ruddersysevents::dsl::*,
+        configuration::{main::RunlogValidation, Secret},
+        data::report::QueryableReport,
+        output::database::schema::{agent_logs, nodes_last_run, ruddersysevents::dsl::*},
     };
     use diesel;
+    use std::{fs::File, io::BufReader};
 
     pub fn db() -> PgPool {
         let db_config = DatabaseConfig {
             url: "postgres://rudderreports:@127.0.0.1/rudder".to_string(),
             password: Secret::new("PASSWORD".to_string()),
             max_pool_size: 5,
+            max_batch_size: 100,
+            batch_window: 500,
         };
         pg_pool(&db_config).unwrap()
     }
@@ -182,6 +577,8 @@ mod tests {
         let db = &*pool.get().unwrap();
 
         diesel::delete(ruddersysevents).execute(db).unwrap();
+        diesel::delete(agent_logs::table).execute(db).unwrap();
+        diesel::delete(nodes_last_run::table).execute(db).unwrap();
         let results = ruddersysevents
             .limit(1)
             .load::<QueryableReport>(db)
@@ -204,7 +601,35 @@ mod tests {
             .limit(100)
             .load::<QueryableReport>(db)
             .unwrap();
-        assert_eq!(results.len(), 71);
+        assert_eq!(results.len(), 67);
+
+        let logs = agent_logs::table
+            .select((agent_logs::event_type, agent_logs::msg))
+            .order(agent_logs::id)
+            .load::<(String, String)>(db)
+            .unwrap();
+        assert_eq!(logs.len(), 4);
+        assert_eq!(
+            logs[0],
+            ("log_info".to_string(), "message report".to_string())
+        );
+
+        let last_runs = nodes_last_run::table
+            .select((
+                nodes_last_run::node_id,
+                nodes_last_run::serial,
+                nodes_last_run::node_config_id,
+            ))
+            .load::<(String, i32, Option<String>)>(db)
+            .unwrap();
+        assert_eq!(
+            last_runs,
+            vec![(
+                "e745a140-40bc-4b86-b6dc-084488fc906b".to_string(),
+                0,
+                Some("20180824-130007-3ad37587".to_string())
+            )]
+        );
 
         // Test inserting twice the same runlog
 
@@ -217,6 +642,134 @@ mod tests {
             .limit(100)
             .load::<QueryableReport>(db)
             .unwrap();
-        assert_eq!(results.len(), 71);
+        assert_eq!(results.len(), 67);
+    }
+
+    #[test]
+    fn it_inserts_runlogs_in_batches() {
+        let pool = db();
+        let db = &*pool.get().unwrap();
+
+        diesel::delete(ruddersysevents).execute(db).unwrap();
+        diesel::delete(agent_logs::table).execute(db).unwrap();
+
+        let runlog = || {
+            RunLog::new(
+                "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+            )
+            .unwrap()
+        };
+
+        let outcomes = insert_runlogs(
+            &pool,
+            &[runlog(), runlog()],
+            InsertionBehavior::SkipDuplicate,
+        );
+        assert_eq!(
+            outcomes.into_iter().map(|o| o.unwrap()).collect::<Vec<_>>(),
+            vec![RunlogInsertion::Inserted, RunlogInsertion::AlreadyThere]
+        );
+
+        let outcomes = insert_runlogs(&pool, &[runlog()], InsertionBehavior::SkipDuplicate);
+        assert_eq!(
+            outcomes.into_iter().map(|o| o.unwrap()).collect::<Vec<_>>(),
+            vec![RunlogInsertion::AlreadyThere]
+        );
+
+        let results = ruddersysevents
+            .limit(100)
+            .load::<QueryableReport>(db)
+            .unwrap();
+        assert_eq!(results.len(), 67);
+    }
+
+    #[test]
+    fn it_inserts_runlog_chunks() {
+        let pool = db();
+        let db = &*pool.get().unwrap();
+
+        diesel::delete(ruddersysevents).execute(db).unwrap();
+        diesel::delete(agent_logs::table).execute(db).unwrap();
+
+        let path =
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+        let chunks = || {
+            RunLog::chunks(
+                RunLog::new(path).unwrap().info,
+                BufReader::new(File::open(path).unwrap()),
+                10,
+                RunlogValidation::Keep,
+            )
+        };
+
+        assert_eq!(
+            insert_runlog_chunks(&pool, chunks(), InsertionBehavior::SkipDuplicate).unwrap(),
+            RunlogInsertion::Inserted
+        );
+        assert_eq!(
+            insert_runlog_chunks(&pool, chunks(), InsertionBehavior::SkipDuplicate).unwrap(),
+            RunlogInsertion::AlreadyThere
+        );
+
+        let results = ruddersysevents
+            .limit(100)
+            .load::<QueryableReport>(db)
+            .unwrap();
+        assert_eq!(results.len(), 67);
+        let logs = agent_logs::table
+            .select(agent_logs::id)
+            .load::<i64>(db)
+            .unwrap();
+        assert_eq!(logs.len(), 4);
+    }
+
+    #[test]
+    fn it_inserts_large_runlog() {
+        let pool = db();
+        let db = &*pool.get().unwrap();
+
+        diesel::delete(ruddersysevents).execute(db).unwrap();
+
+        let mut runlog = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+        let reports = runlog.reports.clone();
+        while runlog.reports.len() <= 2 * MAX_INSERTED_REPORTS {
+            runlog.reports.extend(reports.iter().cloned());
+        }
+
+        assert_eq!(
+            insert_runlog(&pool, &runlog, InsertionBehavior::SkipDuplicate).unwrap(),
+            RunlogInsertion::Inserted
+        );
+        let inserted = ruddersysevents.count().get_result::<i64>(db).unwrap();
+        assert_eq!(inserted as usize, runlog.reports.len());
+    }
+
+    #[test]
+    fn it_batches_runlogs() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (sender, receiver) = mpsc::channel(10);
+        let sender = runtime
+            .block_on(
+                sender
+                    .send(1)
+                    .and_then(|s| s.send(2))
+                    .and_then(|s| s.send(3)),
+            )
+            .unwrap();
+        let batches = Batches::new(receiver, 2, Duration::from_millis(10));
+
+        // Full batches are sent immediately
+        let (batch, batches) = runtime.block_on(batches.into_future()).ok().unwrap();
+        assert_eq!(batch, Some(vec![1, 2]));
+        // Others once the window is over
+        let (batch, batches) = runtime.block_on(batches.into_future()).ok().unwrap();
+        assert_eq!(batch, Some(vec![3]));
+
+        drop(sender);
+        let (batch, _) = runtime.block_on(batches.into_future()).ok().unwrap();
+        assert_eq!(batch, None);
     }
 }
@@ -28,9 +28,19 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::{processing::inventory::InventoryType, Error, JobConfig};
+use crate::{
+    configuration::main::ClientCertificate, input::uncompressed_file_name,
+    processing::inventory::InventoryType, Error, JobConfig,
+};
 use futures::Future;
-use std::{path::PathBuf, sync::Arc};
+use openssl::{
+    pkcs7::{Pkcs7, Pkcs7Flags},
+    pkey::{PKey, Private},
+    stack::Stack,
+    x509::X509,
+};
+use std::{io, path::PathBuf, sync::Arc, time::Duration};
+use tokio::timer::Timeout;
 use tracing::{debug, span, Level};
 
 pub fn send_report(
@@ -42,6 +52,53 @@ pub fn send_report(
     Box::new(forward_file(job_config, "reports", path))
 }
 
+/// Sends a modified run log instead of the received file
+pub fn send_modified_report(
+    job_config: Arc<JobConfig>,
+    path: PathBuf,
+    runlog: Vec<u8>,
+) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    let report_span = span!(Level::TRACE, "upstream");
+    let _report_enter = report_span.enter();
+    // The modified run log is not compressed
+    let file_name = uncompressed_file_name(&path)
+        .expect("not a file")
+        .to_string_lossy()
+        .to_string();
+    Box::new(put(job_config, "reports", file_name, runlog))
+}
+
+/// Certificate and private key used to sign modified run logs, read once
+/// and kept until the next reload
+#[derive(Clone)]
+pub struct SigningKey {
+    cert: X509,
+    key: PKey<Private>,
+}
+
+impl SigningKey {
+    pub fn new(certificate: &ClientCertificate) -> Result<Self, Error> {
+        let (cert, key) = certificate.read()?;
+        Ok(Self { cert, key })
+    }
+}
+
+/// Signs a run log the same way agents do
+///
+/// Equivalent to `openssl smime -sign -text -nocerts`.
+pub fn signed_runlog(runlog: &str, signing_key: &SigningKey) -> Result<Vec<u8>, Error> {
+    let flags = Pkcs7Flags::TEXT | Pkcs7Flags::NOCERTS | Pkcs7Flags::DETACHED;
+    let certs: Stack<X509> = Stack::new()?;
+    let signature = Pkcs7::sign(
+        &signing_key.cert,
+        &signing_key.key,
+        &certs,
+        runlog.as_bytes(),
+        flags,
+    )?;
+    Ok(signature.to_smime(runlog.as_bytes(), flags)?)
+}
+
 pub fn send_inventory(
     job_config: Arc<JobConfig>,
     path: PathBuf,
@@ -61,28 +118,111 @@ pub fn send_inventory(
 
 fn forward_file(
     job_config: Arc<JobConfig>,
-    endpoint: &str,
+    endpoint: &'static str,
     path: PathBuf,
-) -> impl Future<Item = (), Error = Error> + '_ {
+) -> impl Future<Item = (), Error = Error> {
     tokio::fs::read(path.clone())
         .map_err(|e| e.into())
         .and_then(move |d| {
-            job_config
-                .client
-                .clone()
-                .put(&format!(
-                    "{}/{}/{}",
-                    job_config.cfg.output.upstream.url,
-                    endpoint,
-                    path.file_name().expect("not a file").to_string_lossy()
-                ))
-                .basic_auth(
-                    &job_config.cfg.output.upstream.user,
-                    Some(&job_config.cfg.output.upstream.password.value()),
-                )
-                .body(d)
-                .send()
-                .map(|r| debug!("Server response: {:#?}", r))
-                .map_err(|e| e.into())
+            put(
+                job_config,
+                endpoint,
+                path.file_name()
+                    .expect("not a file")
+                    .to_string_lossy()
+                    .to_string(),
+                d,
+            )
         })
 }
+
+/// Limits the duration of an upload to the configured request timeout
+///
+/// It is not set on the HTTP client as it is also used for long-running remote runs.
+pub fn with_request_timeout<F>(
+    job_config: &JobConfig,
+    request: F,
+) -> impl Future<Item = F::Item, Error = Error>
+where
+    F: Future<Error = Error>,
+{
+    let timeout = job_config.cfg().output.upstream.request_timeout;
+    Timeout::new(request, Duration::from_secs(timeout)).map_err(move |e| {
+        if e.is_elapsed() {
+            Error::HttpTimeout(timeout)
+        } else if e.is_timer() {
+            io::Error::new(
+                io::ErrorKind::Other,
+                e.into_timer().expect("checked timer error"),
+            )
+            .into()
+        } else {
+            e.into_inner().expect("checked inner error")
+        }
+    })
+}
+
+fn put(
+    job_config: Arc<JobConfig>,
+    endpoint: &str,
+    file_name: String,
+    data: Vec<u8>,
+) -> impl Future<Item = (), Error = Error> {
+    let request = job_config
+        .client()
+        .put(&format!(
+            "{}/{}/{}",
+            job_config.cfg().output.upstream.url,
+            endpoint,
+            file_name
+        ))
+        .basic_auth(
+            &job_config.cfg().output.upstream.user,
+            Some(&job_config.cfg().output.upstream.password.value()),
+        )
+        .body(data)
+        .send()
+        .map(|r| debug!("Server response: {:#?}", r))
+        .map_err(Error::from);
+    with_request_timeout(&job_config, request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{configuration::Secret, data::node::NodesList, input::signature};
+    use std::collections::HashSet;
+
+    #[test]
+    fn it_signs_runlogs() {
+        let runlog = "2018-08-24T15:55:01+00:00 R: @@Common@@control@@rudder@@run@@0@@start@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##e745a140-40bc-4b86-b6dc-084488fc906b@#Start execution\r\n";
+        let certificate = ClientCertificate {
+            certificate: PathBuf::from(
+                "tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert",
+            ),
+            private_key: PathBuf::from(
+                "tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.priv",
+            ),
+            passphrase: Some(Secret::new("Cfengine passphrase".to_string())),
+        };
+        let nodes = NodesList::new(
+            "root".to_string(),
+            "tests/files/nodeslist.json",
+            Some("tests/files/keys/nodescerts.pem"),
+        )
+        .unwrap();
+        let mut allowed_digests = HashSet::new();
+        let _ = allowed_digests.insert("sha256".to_string());
+
+        let signed = signed_runlog(runlog, &SigningKey::new(&certificate).unwrap()).unwrap();
+        assert_eq!(
+            signature(
+                &signed,
+                nodes.certs("e745a140-40bc-4b86-b6dc-084488fc906b").unwrap(),
+                &allowed_digests
+            )
+            .unwrap(),
+            runlog
+        );
+    }
+}
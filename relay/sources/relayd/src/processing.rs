@@ -28,17 +28,24 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::{error::Error, stats::Event};
+use crate::{
+    error::Error,
+    processing::failed::{failed_directory, quarantine_directory, reason_path, FailureReason},
+    stats::Event,
+};
 use futures::{future::Future, sync::mpsc};
 use std::path::PathBuf;
 use tokio::{
-    fs::{remove_file, rename},
+    fs::{remove_file, rename, write},
     prelude::*,
 };
 use tracing::{debug, error};
 
+pub mod failed;
 pub mod inventory;
 pub mod reporting;
+pub mod retry;
+pub mod shared_files;
 
 pub type ReceivedFile = PathBuf;
 pub type RootDirectory = PathBuf;
@@ -49,12 +56,13 @@ enum OutputError {
     Permanent,
 }
 
-impl From<Error> for OutputError {
-    fn from(err: Error) -> Self {
+impl From<&Error> for OutputError {
+    fn from(err: &Error) -> Self {
         match err {
-            Error::Database(_) | Error::DatabaseConnection(_) | Error::HttpClient(_) => {
-                OutputError::Transient
-            }
+            Error::Database(_)
+            | Error::DatabaseConnection(_)
+            | Error::HttpClient(_)
+            | Error::HttpTimeout(_) => OutputError::Transient,
             _ => OutputError::Permanent,
         }
     }
@@ -70,42 +78,158 @@ fn success(
             .send(event)
             .map_err(|e| error!("send error: {}", e))
             .then(|_| {
-                remove_file(file.clone())
-                    .map(move |_| debug!("deleted: {:#?}", file))
-                    .map_err(|e| error!("error: {}", e))
+                retry::forget(&file).and_then(|_| {
+                    remove_file(file.clone())
+                        .map(move |_| debug!("deleted: {:#?}", file))
+                        .map_err(|e| error!("error: {}", e))
+                })
             }),
     )
 }
 
+/// Moves the file to the failed directory, next to a file storing the failure reason
 fn failure(
     file: ReceivedFile,
     directory: RootDirectory,
+    reason: FailureReason,
+    event: Event,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    move_failed(file, failed_directory(&directory), reason, event, stats)
+}
+
+/// Moves the file to the quarantine directory, with the inconsistencies found
+/// in it in its failure reason
+fn quarantine(
+    file: ReceivedFile,
+    directory: RootDirectory,
+    reason: FailureReason,
+    event: Event,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    move_failed(file, quarantine_directory(&directory), reason, event, stats)
+}
+
+fn move_failed(
+    file: ReceivedFile,
+    destination_directory: PathBuf,
+    reason: FailureReason,
     event: Event,
     stats: mpsc::Sender<Event>,
 ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    let destination = destination_directory.join(file.file_name().expect("not a file"));
+    let content = serde_json::to_vec_pretty(&reason).expect("serializable failure reason");
+
     Box::new(
         stats
             .send(event)
             .map_err(|e| error!("send error: {}", e))
-            .then(move |_| {
-                rename(
-                    file.clone(),
-                    directory
-                        .join("failed")
-                        .join(file.file_name().expect("not a file")),
-                )
-                .map(move |_| {
-                    debug!(
-                        "moved: {:#?} to {:#?}",
-                        file,
-                        directory
-                            .join("failed")
-                            .join(file.file_name().expect("not a file"))
-                    )
-                })
-                .map_err(|e| error!("error: {}", e))
+            .then(move |_| retry::forget(&file).map(|_| file))
+            .and_then(move |file| {
+                write(reason_path(&destination), content)
+                    .and_then(move |_| {
+                        rename(file.clone(), destination.clone()).map(|_| (file, destination))
+                    })
+                    .map(|(file, destination)| debug!("moved: {:#?} to {:#?}", file, destination))
+                    .map_err(|e| error!("error: {}", e))
             })
             // Hack for easier chaining
             .and_then(|_| Box::new(futures::future::err::<(), ()>(()))),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::runlog::{Violation, ViolationKind, Violations};
+    use std::fs;
+    use tempfile::tempdir;
+    use tokio::runtime::Runtime;
+
+    const RUNLOG: &str = "2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+
+    fn received(directory: &RootDirectory) -> ReceivedFile {
+        fs::create_dir_all(directory.join("incoming")).unwrap();
+        fs::create_dir_all(failed_directory(directory)).unwrap();
+        fs::create_dir_all(quarantine_directory(directory)).unwrap();
+        let file = directory.join("incoming").join(RUNLOG);
+        fs::write(&file, "content").unwrap();
+        file
+    }
+
+    #[test]
+    fn it_moves_failed_files() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_path_buf();
+        let file = received(&directory);
+        let (tx, _rx) = mpsc::channel(1);
+        let mut runtime = Runtime::new().unwrap();
+
+        let reason = FailureReason::new(
+            &file,
+            Some("e745a140-40bc-4b86-b6dc-084488fc906b".to_string()),
+            "invalid run log".to_string(),
+        );
+        assert!(runtime
+            .block_on(failure(
+                file.clone(),
+                directory.clone(),
+                reason.clone(),
+                Event::ReportRefused,
+                tx
+            ))
+            .is_err());
+
+        let failed = failed_directory(&directory).join(RUNLOG);
+        assert!(!file.exists());
+        assert_eq!(fs::read_to_string(&failed).unwrap(), "content");
+        assert_eq!(
+            serde_json::from_slice::<FailureReason>(&fs::read(reason_path(&failed)).unwrap())
+                .unwrap(),
+            reason
+        );
+    }
+
+    #[test]
+    fn it_quarantines_files() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_path_buf();
+        let file = received(&directory);
+        let (tx, _rx) = mpsc::channel(1);
+        let mut runtime = Runtime::new().unwrap();
+
+        let reason = FailureReason {
+            violations: Some(Violations(vec![Violation {
+                line: None,
+                kind: ViolationKind::MissingStart,
+            }])),
+            ..FailureReason::new(
+                &file,
+                Some("e745a140-40bc-4b86-b6dc-084488fc906b".to_string()),
+                "missing run start report".to_string(),
+            )
+        };
+        assert!(runtime
+            .block_on(quarantine(
+                file.clone(),
+                directory.clone(),
+                reason.clone(),
+                Event::ReportRefused,
+                tx
+            ))
+            .is_err());
+
+        let quarantined = quarantine_directory(&directory).join(RUNLOG);
+        assert!(!file.exists());
+        assert_eq!(fs::read_to_string(&quarantined).unwrap(), "content");
+        let content = fs::read(reason_path(&quarantined)).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<FailureReason>(&content).unwrap(),
+            reason
+        );
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&content).unwrap()["violations"],
+            serde_json::json!([{ "line": null, "type": "missing_start" }])
+        );
+    }
+}
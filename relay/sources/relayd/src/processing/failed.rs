@@ -0,0 +1,540 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+use crate::{
+    configuration::main::{Configuration, FailedConfig},
+    data::{node::NodeId, runlog::Violations},
+    error::Error,
+    processing::{inventory::signature_path, retry, shared_files::remove_file, RootDirectory},
+    JobConfig,
+};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use futures::future::{loop_fn, poll_fn, Future, Loop};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
+use tokio_threadpool::blocking;
+use tracing::{debug, info, span, warn, Level};
+
+/// Suffix of the files storing the failure reason, appended to the failed file name
+const REASON_SUFFIX: &str = ".failure.json";
+
+/// Watched directory where files without failure reason are sent back
+const DEFAULT_QUEUE: &str = "incoming";
+
+/// Files that could not be processed are moved into the `failed` directory
+pub fn failed_directory(directory: &RootDirectory) -> PathBuf {
+    directory.join("failed")
+}
+
+/// Inconsistent run logs are moved into the `quarantine` directory
+pub fn quarantine_directory(directory: &RootDirectory) -> PathBuf {
+    directory.join("quarantine")
+}
+
+/// Processed file types, each with its own failed directory
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum FailedKind {
+    Reports,
+    Inventories,
+    /// Quarantined run logs, stored next to the failed reports
+    Quarantine,
+}
+
+impl FailedKind {
+    pub fn directory(self, cfg: &Configuration) -> RootDirectory {
+        match self {
+            FailedKind::Reports | FailedKind::Quarantine => {
+                cfg.processing.reporting.directory.clone()
+            }
+            FailedKind::Inventories => cfg.processing.inventory.directory.clone(),
+        }
+    }
+
+    /// Directory containing the files, inside of the root directory
+    fn files_directory(self, directory: &RootDirectory) -> PathBuf {
+        match self {
+            FailedKind::Reports | FailedKind::Inventories => failed_directory(directory),
+            FailedKind::Quarantine => quarantine_directory(directory),
+        }
+    }
+
+    fn cfg(self, cfg: &Configuration) -> FailedConfig {
+        match self {
+            FailedKind::Reports | FailedKind::Quarantine => cfg.processing.reporting.failed,
+            FailedKind::Inventories => cfg.processing.inventory.failed,
+        }
+    }
+}
+
+impl FromStr for FailedKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reports" => Ok(FailedKind::Reports),
+            "inventories" => Ok(FailedKind::Inventories),
+            "quarantine" => Ok(FailedKind::Quarantine),
+            _ => Err(Error::UnknownFailedFile(s.to_string())),
+        }
+    }
+}
+
+/// Stored as JSON next to each failed file
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct FailureReason {
+    pub error: String,
+    pub timestamp: DateTime<Utc>,
+    pub node_id: Option<NodeId>,
+    /// Number of processing attempts, including retries
+    pub attempts: u32,
+    /// Watched directory the file was received in, where it is sent back when requeued
+    pub queue: String,
+    /// Inconsistencies found in quarantined run logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub violations: Option<Violations>,
+}
+
+impl FailureReason {
+    /// The node id is taken from the processing, as it can be costly to extract from the file
+    pub fn new(file: &Path, node_id: Option<NodeId>, error: String) -> Self {
+        Self {
+            error,
+            timestamp: Utc::now(),
+            node_id,
+            attempts: retry::attempts(file),
+            queue: file
+                .parent()
+                .and_then(Path::file_name)
+                .and_then(|name| name.to_str())
+                .unwrap_or(DEFAULT_QUEUE)
+                .to_string(),
+            violations: None,
+        }
+    }
+
+    fn read(file: &Path) -> Result<Option<Self>, Error> {
+        match fs::read(reason_path(file)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+pub fn reason_path(file: &Path) -> PathBuf {
+    let mut name = file.file_name().map(OsString::from).unwrap_or_default();
+    name.push(REASON_SUFFIX);
+    file.with_file_name(name)
+}
+
+/// Failure reasons and signatures are moved along with their file
+fn is_companion(file: &Path) -> bool {
+    file.file_name()
+        .map(|name| name.to_string_lossy().ends_with(REASON_SUFFIX))
+        .unwrap_or(false)
+        || (file.extension().map(|e| e == "sign").unwrap_or(false)
+            && file.with_extension("").exists())
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct FailedFile {
+    pub name: String,
+    /// Size in bytes, including the signature and the failure reason
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    /// Missing for files failed before reasons were stored
+    pub reason: Option<FailureReason>,
+}
+
+impl FailedFile {
+    fn read(file: &Path) -> Result<Self, Error> {
+        let metadata = fs::metadata(file)?;
+        let companions_size: u64 = [reason_path(file), signature_path(file)]
+            .iter()
+            .filter_map(|companion| fs::metadata(companion).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        let reason = FailureReason::read(file).unwrap_or_else(|e| {
+            warn!("invalid failure reason for {:?}: {}", file, e);
+            None
+        });
+        Ok(Self {
+            name: file
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or(Error::InvalidFileName)?
+                .to_string(),
+            size: metadata.len() + companions_size,
+            modified: metadata.modified()?.into(),
+            reason,
+        })
+    }
+
+    /// Time of the failure, or of the reception for files without reason
+    fn failed_at(&self) -> DateTime<Utc> {
+        self.reason
+            .as_ref()
+            .map(|reason| reason.timestamp)
+            .unwrap_or(self.modified)
+    }
+}
+
+/// Lists failed files, sorted by name
+///
+/// Entries that cannot be read are skipped, as they may have been removed concurrently.
+pub fn list(directory: &RootDirectory, kind: FailedKind) -> Result<Vec<FailedFile>, Error> {
+    let files_directory = kind.files_directory(directory);
+    let mut files = vec![];
+    for entry in fs::read_dir(&files_directory)? {
+        let file = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                warn!("skipping entry in {:?}: {}", files_directory, e);
+                continue;
+            }
+        };
+        if !file.is_file() || is_companion(&file) {
+            continue;
+        }
+        match FailedFile::read(&file) {
+            Ok(failed_file) => files.push(failed_file),
+            Err(e) => warn!("skipping {:?}: {}", file, e),
+        }
+    }
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files)
+}
+
+/// Path of a failed file, from a name given by the client
+pub fn path(directory: &RootDirectory, kind: FailedKind, name: &str) -> Result<PathBuf, Error> {
+    let file = kind.files_directory(directory).join(name);
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.contains('/')
+        || is_companion(&file)
+        || !file.is_file()
+    {
+        return Err(Error::UnknownFailedFile(name.to_string()));
+    }
+    Ok(file)
+}
+
+/// Removes a failed file along with its signature and failure reason
+pub fn delete(directory: &RootDirectory, kind: FailedKind, name: &str) -> Result<(), Error> {
+    let file = path(directory, kind, name)?;
+    remove_file(&signature_path(&file))?;
+    remove_file(&reason_path(&file))?;
+    remove_file(&file)
+}
+
+/// Sends a failed file back to the directory it was received in,
+/// returns its new path
+pub fn requeue(directory: &RootDirectory, kind: FailedKind, name: &str) -> Result<PathBuf, Error> {
+    let file = path(directory, kind, name)?;
+    let queue = match FailureReason::read(&file)? {
+        Some(ref reason) if !reason.queue.starts_with('.') && !reason.queue.contains('/') => {
+            reason.queue.clone()
+        }
+        _ => DEFAULT_QUEUE.to_string(),
+    };
+    let destination = directory.join(queue).join(name);
+
+    let signature = signature_path(&file);
+    if signature.exists() {
+        // Copied first to get a recent modification time, so that the inventory
+        // is not processed without its signature when it is seen first
+        fs::copy(&signature, signature_path(&destination))?;
+        remove_file(&signature)?;
+    }
+    fs::rename(&file, &destination)?;
+    remove_file(&reason_path(&file))?;
+    info!("requeued {:?} to {:?}", file, destination);
+    Ok(destination)
+}
+
+/// Removes failed files older than `max_age` and the oldest ones exceeding
+/// `max_size`, returns the number of removed files
+fn prune(
+    directory: &RootDirectory,
+    kind: FailedKind,
+    max_age: ChronoDuration,
+    max_size: u64,
+    now: DateTime<Utc>,
+) -> Result<u64, Error> {
+    let mut files = list(directory, kind)?;
+    files.sort_by_key(FailedFile::failed_at);
+    let mut total_size: u64 = files.iter().map(|file| file.size).sum();
+    let mut removed = 0;
+
+    for file in files {
+        if now - file.failed_at() < max_age && total_size <= max_size {
+            // Remaining files are more recent
+            break;
+        }
+        match delete(directory, kind, &file.name) {
+            Ok(()) => {
+                info!(
+                    "removed failed file {} from {}",
+                    file.name,
+                    file.failed_at()
+                );
+                total_size -= file.size;
+                removed += 1;
+            }
+            Err(e) => warn!("could not remove failed file {}: {}", file.name, e),
+        }
+    }
+    Ok(removed)
+}
+
+pub fn start(job_config: &Arc<JobConfig>, kind: FailedKind) {
+    let span = span!(Level::TRACE, "failed");
+    let _enter = span.enter();
+
+    tokio::spawn(job_config.shutdown.until(cleanup(job_config.clone(), kind)));
+}
+
+/// Periodically removes old failed files
+fn cleanup(job_config: Arc<JobConfig>, kind: FailedKind) -> impl Future<Item = (), Error = ()> {
+    loop_fn((), move |_| {
+        let job_config = job_config.clone();
+        // Read at each iteration to apply configuration reloads
+        let cfg = kind.cfg(&job_config.cfg());
+
+        Delay::new(Instant::now() + Duration::from_secs(cfg.cleanup_frequency))
+            .map_err(|e| warn!("timer error: {}", e))
+            .and_then(move |_| {
+                let directory = kind.directory(&job_config.cfg());
+                let directory_log = directory.clone();
+                poll_fn(move || {
+                    blocking(|| {
+                        prune(
+                            &directory,
+                            kind,
+                            ChronoDuration::seconds(cfg.max_age as i64),
+                            cfg.max_size,
+                            Utc::now(),
+                        )
+                    })
+                    .map_err(|_| panic!("the thread pool shut down"))
+                })
+                .map(move |res| match res {
+                    Ok(removed) => {
+                        debug!("removed {} failed files from {:?}", removed, directory_log)
+                    }
+                    Err(e) => warn!("failed files cleanup error: {}", e),
+                })
+            })
+            .map(|_| Loop::<(), ()>::Continue(()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+    use tempfile::tempdir;
+
+    fn fail(directory: &Path, name: &str, error: &str, timestamp: DateTime<Utc>) {
+        let file = directory.join("incoming").join(name);
+        fs::write(&file, "content").unwrap();
+        let reason = FailureReason {
+            timestamp,
+            ..FailureReason::new(&file, Some("root".to_string()), error.to_string())
+        };
+        let failed = failed_directory(&directory.to_path_buf()).join(name);
+        fs::write(reason_path(&failed), serde_json::to_vec(&reason).unwrap()).unwrap();
+        fs::rename(&file, &failed).unwrap();
+    }
+
+    fn failed_tempdir() -> tempfile::TempDir {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("incoming")).unwrap();
+        fs::create_dir_all(dir.path().join("failed")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn it_stores_failure_reasons() {
+        let dir = failed_tempdir();
+        let directory = dir.path().to_path_buf();
+        let now = Utc::now();
+        fail(
+            &directory,
+            "2018-08-24T15:55:01+00:00@root.log",
+            "invalid run log",
+            now,
+        );
+        fs::write(directory.join("failed").join("node1.xml"), "inventory").unwrap();
+        fs::write(directory.join("failed").join("node1.xml.sign"), "sign").unwrap();
+
+        let files = list(&directory, FailedKind::Reports).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].name, "2018-08-24T15:55:01+00:00@root.log");
+        assert_eq!(
+            files[0].reason,
+            Some(FailureReason {
+                error: "invalid run log".to_string(),
+                timestamp: now,
+                node_id: Some("root".to_string()),
+                attempts: 1,
+                queue: "incoming".to_string(),
+                violations: None,
+            })
+        );
+        assert_eq!(files[1].name, "node1.xml");
+        assert_eq!(files[1].size, 13);
+        assert_eq!(files[1].reason, None);
+
+        assert!(path(&directory, FailedKind::Reports, "node1.xml.sign").is_err());
+        assert!(path(&directory, FailedKind::Reports, "../incoming").is_err());
+        delete(&directory, FailedKind::Reports, "node1.xml").unwrap();
+        assert!(!directory.join("failed").join("node1.xml.sign").exists());
+        assert_eq!(list(&directory, FailedKind::Reports).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn it_skips_unreadable_failed_files() {
+        let dir = failed_tempdir();
+        let directory = dir.path().to_path_buf();
+        fail(&directory, "file0", "error", Utc::now());
+        // Not valid UTF-8
+        fs::write(
+            directory
+                .join("failed")
+                .join(std::ffi::OsStr::from_bytes(b"file\xff")),
+            "content",
+        )
+        .unwrap();
+
+        let files = list(&directory, FailedKind::Reports).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "file0");
+    }
+
+    #[test]
+    fn it_manages_quarantined_files() {
+        let dir = failed_tempdir();
+        let directory = dir.path().to_path_buf();
+        let name = "2018-08-24T15:55:01+00:00@root.log";
+        fs::create_dir_all(quarantine_directory(&directory)).unwrap();
+        let file = directory.join("incoming").join(name);
+        fs::write(&file, "content").unwrap();
+        let reason = FailureReason {
+            violations: Some(Violations(vec![])),
+            ..FailureReason::new(&file, Some("root".to_string()), "inconsistent".to_string())
+        };
+        let quarantined = quarantine_directory(&directory).join(name);
+        fs::write(
+            reason_path(&quarantined),
+            serde_json::to_vec(&reason).unwrap(),
+        )
+        .unwrap();
+        fs::rename(&file, &quarantined).unwrap();
+
+        assert!(list(&directory, FailedKind::Reports).unwrap().is_empty());
+        let files = list(&directory, FailedKind::Quarantine).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].reason, Some(reason));
+
+        assert_eq!(
+            requeue(&directory, FailedKind::Quarantine, name).unwrap(),
+            directory.join("incoming").join(name)
+        );
+        assert!(list(&directory, FailedKind::Quarantine).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_requeues_failed_files() {
+        let dir = failed_tempdir();
+        let directory = dir.path().to_path_buf();
+        let name = "2018-08-24T15:55:01+00:00@root.log";
+        fail(&directory, name, "invalid run log", Utc::now());
+
+        assert_eq!(
+            requeue(&directory, FailedKind::Reports, name).unwrap(),
+            directory.join("incoming").join(name)
+        );
+        assert!(directory.join("incoming").join(name).exists());
+        assert!(fs::read_dir(directory.join("failed"))
+            .unwrap()
+            .next()
+            .is_none());
+        assert!(requeue(&directory, FailedKind::Reports, name).is_err());
+    }
+
+    #[test]
+    fn it_prunes_failed_files() {
+        let dir = failed_tempdir();
+        let directory = dir.path().to_path_buf();
+        let now = Utc::now();
+        // Same name lengths for same sizes
+        for (name, age) in &[("file0", 10), ("file1", 2), ("file2", 1), ("file3", 0)] {
+            fail(&directory, name, "error", now - ChronoDuration::days(*age));
+        }
+        let size = list(&directory, FailedKind::Reports).unwrap()[0].size;
+
+        assert_eq!(
+            prune(
+                &directory,
+                FailedKind::Reports,
+                ChronoDuration::days(7),
+                10 * size,
+                now
+            )
+            .unwrap(),
+            1
+        );
+        assert_eq!(
+            prune(
+                &directory,
+                FailedKind::Reports,
+                ChronoDuration::days(7),
+                2 * size,
+                now
+            )
+            .unwrap(),
+            1
+        );
+        let names: Vec<String> = list(&directory, FailedKind::Reports)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.name)
+            .collect();
+        assert_eq!(names, vec!["file2".to_string(), "file3".to_string()]);
+    }
+}
@@ -30,19 +30,50 @@
 
 use crate::{
     configuration::main::InventoryOutputSelect,
-    input::watch::*,
+    data::node::{NodeId, NodeIdRef},
+    error::Error,
+    input::{
+        inventory::{node_id, verify},
+        uncompressed,
+        upload::{upload_directory, Upload, UploadTarget},
+        watch::*,
+        Input,
+    },
+    metrics::Step,
     output::upstream::send_inventory,
-    processing::{failure, success, OutputError, ReceivedFile},
+    processing::{
+        failed::{self, failed_directory, reason_path, FailedKind, FailureReason},
+        failure,
+        retry::{retry, retry_directory, schedule, RetryDirectory},
+        success, OutputError, ReceivedFile, RootDirectory,
+    },
     stats::Event,
     JobConfig,
 };
-use futures::{future::Future, lazy, sync::mpsc, Stream};
+use futures::{
+    future::{self, poll_fn, Either, Future},
+    lazy,
+    sync::mpsc,
+    Stream,
+};
 use md5::{Digest, Md5};
-use std::{os::unix::ffi::OsStrExt, sync::Arc};
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::prelude::*;
-use tracing::{debug, error, info, span, Level};
+use tokio_threadpool::blocking;
+use tracing::{debug, error, span, warn, Level};
+
+static INVENTORY_EXTENSIONS: &[&str] = &["gz", "xz", "zst", "xml", "sign"];
 
-static INVENTORY_EXTENSIONS: &[&str] = &["gz", "xml", "sign"];
+/// Extension of inventory signatures, appended to the inventory file name
+const SIGNATURE_EXTENSION: &str = "sign";
 
 #[derive(Debug, Copy, Clone)]
 pub enum InventoryType {
@@ -50,44 +81,137 @@ pub enum InventoryType {
     Update,
 }
 
+impl InventoryType {
+    /// Name of the directory receiving inventories of this type
+    pub fn directory(self) -> &'static str {
+        match self {
+            InventoryType::New => "incoming",
+            InventoryType::Update => "accepted-nodes-updates",
+        }
+    }
+
+    /// Pipeline receiving uploaded inventories of this type
+    pub fn upload_target(self) -> UploadTarget {
+        match self {
+            InventoryType::New => UploadTarget::NewInventories,
+            InventoryType::Update => UploadTarget::InventoryUpdates,
+        }
+    }
+
+    /// Catchup settings of the directory receiving inventories of this type
+    fn catchup(self) -> CatchupSelector {
+        match self {
+            InventoryType::New => |cfg| cfg.processing.inventory.catchup,
+            InventoryType::Update => |cfg| {
+                cfg.processing
+                    .inventory
+                    .updates_catchup
+                    .unwrap_or(cfg.processing.inventory.catchup)
+            },
+        }
+    }
+}
+
+pub fn has_inventory_extension(file: &Path) -> bool {
+    file.extension()
+        .map(|f| INVENTORY_EXTENSIONS.contains(&f.to_string_lossy().as_ref()))
+        .unwrap_or(false)
+}
+
+pub fn signature_path(inventory: &Path) -> PathBuf {
+    let mut name = inventory
+        .file_name()
+        .map(OsString::from)
+        .unwrap_or_default();
+    name.push(".");
+    name.push(SIGNATURE_EXTENSION);
+    inventory.with_file_name(name)
+}
+
+/// Inventories and their signatures are received as separate files, in any order
+#[derive(Debug, PartialEq, Eq)]
+enum Pairing {
+    /// Inventory ready to be processed, with its signature if any
+    Complete(ReceivedFile, Option<ReceivedFile>),
+    /// Other file of the pair not received yet, catchup will bring the file back
+    Waiting,
+    /// Signature whose inventory was never received
+    Orphan(ReceivedFile),
+}
+
+fn age(file: &Path) -> Result<Duration, Error> {
+    Ok(fs::metadata(file)?
+        .modified()?
+        .elapsed()
+        // An error indicates a file in the future, let's approximate it to now
+        .unwrap_or_else(|_| Duration::new(0, 0)))
+}
+
+/// Finds the other file of the pair, waiting for it up to `grace_period`
+fn pair(file: &Path, grace_period: Duration) -> Result<Pairing, Error> {
+    if file
+        .extension()
+        .map(|e| e == SIGNATURE_EXTENSION)
+        .unwrap_or(false)
+    {
+        let inventory = file.with_extension("");
+        Ok(if inventory.exists() {
+            Pairing::Complete(inventory, Some(file.to_path_buf()))
+        } else if age(file)? >= grace_period {
+            Pairing::Orphan(file.to_path_buf())
+        } else {
+            Pairing::Waiting
+        })
+    } else {
+        let age = age(file)?;
+        let signature = signature_path(file);
+        Ok(if signature.exists() {
+            Pairing::Complete(file.to_path_buf(), Some(signature))
+        } else if age >= grace_period {
+            Pairing::Complete(file.to_path_buf(), None)
+        } else {
+            Pairing::Waiting
+        })
+    }
+}
+
 pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) {
     let span = span!(Level::TRACE, "inventory");
     let _enter = span.enter();
 
-    let (sender, receiver) = mpsc::channel(1_024);
-    tokio::spawn(serve(
-        job_config.clone(),
-        receiver,
-        InventoryType::New,
-        stats.clone(),
-    ));
-    watch(
-        &job_config
-            .cfg
-            .processing
-            .inventory
-            .directory
-            .join("incoming"),
-        &job_config,
-        &sender,
-    );
-    let (sender, receiver) = mpsc::channel(1_024);
-    tokio::spawn(serve(
-        job_config.clone(),
-        receiver,
-        InventoryType::Update,
-        stats.clone(),
-    ));
-    watch(
-        &job_config
-            .cfg
-            .processing
-            .inventory
-            .directory
-            .join("accepted-nodes-updates"),
-        &job_config,
-        &sender,
-    );
+    failed::start(job_config, FailedKind::Inventories);
+    for inventory_type in &[InventoryType::New, InventoryType::Update] {
+        let (sender, receiver) = mpsc::channel(1_024);
+        tokio::spawn(job_config.shutdown.track(serve(
+            job_config.clone(),
+            receiver,
+            *inventory_type,
+            stats.clone(),
+        )));
+        tokio::spawn(job_config.shutdown.until(schedule(
+            retry_directory(
+                &job_config.cfg().processing.inventory.directory,
+                inventory_type.directory(),
+            ),
+            inventory_type.catchup()(&job_config.cfg()).frequency,
+            job_config.cfg().processing.inventory.retry,
+            sender.clone(),
+        )));
+        let directory = job_config.cfg().processing.inventory.directory.clone();
+        let inputs: Vec<Box<dyn Input>> = vec![
+            Box::new(Watch {
+                path: directory.join(inventory_type.directory()),
+                catchup: inventory_type.catchup(),
+            }),
+            Box::new(Upload {
+                target: inventory_type.upload_target(),
+                directory: upload_directory(&directory, inventory_type.directory()),
+            }),
+        ];
+        for input in inputs {
+            input.start(&job_config, &sender);
+        }
+    }
 }
 
 fn serve(
@@ -96,13 +220,13 @@ fn serve(
     inventory_type: InventoryType,
     stats: mpsc::Sender<Event>,
 ) -> impl Future<Item = (), Error = ()> {
+    // Both files of a pair trigger processing, and catchup can send
+    // them again while they are being sent
+    let in_progress: Arc<Mutex<HashSet<ReceivedFile>>> = Arc::new(Mutex::new(HashSet::new()));
+
     rx.for_each(move |file| {
         // allows skipping temporary .dav files
-        if !file
-            .extension()
-            .map(|f| INVENTORY_EXTENSIONS.contains(&f.to_string_lossy().as_ref()))
-            .unwrap_or(false)
-        {
+        if !has_inventory_extension(&file) {
             debug!(
                 "skipping {:#?} as it does not have a known inventory extension",
                 file
@@ -110,6 +234,49 @@ fn serve(
             return Ok(());
         }
 
+        let grace_period =
+            Duration::from_secs(job_config.cfg().processing.inventory.signature_grace_period);
+        let (file, signature) = match pair(&file, grace_period) {
+            Ok(Pairing::Complete(inventory, signature)) => (inventory, signature),
+            Ok(Pairing::Waiting) => {
+                debug!("waiting for the other file of the pair of {:#?}", file);
+                return Ok(());
+            }
+            Ok(Pairing::Orphan(signature)) => {
+                warn!("no inventory received for signature {:#?}", signature);
+                let destination =
+                    failed_directory(&job_config.cfg().processing.inventory.directory)
+                        .join(signature.file_name().expect("not a file"));
+                let reason = FailureReason::new(
+                    &signature,
+                    None,
+                    "no inventory received for signature".into(),
+                );
+                tokio::spawn(lazy(move || {
+                    serde_json::to_vec_pretty(&reason)
+                        .map_err(Error::from)
+                        .and_then(|content| Ok(fs::write(reason_path(&destination), content)?))
+                        .and_then(|_| Ok(fs::rename(&signature, &destination)?))
+                        .map_err(|e| error!("error: {}", e))
+                }));
+                return Ok(());
+            }
+            // Most likely already processed
+            Err(e) => {
+                debug!("skipping {:#?}: {}", file, e);
+                return Ok(());
+            }
+        };
+
+        if !in_progress
+            .lock()
+            .expect("could not lock inventories in progress")
+            .insert(file.clone())
+        {
+            debug!("skipping {:#?} as it is already being processed", file);
+            return Ok(());
+        }
+
         let queue_id = format!(
             "{:X}",
             Md5::digest(
@@ -136,9 +303,10 @@ fn serve(
         debug!("received: {:?}", file);
 
         let treat_file: Box<dyn Future<Item = (), Error = ()> + Send> =
-            match job_config.cfg.processing.inventory.output {
+            match job_config.cfg().processing.inventory.output {
                 InventoryOutputSelect::Upstream => output_inventory_upstream(
                     file.clone(),
+                    signature,
                     inventory_type,
                     job_config.clone(),
                     stats.clone(),
@@ -149,44 +317,283 @@ fn serve(
                 }
             };
 
-        tokio::spawn(lazy(|| treat_file));
+        let in_progress = in_progress.clone();
+        tokio::spawn(
+            job_config
+                .shutdown
+                .track(lazy(|| treat_file).then(move |_| {
+                    in_progress
+                        .lock()
+                        .expect("could not lock inventories in progress")
+                        .remove(&file);
+                    Ok(())
+                })),
+        );
         Ok(())
     })
 }
 
 fn output_inventory_upstream(
     path: ReceivedFile,
+    signature: Option<ReceivedFile>,
     inventory_type: InventoryType,
     job_config: Arc<JobConfig>,
     stats: mpsc::Sender<Event>,
 ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
     let job_config_clone = job_config.clone();
+    let job_config_check = job_config.clone();
+    let job_config_send = job_config.clone();
+    let path_check = path.clone();
     let path_clone2 = path.clone();
+    let path_clone3 = path.clone();
+    let path_follow = path.clone();
+    let signature_check = signature.clone();
+    let signature_send = signature.clone();
     let stats_clone = stats.clone();
+    let queue = retry_directory(
+        &job_config.cfg().processing.inventory.directory,
+        inventory_type.directory(),
+    );
+    let directory = job_config.cfg().processing.inventory.directory.clone();
+
     Box::new(
-        send_inventory(job_config.clone(), path.clone(), inventory_type)
-            .map_err(|e| {
-                error!("output error: {}", e);
-                OutputError::from(e)
+        poll_fn(move || {
+            blocking(|| {
+                check_inventory(
+                    &path_check,
+                    signature_check.as_ref(),
+                    inventory_type,
+                    &job_config_check,
+                )
             })
-            .or_else(move |e| match e {
-                OutputError::Permanent => failure(
-                    path_clone2.clone(),
-                    job_config_clone
-                        .clone()
-                        .cfg
-                        .processing
-                        .inventory
-                        .directory
-                        .clone(),
-                    Event::InventoryRefused,
-                    stats.clone(),
-                ),
-                OutputError::Transient => {
-                    info!("transient error, skipping");
-                    Box::new(futures::future::err::<(), ()>(()))
-                }
+            .map_err(|_| -> (Option<NodeId>, Error) { panic!("the thread pool shut down") })
+        })
+        .flatten()
+        .and_then(move |node_id| {
+            let timer = job_config.metrics.timer(Step::Upstream);
+            // The signature is sent first, so that the inventory is complete
+            // when upstream starts processing it
+            match signature_send {
+                Some(signature) => Either::A(send_inventory(
+                    job_config_send.clone(),
+                    signature,
+                    inventory_type,
+                )),
+                None => Either::B(future::ok(())),
+            }
+            .and_then(move |_| send_inventory(job_config.clone(), path.clone(), inventory_type))
+            .then(move |res| {
+                drop(timer);
+                res
             })
-            .and_then(move |_| success(path.clone(), Event::InventorySent, stats_clone.clone())),
+            .map_err(|e| (Some(node_id), e))
+        })
+        .map_err(|(node_id, e)| {
+            error!("output error: {}", e);
+            (OutputError::from(&e), node_id, e.to_string())
+        })
+        .or_else(move |(e, node_id, message)| match e {
+            OutputError::Permanent => failure(
+                path_clone2.clone(),
+                job_config_clone
+                    .clone()
+                    .cfg()
+                    .processing
+                    .inventory
+                    .directory
+                    .clone(),
+                FailureReason::new(&path_clone2, node_id, message),
+                Event::InventoryRefused,
+                stats.clone(),
+            ),
+            OutputError::Transient => retry(
+                path_clone2.clone(),
+                FailureReason::new(&path_clone2, node_id, message),
+                retry_directory(
+                    &job_config_clone.cfg().processing.inventory.directory,
+                    inventory_type.directory(),
+                ),
+                job_config_clone
+                    .cfg()
+                    .processing
+                    .inventory
+                    .directory
+                    .clone(),
+                job_config_clone.cfg().processing.inventory.retry,
+                Event::InventoryRefused,
+                stats.clone(),
+            ),
+        })
+        .and_then(move |_| {
+            success(
+                path_clone3.clone(),
+                Event::InventorySent,
+                stats_clone.clone(),
+            )
+        })
+        .then(move |res| {
+            follow_inventory(&path_follow, signature, &queue, &directory);
+            res
+        }),
     )
 }
+
+/// Checks the inventory content and its signature, returns the node id it declares
+///
+/// Errors come with the node id when it could be read, to store it in the failure reason.
+fn check_inventory(
+    path: &Path,
+    signature: Option<&ReceivedFile>,
+    inventory_type: InventoryType,
+    job_config: &Arc<JobConfig>,
+) -> Result<NodeId, (Option<NodeId>, Error)> {
+    let data = fs::read(path).map_err(|e| (None, e.into()))?;
+    let node_id = uncompressed(path, &data)
+        .and_then(|inventory| node_id(&inventory))
+        .map_err(|e| (None, e))?;
+    check_signature(path, &data, &node_id, signature, inventory_type, job_config)
+        .map(|_| node_id.clone())
+        .map_err(|e| (Some(node_id), e))
+}
+
+fn check_signature(
+    path: &Path,
+    data: &[u8],
+    node_id: &NodeIdRef,
+    signature: Option<&ReceivedFile>,
+    inventory_type: InventoryType,
+    job_config: &Arc<JobConfig>,
+) -> Result<(), Error> {
+    let _timer = job_config.metrics.timer(Step::Signature);
+    match (signature, inventory_type) {
+        (Some(signature), InventoryType::Update) => verify(
+            data,
+            &fs::read_to_string(signature)?,
+            Some((
+                node_id,
+                &job_config.nodes.read().expect("could not read nodes list"),
+            )),
+        ),
+        // The node is not known yet
+        (Some(signature), InventoryType::New) => {
+            verify(data, &fs::read_to_string(signature)?, None)
+        }
+        (None, InventoryType::Update) => Err(Error::InvalidInventorySignature(
+            "missing signature".to_string(),
+        )),
+        (None, InventoryType::New) => {
+            warn!("forwarding {:#?} from {} without signature", path, node_id);
+            Ok(())
+        }
+    }
+}
+
+/// Moves the signature where its inventory went after processing, or removes it
+/// if the inventory was sent
+fn follow_inventory(
+    inventory: &Path,
+    signature: Option<ReceivedFile>,
+    queue: &RetryDirectory,
+    directory: &RootDirectory,
+) {
+    let signature = match signature {
+        Some(signature) => signature,
+        None => return,
+    };
+    // Still in place if the processing stopped before the end
+    if inventory.exists() {
+        return;
+    }
+
+    let inventory_name = inventory.file_name().expect("not a file");
+    let signature_name = signature.file_name().expect("not a file");
+    let destination = if queue.join(inventory_name).exists() {
+        Some(queue.join(signature_name))
+    } else if directory.join("failed").join(inventory_name).exists() {
+        Some(directory.join("failed").join(signature_name))
+    } else {
+        None
+    };
+
+    let res = match destination {
+        Some(ref destination) if *destination == signature => Ok(()),
+        Some(destination) => fs::rename(&signature, &destination)
+            .map(|_| debug!("moved: {:#?} to {:#?}", signature, destination)),
+        None => fs::remove_file(&signature).map(|_| debug!("deleted: {:#?}", signature)),
+    };
+    if let Err(e) = res {
+        error!("error: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filetime::{set_file_mtime, FileTime};
+    use std::time::SystemTime;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_pairs_inventories_with_signatures() {
+        let dir = tempdir().unwrap();
+        let inventory = dir.path().join("node1.xml.gz");
+        let signature = dir.path().join("node1.xml.gz.sign");
+        let grace_period = Duration::from_secs(60);
+        let old = FileTime::from_system_time(SystemTime::now() - Duration::from_secs(120));
+
+        fs::write(&inventory, "inventory").unwrap();
+        assert_eq!(pair(&inventory, grace_period).unwrap(), Pairing::Waiting);
+        set_file_mtime(&inventory, old).unwrap();
+        assert_eq!(
+            pair(&inventory, grace_period).unwrap(),
+            Pairing::Complete(inventory.clone(), None)
+        );
+
+        fs::write(&signature, "signature").unwrap();
+        assert_eq!(
+            pair(&inventory, grace_period).unwrap(),
+            Pairing::Complete(inventory.clone(), Some(signature.clone()))
+        );
+        assert_eq!(
+            pair(&signature, grace_period).unwrap(),
+            Pairing::Complete(inventory.clone(), Some(signature.clone()))
+        );
+
+        fs::remove_file(&inventory).unwrap();
+        assert_eq!(pair(&signature, grace_period).unwrap(), Pairing::Waiting);
+        set_file_mtime(&signature, old).unwrap();
+        assert_eq!(
+            pair(&signature, grace_period).unwrap(),
+            Pairing::Orphan(signature.clone())
+        );
+        assert!(pair(&inventory, grace_period).is_err());
+    }
+
+    #[test]
+    fn it_moves_signatures_with_inventories() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_path_buf();
+        let queue = directory.join("retry").join("incoming");
+        fs::create_dir_all(&queue).unwrap();
+        fs::create_dir_all(directory.join("failed")).unwrap();
+        let inventory = directory.join("incoming").join("node1.xml");
+        let signature = directory.join("incoming").join("node1.xml.sign");
+        fs::create_dir_all(directory.join("incoming")).unwrap();
+
+        fs::write(&signature, "signature").unwrap();
+        fs::write(queue.join("node1.xml"), "inventory").unwrap();
+        follow_inventory(&inventory, Some(signature.clone()), &queue, &directory);
+        assert!(queue.join("node1.xml.sign").exists());
+
+        fs::write(&signature, "signature").unwrap();
+        fs::remove_file(queue.join("node1.xml")).unwrap();
+        fs::write(directory.join("failed").join("node1.xml"), "inventory").unwrap();
+        follow_inventory(&inventory, Some(signature.clone()), &queue, &directory);
+        assert!(directory.join("failed").join("node1.xml.sign").exists());
+
+        fs::write(&signature, "signature").unwrap();
+        fs::remove_file(directory.join("failed").join("node1.xml")).unwrap();
+        follow_inventory(&inventory, Some(signature.clone()), &queue, &directory);
+        assert!(!signature.exists());
+    }
+}
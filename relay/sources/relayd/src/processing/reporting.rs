@@ -29,54 +29,111 @@
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{
-    configuration::main::ReportingOutputSelect,
-    data::{RunInfo, RunLog},
+    configuration::main::{
+        AgentLogOutput, ReportCheck, ReportingConfig, ReportingOutputSelect, RunlogValidation,
+    },
+    data::{node::NodeId, RunInfo, RunLog},
     error::Error,
-    input::{read_compressed_file, signature, watch::*},
+    input::{
+        decompress_file, signature,
+        upload::{upload_directory, Upload, UploadTarget},
+        watch::*,
+        Input,
+    },
+    metrics::Step,
     output::{
-        database::{insert_runlog, InsertionBehavior},
-        upstream::send_report,
+        database::{
+            insert_runlog_chunks, insertion_queue, queue_runlog, InsertionBehavior, InsertionQueue,
+        },
+        upstream::{send_modified_report, send_report, signed_runlog},
+    },
+    processing::{
+        failed::{self, FailedKind, FailureReason},
+        failure, quarantine,
+        retry::{retry, retry_directory, schedule},
+        success, OutputError, ReceivedFile,
     },
-    processing::{failure, success, OutputError, ReceivedFile},
     stats::Event,
     JobConfig,
 };
 use futures::{
-    future::{poll_fn, Future},
+    future::{self, poll_fn, Either, Future},
     lazy,
     sync::mpsc,
     Stream,
 };
 use md5::{Digest, Md5};
-use std::{convert::TryFrom, os::unix::ffi::OsStrExt, sync::Arc};
+use std::{
+    convert::TryFrom,
+    fs::{self, File},
+    io::{self, BufReader},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use tokio::prelude::*;
 use tokio_threadpool::blocking;
-use tracing::{debug, error, info, span, warn, Level};
+use tracing::{debug, error, span, warn, Level};
 
-static REPORT_EXTENSIONS: &[&str] = &["gz", "log"];
+static REPORT_EXTENSIONS: &[&str] = &["gz", "xz", "zst", "log"];
+
+/// Larger runlogs are inserted while being parsed instead of by batches,
+/// to keep memory use bounded
+const STREAMED_RUNLOG_SIZE: u64 = 10 * 1024 * 1024;
+/// Reports kept in memory when inserting a runlog while parsing it
+const STREAMED_CHUNK_SIZE: usize = 1_000;
 
 pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) {
     let span = span!(Level::TRACE, "reporting");
     let _enter = span.enter();
 
+    // Runlogs are inserted by batches, the writer ends once all files are processed
+    let insertion = match job_config.cfg().processing.reporting.output {
+        ReportingOutputSelect::Database => {
+            let (queue, writer) = insertion_queue(job_config.clone());
+            tokio::spawn(job_config.shutdown.track(writer));
+            Some(queue)
+        }
+        _ => None,
+    };
+
     let (sender, receiver) = mpsc::channel(1_024);
-    tokio::spawn(serve(job_config.clone(), receiver, stats.clone()));
-    watch(
-        &job_config
-            .cfg
-            .processing
-            .reporting
-            .directory
-            .join("incoming"),
-        &job_config,
-        &sender,
-    );
+    // Serving ends once all watchers are stopped and the queue is empty
+    tokio::spawn(job_config.shutdown.track(serve(
+        job_config.clone(),
+        receiver,
+        stats.clone(),
+        insertion,
+    )));
+    tokio::spawn(job_config.shutdown.until(schedule(
+        retry_directory(&job_config.cfg().processing.reporting.directory, "incoming"),
+        job_config.cfg().processing.reporting.catchup.frequency,
+        job_config.cfg().processing.reporting.retry,
+        sender.clone(),
+    )));
+    failed::start(job_config, FailedKind::Reports);
+    failed::start(job_config, FailedKind::Quarantine);
+    let directory = job_config.cfg().processing.reporting.directory.clone();
+    let inputs: Vec<Box<dyn Input>> = vec![
+        Box::new(Watch {
+            path: directory.join("incoming"),
+            catchup: |cfg| cfg.processing.reporting.catchup,
+        }),
+        Box::new(Upload {
+            target: UploadTarget::Reports,
+            directory: upload_directory(&directory, "incoming"),
+        }),
+    ];
+    for input in inputs {
+        input.start(&job_config, &sender);
+    }
 }
 
 fn serve(
     job_config: Arc<JobConfig>,
     rx: mpsc::Receiver<ReceivedFile>,
     stats: mpsc::Sender<Event>,
+    insertion: Option<InsertionQueue>,
 ) -> impl Future<Item = (), Error = ()> {
     rx.for_each(move |file| {
         // allows skipping temporary .dav files
@@ -131,9 +188,15 @@ fn serve(
             .expect("Cannot read nodes list")
             .is_subnode(&info.node_id)
         {
+            let reason = FailureReason::new(
+                &file,
+                Some(info.node_id.clone()),
+                format!("unknown node {}", info.node_id),
+            );
             let fail = failure(
                 file,
-                job_config.cfg.processing.reporting.directory.clone(),
+                job_config.cfg().processing.reporting.directory.clone(),
+                reason,
                 Event::ReportRefused,
                 stats.clone(),
             );
@@ -148,18 +211,24 @@ fn serve(
         debug!("received: {:?}", file);
 
         let treat_file: Box<dyn Future<Item = (), Error = ()> + Send> =
-            match job_config.cfg.processing.reporting.output {
-                ReportingOutputSelect::Database => {
-                    output_report_database(file.clone(), info, job_config.clone(), stats.clone())
-                }
+            match job_config.cfg().processing.reporting.output {
+                ReportingOutputSelect::Database => output_report_database(
+                    file.clone(),
+                    info,
+                    job_config.clone(),
+                    stats.clone(),
+                    insertion
+                        .clone()
+                        .expect("output uses database but no insertion queue"),
+                ),
                 ReportingOutputSelect::Upstream => {
-                    output_report_upstream(file.clone(), job_config.clone(), stats.clone())
+                    output_report_upstream(file.clone(), info, job_config.clone(), stats.clone())
                 }
                 // The job should not be started in this case
                 ReportingOutputSelect::Disabled => unreachable!("Report server should be disabled"),
             };
 
-        tokio::spawn(lazy(|| treat_file));
+        tokio::spawn(job_config.shutdown.track(lazy(|| treat_file)));
         Ok(())
     })
 }
@@ -169,130 +238,357 @@ fn output_report_database(
     run_info: RunInfo,
     job_config: Arc<JobConfig>,
     stats: mpsc::Sender<Event>,
+    insertion: InsertionQueue,
 ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
-    // Everything here is blocking: reading on disk or inserting into database
+    // Everything here is blocking: reading on disk or parsing
     // We could use tokio::fs but it works the same and only makes things
     // more complicated.
     // We can switch to it once we also have stream (i.e. not on disk) input.
+    let node_id = run_info.node_id.clone();
     let job_config_clone = job_config.clone();
     let path_clone = path.clone();
     let path_clone2 = path.clone();
     let stats_clone = stats.clone();
     Box::new(
         poll_fn(move || {
-            blocking(|| {
-                output_report_database_inner(&path_clone.clone(), &run_info, &job_config).map_err(
-                    |e| {
-                        error!("output error: {}", e);
-                        OutputError::from(e)
-                    },
-                )
-            })
-            .map_err(|_| {
-                panic!("the thread pool shut down");
-                // Temp hack to fix typing
-                #[allow(unreachable_code)]
-                OutputError::Transient
-            })
+            blocking(|| queued_runlog(&path_clone.clone(), &run_info, &job_config))
+                .map_err(|_| -> Error { panic!("the thread pool shut down") })
         })
         .flatten()
-        .or_else(move |e| match e {
-            OutputError::Permanent => failure(
+        // Insertion is done by batches, outside of the blocking thread
+        .and_then(move |runlog| match runlog {
+            Some(runlog) => Either::A(queue_runlog(insertion, runlog).map(|_inserted| ())),
+            None => Either::B(future::ok(())),
+        })
+        .or_else(move |e| {
+            output_error(
+                e,
                 path_clone2.clone(),
-                job_config_clone
-                    .clone()
-                    .cfg
-                    .processing
-                    .reporting
-                    .directory
-                    .clone(),
-                Event::ReportRefused,
+                node_id.clone(),
+                &job_config_clone,
                 stats.clone(),
-            ),
-            OutputError::Transient => {
-                info!("transient error, skipping");
-                Box::new(futures::future::err::<(), ()>(()))
-            }
+            )
         })
         .and_then(move |_| success(path.clone(), Event::ReportInserted, stats_clone.clone())),
     )
 }
 
+/// Moves a file that could not be output, depending on the error
+fn output_error(
+    error: Error,
+    path: ReceivedFile,
+    node_id: NodeId,
+    job_config: &Arc<JobConfig>,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    error!("output error: {}", error);
+    let cfg = job_config.cfg();
+    let directory = cfg.processing.reporting.directory.clone();
+
+    let reason = FailureReason::new(&path, Some(node_id), error.to_string());
+
+    match error {
+        Error::RunlogViolations(violations)
+            if cfg.processing.reporting.validation == RunlogValidation::Quarantine
+                && !violations.rejects_runlog() =>
+        {
+            let reason = FailureReason {
+                violations: Some(violations),
+                ..reason
+            };
+            quarantine(path, directory, reason, Event::ReportRefused, stats)
+        }
+        error => match OutputError::from(&error) {
+            OutputError::Permanent => failure(path, directory, reason, Event::ReportRefused, stats),
+            OutputError::Transient => retry(
+                path,
+                reason,
+                retry_directory(&directory, "incoming"),
+                directory.clone(),
+                cfg.processing.reporting.retry,
+                Event::ReportRefused,
+                stats,
+            ),
+        },
+    }
+}
+
 fn output_report_upstream(
     path: ReceivedFile,
+    run_info: RunInfo,
     job_config: Arc<JobConfig>,
     stats: mpsc::Sender<Event>,
 ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    let node_id = run_info.node_id.clone();
     let job_config_clone = job_config.clone();
+    let job_config_check = job_config.clone();
+    let path_clone = path.clone();
     let path_clone2 = path.clone();
+    let path_send = path.clone();
     let stats_clone = stats.clone();
+
+    // Avoid forwarding invalid reports
+    let check: Box<dyn Future<Item = Option<Vec<u8>>, Error = Error> + Send> =
+        match job_config.cfg().processing.reporting.upstream_check {
+            ReportCheck::Disabled if !job_config.cfg().processing.reporting.modifies_runlogs() => {
+                Box::new(futures::future::ok(None))
+            }
+            check => Box::new(
+                poll_fn(move || {
+                    blocking(|| {
+                        check_report_inner(&path_clone, &run_info, &job_config_check, check)
+                    })
+                    .map_err(|_| -> Error { panic!("the thread pool shut down") })
+                })
+                .flatten(),
+            ),
+        };
+
     Box::new(
-        send_report(job_config.clone(), path.clone())
-            .map_err(|e| {
-                error!("output error: {}", e);
-                OutputError::from(e)
+        check
+            .and_then(move |modified_runlog| {
+                let timer = job_config.metrics.timer(Step::Upstream);
+                match modified_runlog {
+                    Some(runlog) => send_modified_report(job_config.clone(), path_send, runlog),
+                    None => send_report(job_config.clone(), path_send),
+                }
+                .then(move |res| {
+                    drop(timer);
+                    res
+                })
             })
-            .or_else(move |e| match e {
-                OutputError::Permanent => failure(
+            .or_else(move |e| {
+                output_error(
+                    e,
                     path_clone2.clone(),
-                    job_config_clone
-                        .clone()
-                        .cfg
-                        .processing
-                        .reporting
-                        .directory
-                        .clone(),
-                    Event::ReportRefused,
+                    node_id.clone(),
+                    &job_config_clone,
                     stats.clone(),
-                ),
-                OutputError::Transient => {
-                    info!("transient error, skipping");
-                    Box::new(futures::future::err::<(), ()>(()))
-                }
+                )
             })
             .and_then(move |_| success(path.clone(), Event::ReportSent, stats_clone.clone())),
     )
 }
 
-fn output_report_database_inner(
+/// Verified and routed runlog, ready for insertion, and whether it was modified
+fn parsed_runlog(
     path: &ReceivedFile,
     run_info: &RunInfo,
     job_config: &Arc<JobConfig>,
-) -> Result<(), Error> {
-    debug!("Starting insertion of {:#?}", path);
+) -> Result<(RunLog, bool), Error> {
+    debug!("Starting parsing of {:#?}", path);
 
-    let signed_runlog = signature(
-        &read_compressed_file(&path)?,
-        job_config
-            .clone()
-            .nodes
-            .read()
-            .expect("read nodes")
-            .certs(&run_info.node_id)
-            .ok_or_else(|| Error::MissingCertificateForNode(run_info.node_id.clone()))?,
+    verified_runlog(path, run_info, job_config, |verified| {
+        processed_runlog(verified, run_info, job_config)
+    })
+}
+
+/// Parses and processes a verified runlog from a file
+fn processed_runlog(
+    path: &Path,
+    run_info: &RunInfo,
+    job_config: &Arc<JobConfig>,
+) -> Result<(RunLog, bool), Error> {
+    let timer = job_config.metrics.timer(Step::Parse);
+    let cfg = job_config.cfg();
+    let mut parsed_runlog = RunLog::from_reader(
+        run_info.clone(),
+        BufReader::new(File::open(path)?),
+        cfg.processing.reporting.validation,
     )?;
+    drop(timer);
 
-    let parsed_runlog = RunLog::try_from((run_info.clone(), signed_runlog.as_ref()))?;
-
-    let filtered_runlog = if !job_config
-        .cfg
-        .processing
-        .reporting
-        .skip_event_types
-        .is_empty()
-    {
-        parsed_runlog.without_types(&job_config.cfg.processing.reporting.skip_event_types)
-    } else {
-        parsed_runlog
-    };
+    let modified = process(&mut parsed_runlog, &cfg.processing.reporting)?;
+    Ok((parsed_runlog, modified))
+}
+
+/// Applies routing rules and agent logs output, returns whether the runlog was modified
+fn process(runlog: &mut RunLog, cfg: &ReportingConfig) -> Result<bool, Error> {
+    let routed = runlog.route(&cfg.rules)?;
+    let logs_modified = runlog.output_logs(cfg.agent_logs);
+    Ok(routed || logs_modified)
+}
+
+/// Moves agent logs among the reports when they are not stored in their own table
+fn database_logs(runlog: &mut RunLog, cfg: &ReportingConfig) {
+    if cfg.agent_logs == AgentLogOutput::Legacy {
+        runlog.flatten_logs();
+    }
+}
+
+/// Parsed runlog to queue for insertion, or `None` if it was large enough
+/// to be inserted while being parsed
+fn queued_runlog(
+    path: &ReceivedFile,
+    run_info: &RunInfo,
+    job_config: &Arc<JobConfig>,
+) -> Result<Option<RunLog>, Error> {
+    debug!("Starting parsing of {:#?}", path);
+
+    verified_runlog(path, run_info, job_config, |verified| {
+        let size = fs::metadata(verified)?.len();
+        if size <= STREAMED_RUNLOG_SIZE {
+            return processed_runlog(verified, run_info, job_config).map(
+                |(mut runlog, _modified)| {
+                    database_logs(&mut runlog, &job_config.cfg().processing.reporting);
+                    Some(runlog)
+                },
+            );
+        }
+
+        debug!(
+            "Inserting {:#?} while parsing it, as it is {} bytes large",
+            path, size
+        );
+        insert_verified_runlog(verified, run_info, job_config).map(|_| None)
+    })
+}
+
+/// Directory containing the verified content of runlogs while they are parsed
+pub fn parsing_directory(directory: &Path) -> PathBuf {
+    directory.join("parsing")
+}
+
+/// Parses a verified runlog from a file and inserts it by chunks
+fn insert_verified_runlog(
+    path: &Path,
+    run_info: &RunInfo,
+    job_config: &Arc<JobConfig>,
+) -> Result<(), Error> {
+    let cfg = job_config.cfg();
+    let chunks = RunLog::chunks(
+        run_info.clone(),
+        BufReader::new(File::open(path)?),
+        STREAMED_CHUNK_SIZE,
+        cfg.processing.reporting.validation,
+    )
+    .filter_map(|chunk| {
+        match chunk.and_then(|mut chunk| {
+            process(&mut chunk, &cfg.processing.reporting).map(|_modified| {
+                database_logs(&mut chunk, &cfg.processing.reporting);
+                chunk
+            })
+        }) {
+            // All reports of the chunk were dropped by routing rules
+            Err(Error::EmptyRunlog) => None,
+            chunk => Some(chunk),
+        }
+    });
 
-    let _inserted = insert_runlog(
+    let _timer = job_config.metrics.timer(Step::Insert);
+    insert_runlog_chunks(
         &job_config
-            .pool
-            .clone()
+            .pool()
             .expect("output uses database but no config provided"),
-        &filtered_runlog,
+        chunks,
         InsertionBehavior::SkipDuplicate,
     )?;
     Ok(())
 }
+
+/// Checks the signature of the report and gives the file containing
+/// the signed content to `parse`
+///
+/// The file is removed from the parsing directory once parsed.
+fn verified_runlog<T, F>(
+    path: &ReceivedFile,
+    run_info: &RunInfo,
+    job_config: &Arc<JobConfig>,
+    parse: F,
+) -> Result<T, Error>
+where
+    F: FnOnce(&Path) -> Result<T, Error>,
+{
+    let verified = parsing_directory(&job_config.cfg().processing.reporting.directory)
+        .join(path.file_name().expect("received file should have a name"));
+    let res =
+        write_verified_runlog(path, &verified, run_info, job_config).and_then(|_| parse(&verified));
+    match fs::remove_file(&verified) {
+        Err(ref e) if e.kind() != io::ErrorKind::NotFound => {
+            warn!("could not remove {:#?}: {}", verified, e)
+        }
+        _ => (),
+    }
+    res
+}
+
+/// Decompresses the report into `verified` and replaces it with the signed content
+///
+/// Decompression is streamed to the file, but S/MIME verification needs the whole
+/// content in memory. It is released before parsing, which reads the file by chunks.
+///
+/// Run logs modified on a relay are signed by the relay, so when enabled,
+/// the relays between the node and this server are trusted for its reports.
+fn write_verified_runlog(
+    path: &ReceivedFile,
+    verified: &Path,
+    run_info: &RunInfo,
+    job_config: &Arc<JobConfig>,
+) -> Result<(), Error> {
+    let _timer = job_config.metrics.timer(Step::Signature);
+    decompress_file(&path, verified)?;
+    let content = fs::read(verified)?;
+    let allowed_digests = &job_config.cfg().processing.reporting.allowed_digests;
+    let nodes = job_config.nodes.read().expect("read nodes");
+    let certs = nodes
+        .certs(&run_info.node_id)
+        .ok_or_else(|| Error::MissingCertificateForNode(run_info.node_id.clone()))?;
+
+    let signed_runlog = signature(&content, certs, allowed_digests).or_else(|e| {
+        if !job_config.cfg().processing.reporting.trust_relay_signatures {
+            return Err(e);
+        }
+        nodes
+            .relays(&run_info.node_id)
+            .iter()
+            .filter_map(|relay| nodes.certs(relay))
+            .find_map(|certs| signature(&content, certs, allowed_digests).ok())
+            .ok_or(e)
+    })?;
+    drop(content);
+    fs::write(verified, signed_runlog)?;
+    Ok(())
+}
+
+/// Returns the run log to forward instead of the received file when it was
+/// modified. It is signed by this relay as the node signature does not
+/// match anymore.
+fn check_report_inner(
+    path: &ReceivedFile,
+    run_info: &RunInfo,
+    job_config: &Arc<JobConfig>,
+    check: ReportCheck,
+) -> Result<Option<Vec<u8>>, Error> {
+    debug!("Checking {:#?} before forwarding", path);
+
+    let cfg = job_config.cfg();
+    // Modifications need the parsed run log
+    if cfg.processing.reporting.modifies_runlogs() {
+        let (runlog, modified) = parsed_runlog(path, run_info, job_config)?;
+        if !modified {
+            return Ok(None);
+        }
+        let signing_key = job_config
+            .signing_key()
+            .ok_or(Error::MissingSigningCertificate)?;
+        return signed_runlog(&runlog.agent_output(), &signing_key).map(Some);
+    }
+
+    match check {
+        ReportCheck::Disabled => (),
+        ReportCheck::Signature => {
+            verified_runlog(path, run_info, job_config, |_| Ok(()))?;
+        }
+        ReportCheck::RunLog => {
+            verified_runlog(path, run_info, job_config, |verified| {
+                let _timer = job_config.metrics.timer(Step::Parse);
+                RunLog::from_reader(
+                    run_info.clone(),
+                    BufReader::new(File::open(verified)?),
+                    cfg.processing.reporting.validation,
+                )
+            })?;
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,407 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{
+    configuration::main::RetryConfig,
+    error::Error,
+    processing::{failed::FailureReason, failure, ReceivedFile, RootDirectory},
+    stats::Event,
+};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use futures::{
+    future::{lazy, Future},
+    stream::{iter_ok, Stream},
+    sync::mpsc,
+};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tokio::{fs::remove_file, prelude::*, timer::Interval};
+use tracing::{debug, error, info, span, warn, Level};
+
+/// Directory containing files waiting for a new attempt
+pub type RetryDirectory = PathBuf;
+
+/// Extension of the files storing the retry state, placed next to the retried file
+const STATE_EXTENSION: &str = "retry";
+
+/// Files failing with a transient error are moved into a dedicated directory
+/// for each watched directory (`retry/incoming` for `incoming`), along with their
+/// retry state. They are sent back to processing once their next retry time is reached.
+pub fn retry_directory(directory: &RootDirectory, watched: &str) -> RetryDirectory {
+    directory.join("retry").join(watched)
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct RetryState {
+    pub attempts: u32,
+    pub first_failure: DateTime<Utc>,
+    pub next_retry: DateTime<Utc>,
+}
+
+impl RetryState {
+    /// Computes the state after a new failure
+    ///
+    /// `jitter` is the fraction of the backoff delay to actually wait, between 0 and 1
+    fn next(previous: Option<Self>, cfg: &RetryConfig, now: DateTime<Utc>, jitter: f64) -> Self {
+        let (attempts, first_failure) = match previous {
+            Some(state) => (state.attempts.saturating_add(1), state.first_failure),
+            None => (1, now),
+        };
+        let delay = backoff(cfg, attempts).as_secs() as f64 * jitter;
+        Self {
+            attempts,
+            first_failure,
+            next_retry: now + ChronoDuration::seconds(delay as i64),
+        }
+    }
+
+    fn is_expired(&self, cfg: &RetryConfig, now: DateTime<Utc>) -> bool {
+        self.attempts >= cfg.max_attempts
+            || now - self.first_failure >= ChronoDuration::seconds(cfg.max_age as i64)
+    }
+
+    fn read(path: &Path) -> Result<Option<Self>, Error> {
+        match fs::read(path) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, path: &Path) -> Result<(), Error> {
+        fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+}
+
+/// Exponential backoff, without jitter
+fn backoff(cfg: &RetryConfig, attempts: u32) -> Duration {
+    let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+    Duration::from_secs(cfg.initial_delay.saturating_mul(factor).min(cfg.max_delay))
+}
+
+fn state_path(file: &Path) -> PathBuf {
+    let mut name = file.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".");
+    name.push(STATE_EXTENSION);
+    file.with_file_name(name)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Scheduled(RetryState),
+    Expired(RetryState),
+}
+
+/// Updates the retry state of the file and moves it into the retry queue
+fn postpone(
+    file: &Path,
+    queue: &Path,
+    cfg: &RetryConfig,
+    now: DateTime<Utc>,
+    jitter: f64,
+) -> Result<Outcome, Error> {
+    let queued_file = queue.join(
+        file.file_name()
+            .ok_or_else(|| Error::InvalidFile(file.to_path_buf()))?,
+    );
+    let state_file = state_path(&queued_file);
+
+    let state = RetryState::next(RetryState::read(&state_file)?, cfg, now, jitter);
+    if state.is_expired(cfg, now) {
+        match fs::remove_file(&state_file) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            res => res?,
+        }
+        return Ok(Outcome::Expired(state));
+    }
+
+    fs::create_dir_all(queue)?;
+    state.write(&state_file)?;
+    if file != queued_file.as_path() {
+        fs::rename(file, &queued_file)?;
+    }
+    Ok(Outcome::Scheduled(state))
+}
+
+/// Schedules a new attempt for a file that failed with a transient error,
+/// or gives up and moves it to the failed directory
+pub fn retry(
+    file: ReceivedFile,
+    reason: FailureReason,
+    queue: RetryDirectory,
+    directory: RootDirectory,
+    cfg: RetryConfig,
+    event: Event,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    Box::new(lazy(move || {
+        let jitter = thread_rng().gen_range(0.5, 1.0);
+        match postpone(&file, &queue, &cfg, Utc::now(), jitter) {
+            Ok(Outcome::Scheduled(state)) => {
+                info!(
+                    "transient error, attempt {} failed, next retry at {}",
+                    state.attempts, state.next_retry
+                );
+                // Hack for easier chaining
+                Box::new(futures::future::err::<(), ()>(()))
+            }
+            Ok(Outcome::Expired(state)) => {
+                error!(
+                    "transient error, giving up after {} attempts since {}",
+                    state.attempts, state.first_failure
+                );
+                let reason = FailureReason {
+                    attempts: state.attempts,
+                    ..reason
+                };
+                failure(file, directory, reason, event, stats)
+            }
+            Err(e) => {
+                error!("could not schedule retry: {}", e);
+                Box::new(futures::future::err::<(), ()>(()))
+            }
+        }
+    }))
+}
+
+/// Number of processing attempts of a file, including the current one
+pub fn attempts(file: &Path) -> u32 {
+    match RetryState::read(&state_path(file)) {
+        Ok(Some(state)) => state.attempts.saturating_add(1),
+        _ => 1,
+    }
+}
+
+/// Removes the retry state of a file, if any
+pub fn forget(file: &Path) -> impl Future<Item = (), Error = ()> {
+    remove_file(state_path(file)).then(|res| {
+        match res {
+            Err(ref e) if e.kind() != io::ErrorKind::NotFound => {
+                error!("could not remove retry state: {}", e)
+            }
+            _ => (),
+        }
+        Ok(())
+    })
+}
+
+/// Files from the queue that should be processed again
+///
+/// Their next retry is postponed by `lease` before they are returned, so that they
+/// are not sent again while being processed. Processing replaces the state (new
+/// failure) or removes it (success or give up), and the lease only expires if it
+/// never finished, for example if the service was stopped.
+fn due_files(
+    queue: &Path,
+    now: DateTime<Utc>,
+    lease: ChronoDuration,
+) -> Result<Vec<ReceivedFile>, Error> {
+    let mut res = vec![];
+    for entry in fs::read_dir(queue)? {
+        let state_file = entry?.path();
+        if state_file
+            .extension()
+            .map(|e| e != STATE_EXTENSION)
+            .unwrap_or(true)
+        {
+            continue;
+        }
+        let file = state_file.with_extension("");
+        match RetryState::read(&state_file) {
+            Ok(Some(state)) if state.next_retry <= now && file.exists() => {
+                let state = RetryState {
+                    next_retry: now + lease,
+                    ..state
+                };
+                match state.write(&state_file) {
+                    Ok(()) => res.push(file),
+                    Err(e) => warn!("could not update retry state {:?}: {}", state_file, e),
+                }
+            }
+            Ok(_) => (),
+            Err(e) => warn!("invalid retry state {:?}: {}", state_file, e),
+        }
+    }
+    Ok(res)
+}
+
+/// Number of files waiting for a new attempt
+pub fn queue_depth(queue: &Path) -> Result<usize, Error> {
+    let mut depth = 0;
+    for entry in fs::read_dir(queue)? {
+        if entry?
+            .path()
+            .extension()
+            .map(|e| e == STATE_EXTENSION)
+            .unwrap_or(false)
+        {
+            depth += 1;
+        }
+    }
+    Ok(depth)
+}
+
+/// Periodically sends due files from the queue back to processing
+///
+/// Files not done processing after `max_delay` are sent again.
+pub fn schedule(
+    queue: RetryDirectory,
+    frequency: u64,
+    cfg: RetryConfig,
+    tx: mpsc::Sender<ReceivedFile>,
+) -> impl Future<Item = (), Error = ()> {
+    info!("Starting retry scheduler on {:#?}", &queue);
+    let span = span!(Level::TRACE, "retry");
+    let _enter = span.enter();
+
+    Interval::new(Instant::now(), Duration::from_secs(frequency))
+        .map_err(|e| warn!("interval error: {}", e))
+        .for_each(move |_instant| {
+            let tx = tx.clone();
+            let lease = ChronoDuration::seconds(cfg.max_delay as i64);
+            let files = due_files(&queue, Utc::now(), lease).unwrap_or_else(|e| {
+                warn!("retry list error: {}", e);
+                vec![]
+            });
+            iter_ok(files).for_each(move |file| {
+                debug!("retry: {:?}", file);
+                tx.clone()
+                    .send(file)
+                    .map_err(|e| warn!("retry send error: {}", e))
+                    .map(|_| ())
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn cfg() -> RetryConfig {
+        RetryConfig {
+            initial_delay: 60,
+            max_delay: 3600,
+            max_attempts: 5,
+            max_age: 86400,
+        }
+    }
+
+    #[test]
+    fn it_computes_backoff() {
+        assert_eq!(backoff(&cfg(), 1), Duration::from_secs(60));
+        assert_eq!(backoff(&cfg(), 2), Duration::from_secs(120));
+        assert_eq!(backoff(&cfg(), 4), Duration::from_secs(480));
+        assert_eq!(backoff(&cfg(), 7), Duration::from_secs(3600));
+        assert_eq!(backoff(&cfg(), 200), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn it_computes_next_state() {
+        let now = Utc::now();
+        let first = RetryState::next(None, &cfg(), now, 1.0);
+        assert_eq!(
+            first,
+            RetryState {
+                attempts: 1,
+                first_failure: now,
+                next_retry: now + ChronoDuration::seconds(60),
+            }
+        );
+        let later = now + ChronoDuration::seconds(60);
+        let second = RetryState::next(Some(first), &cfg(), later, 0.5);
+        assert_eq!(
+            second,
+            RetryState {
+                attempts: 2,
+                first_failure: now,
+                next_retry: later + ChronoDuration::seconds(60),
+            }
+        );
+        assert!(!second.is_expired(&cfg(), later));
+        assert!(second.is_expired(&cfg(), now + ChronoDuration::days(1)));
+    }
+
+    #[test]
+    fn it_queues_files() {
+        let dir = tempdir().unwrap();
+        let queue = dir.path().join("retry");
+        let file = dir.path().join("2019-01-24T15:55:01+00:00@root.log");
+        let queued = queue.join("2019-01-24T15:55:01+00:00@root.log");
+        fs::write(&file, "report").unwrap();
+        let now = Utc::now();
+
+        let mut outcome = postpone(&file, &queue, &cfg(), now, 1.0).unwrap();
+        assert!(!file.exists());
+        assert!(queued.exists());
+        assert_eq!(queue_depth(&queue).unwrap(), 1);
+        let lease = ChronoDuration::seconds(3600);
+        assert!(due_files(&queue, now, lease).unwrap().is_empty());
+        let later = now + ChronoDuration::seconds(60);
+        assert_eq!(
+            due_files(&queue, later, lease).unwrap(),
+            vec![queued.clone()]
+        );
+        // Not sent again while being processed
+        assert!(due_files(&queue, later, lease).unwrap().is_empty());
+        assert!(
+            due_files(&queue, later + ChronoDuration::seconds(60), lease)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            due_files(&queue, later + lease, lease).unwrap(),
+            vec![queued.clone()]
+        );
+        assert_eq!(
+            RetryState::read(&state_path(&queued)).unwrap().unwrap(),
+            RetryState {
+                attempts: 1,
+                first_failure: now,
+                next_retry: later + lease + lease,
+            }
+        );
+
+        let mut attempts = 1;
+        while let Outcome::Scheduled(_) = outcome {
+            outcome = postpone(&queued, &queue, &cfg(), now, 1.0).unwrap();
+            attempts += 1;
+        }
+        assert_eq!(attempts, 5);
+        assert_eq!(queue_depth(&queue).unwrap(), 0);
+    }
+}
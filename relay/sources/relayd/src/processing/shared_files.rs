@@ -0,0 +1,226 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{data::shared_file::Metadata, error::Error, JobConfig};
+use chrono::{DateTime, Utc};
+use futures::future::{loop_fn, poll_fn, Future, Loop};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
+use tokio_threadpool::blocking;
+use tracing::{debug, info, span, warn, Level};
+
+const METADATA_EXTENSION: &str = "metadata";
+
+pub fn start(job_config: &Arc<JobConfig>) {
+    let span = span!(Level::TRACE, "shared_files");
+    let _enter = span.enter();
+
+    tokio::spawn(job_config.shutdown.until(cleanup(job_config.clone())));
+}
+
+/// Periodically removes expired shared files
+fn cleanup(job_config: Arc<JobConfig>) -> impl Future<Item = (), Error = ()> {
+    loop_fn((), move |_| {
+        let job_config = job_config.clone();
+        // Read at each iteration to apply configuration reloads
+        let frequency = job_config.cfg().shared_files.cleanup_frequency;
+
+        Delay::new(Instant::now() + Duration::from_secs(frequency))
+            .map_err(|e| warn!("timer error: {}", e))
+            .and_then(move |_| {
+                let path = job_config.cfg().shared_files.path.clone();
+                poll_fn(move || {
+                    blocking(|| remove_expired(&path, Utc::now()))
+                        .map_err(|_| panic!("the thread pool shut down"))
+                })
+                .map(move |res| match res {
+                    Ok(removed) => {
+                        job_config.metrics.shared_files_expired(removed);
+                        debug!("removed {} expired shared files", removed);
+                    }
+                    Err(e) => warn!("shared files cleanup error: {}", e),
+                })
+            })
+            .map(|_| Loop::<(), ()>::Continue(()))
+    })
+}
+
+/// Removes a file, succeeding if it does not exist
+pub fn remove_file(path: &Path) -> Result<(), Error> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+/// Lists the entries of a directory, skipping the ones that cannot be read
+/// as they may have been removed concurrently.
+fn entries(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    Ok(fs::read_dir(path)?
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry.path()),
+            Err(e) => {
+                warn!("skipping entry in {:?}: {}", path, e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Lists the entries of a subdirectory, skipping it if it cannot be read
+fn sub_entries(path: &Path) -> Vec<PathBuf> {
+    match entries(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("skipping {:?}: {}", path, e);
+            vec![]
+        }
+    }
+}
+
+/// Removes expired shared files along with their metadata, returns the number
+/// of removed files.
+///
+/// Files are stored in `<path>/<target_id>/<source_id>/<file_id>`.
+///
+/// Errors on individual entries are logged and skipped to avoid blocking the whole cleanup.
+fn remove_expired(path: &Path, now: DateTime<Utc>) -> Result<u64, Error> {
+    let mut removed = 0;
+    for target in entries(path)?.iter().filter(|e| e.is_dir()) {
+        for source in sub_entries(target).iter().filter(|e| e.is_dir()) {
+            for metadata in sub_entries(source) {
+                if metadata
+                    .extension()
+                    .map(|e| e != METADATA_EXTENSION)
+                    .unwrap_or(true)
+                {
+                    continue;
+                }
+
+                let expires = match fs::read_to_string(&metadata)
+                    .map_err(Error::from)
+                    .and_then(|m| Metadata::parse_expires(&m))
+                {
+                    Ok(expires) => expires,
+                    Err(e) => {
+                        warn!("skipping {:?}: {}", metadata, e);
+                        continue;
+                    }
+                };
+                if expires <= now {
+                    let file = metadata.with_extension("");
+                    match remove_file(&file).and_then(|_| remove_file(&metadata)) {
+                        Ok(()) => {
+                            info!("removed {:?} as it expired on {}", file, expires);
+                            removed += 1;
+                        }
+                        Err(e) => warn!("could not remove expired {:?}: {}", file, e),
+                    }
+                }
+            }
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use tempfile::tempdir;
+
+    fn share(path: &Path, file_id: &str, expires: Option<DateTime<Utc>>) {
+        let source = path
+            .join("37817c4d-fbf7-4850-a985-50021f4e8f41")
+            .join("c745a140-40bc-4b86-b6dc-084488fc906b");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join(file_id), "content").unwrap();
+        fs::write(
+            source.join(format!("{}.metadata", file_id)),
+            format!(
+                "header=rudder-signature-v1\nkeyid=B29D02BB\n{}",
+                expires.map(Metadata::expires_line).unwrap_or_default()
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn it_removes_expired_files() {
+        let dir = tempdir().unwrap();
+        let expires = Utc.ymd(2019, 8, 16).and_hms(13, 47, 41);
+        share(dir.path(), "file1", Some(expires));
+        share(
+            dir.path(),
+            "file2",
+            Some(expires + chrono::Duration::days(1)),
+        );
+        share(dir.path(), "file3", None);
+        let source = dir
+            .path()
+            .join("37817c4d-fbf7-4850-a985-50021f4e8f41")
+            .join("c745a140-40bc-4b86-b6dc-084488fc906b");
+
+        assert_eq!(
+            remove_expired(dir.path(), expires - chrono::Duration::hours(1)).unwrap(),
+            0
+        );
+        assert_eq!(remove_expired(dir.path(), expires).unwrap(), 1);
+        assert!(!source.join("file1").exists());
+        assert!(!source.join("file1.metadata").exists());
+        assert!(source.join("file2").exists());
+        // Files without expiration date are kept
+        assert_eq!(remove_expired(dir.path(), Utc::now()).unwrap(), 1);
+        assert!(source.join("file3").exists());
+        assert!(source.join("file3.metadata").exists());
+    }
+
+    #[test]
+    fn it_skips_unreadable_entries() {
+        let dir = tempdir().unwrap();
+        let expires = Utc.ymd(2019, 8, 16).and_hms(13, 47, 41);
+        share(dir.path(), "file1", Some(expires));
+        let source = dir
+            .path()
+            .join("37817c4d-fbf7-4850-a985-50021f4e8f41")
+            .join("c745a140-40bc-4b86-b6dc-084488fc906b");
+        // Cannot be read as a file
+        fs::create_dir(source.join("file0.metadata")).unwrap();
+
+        assert_eq!(remove_expired(dir.path(), expires).unwrap(), 1);
+        assert!(!source.join("file1").exists());
+        assert!(source.join("file0.metadata").exists());
+    }
+}
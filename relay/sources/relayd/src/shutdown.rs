@@ -0,0 +1,196 @@
+// Copyright 2019 Normation SAS
+//
+// This file is part of Rudder.
+//
+// Rudder is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In accordance with the terms of section 7 (7. Additional Terms.) of
+// the GNU General Public License version 3, the copyright holders add
+// the following Additional permissions:
+// Notwithstanding to the terms of section 5 (5. Conveying Modified Source
+// Versions) and 6 (6. Conveying Non-Source Forms.) of the GNU General
+// Public License version 3, when you create a Related Module, this
+// Related Module is not considered as a part of the work and may be
+// distributed under the license agreement of your choice.
+// A "Related Module" means a set of sources files including their
+// documentation that, without modification of the Source Code, enables
+// supplementary functions or services in addition to those offered by
+// the Software.
+//
+// Rudder is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
+
+use futures::{
+    future::{Future, Shared},
+    sync::oneshot,
+    Stream,
+};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::timer::{Delay, Interval};
+use tracing::{debug, info, warn};
+
+// Shutdown happens in two steps:
+//
+// * The signal is triggered, which stops all input sources (file watchers, catch-up
+// listing and API server). Once they are stopped, no new work can enter the program.
+// * We wait for all tracked tasks (queued and in-flight files, pending API requests)
+// to complete, up to a configurable deadline.
+
+/// Interval between two checks of running tasks during drain
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Shutdown {
+    trigger: Mutex<Option<oneshot::Sender<()>>>,
+    signal: Shared<oneshot::Receiver<()>>,
+    tasks: Arc<AtomicUsize>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (trigger, signal) = oneshot::channel();
+        Self {
+            trigger: Mutex::new(Some(trigger)),
+            signal: signal.shared(),
+            tasks: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Future resolving once shutdown has been requested
+    pub fn signal(&self) -> impl Future<Item = (), Error = ()> + Send {
+        self.signal.clone().map(|_| ()).map_err(|_| ())
+    }
+
+    /// Stops the given future when shutdown is requested
+    pub fn until<F>(&self, future: F) -> impl Future<Item = (), Error = ()> + Send
+    where
+        F: Future<Item = (), Error = ()> + Send,
+    {
+        future.select(self.signal()).map(|_| ()).map_err(|_| ())
+    }
+
+    /// Makes shutdown wait for the given future completion
+    pub fn track<F>(&self, future: F) -> impl Future<Item = (), Error = ()> + Send
+    where
+        F: Future<Item = (), Error = ()> + Send,
+    {
+        let guard = TaskGuard::new(self.tasks.clone());
+        future.then(move |res| {
+            drop(guard);
+            res
+        })
+    }
+
+    pub fn running_tasks(&self) -> usize {
+        self.tasks.load(Ordering::SeqCst)
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.trigger
+            .lock()
+            .expect("could not lock shutdown")
+            .is_none()
+    }
+
+    /// Stops inputs and waits for running tasks to end, or for the timeout to expire
+    pub fn drain(&self, timeout: Duration) -> impl Future<Item = (), Error = ()> + Send {
+        if let Some(trigger) = self.trigger.lock().expect("could not lock shutdown").take() {
+            // The receiver is owned by ourselves, it cannot be dropped
+            let _ = trigger.send(());
+        }
+        info!(
+            "Waiting for {} running tasks to complete (timeout: {}s)",
+            self.running_tasks(),
+            timeout.as_secs()
+        );
+
+        let tasks = self.tasks.clone();
+        let drained = Interval::new(Instant::now(), DRAIN_POLL_INTERVAL)
+            .map_err(|e| warn!("interval error: {}", e))
+            .take_while(move |_| {
+                let running = tasks.load(Ordering::SeqCst);
+                debug!("{} tasks still running", running);
+                Ok(running > 0)
+            })
+            .for_each(|_| Ok(()))
+            .map(|_| info!("All tasks completed"));
+
+        let tasks = self.tasks.clone();
+        let deadline = Delay::new(Instant::now() + timeout)
+            .map_err(|e| warn!("timer error: {}", e))
+            .map(move |_| {
+                warn!(
+                    "Shutdown timeout reached, interrupting {} running tasks",
+                    tasks.load(Ordering::SeqCst)
+                )
+            });
+
+        drained.select(deadline).map(|_| ()).map_err(|_| ())
+    }
+}
+
+/// Counts a running task until dropped
+struct TaskGuard {
+    tasks: Arc<AtomicUsize>,
+}
+
+impl TaskGuard {
+    fn new(tasks: Arc<AtomicUsize>) -> Self {
+        let _ = tasks.fetch_add(1, Ordering::SeqCst);
+        Self { tasks }
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        let _ = self.tasks.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    #[test]
+    fn it_tracks_tasks() {
+        let shutdown = Shutdown::default();
+        let task = shutdown.track(future::ok(()));
+        assert_eq!(shutdown.running_tasks(), 1);
+        task.wait().unwrap();
+        assert_eq!(shutdown.running_tasks(), 0);
+
+        let task = shutdown.track(future::err(()));
+        assert_eq!(shutdown.running_tasks(), 1);
+        drop(task);
+        assert_eq!(shutdown.running_tasks(), 0);
+    }
+
+    #[test]
+    fn it_stops_on_signal() {
+        let shutdown = Shutdown::default();
+        assert!(!shutdown.is_requested());
+        let stopped = shutdown.until(future::empty());
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(shutdown.drain(Duration::from_secs(1)))
+            .unwrap();
+        assert!(shutdown.is_requested());
+        runtime.block_on(stopped).unwrap();
+    }
+}
@@ -28,6 +28,7 @@
 // You should have received a copy of the GNU General Public License
 // along with Rudder.  If not, see <http://www.gnu.org/licenses/>.
 
+use crate::metrics::Metrics;
 use futures::{stream::Stream, sync::mpsc, Future};
 use serde::{Deserialize, Serialize};
 use std::sync::{Arc, RwLock};
@@ -70,6 +71,7 @@ impl Stats {
 
     pub fn receiver(
         stats: Arc<RwLock<Self>>,
+        metrics: Metrics,
         rx: mpsc::Receiver<Event>,
     ) -> impl Future<Item = (), Error = ()> {
         rx.for_each(move |event| {
@@ -77,6 +79,7 @@ impl Stats {
                 .write()
                 .expect("could not write lock stats")
                 .event(event);
+            metrics.event(event);
             trace!("Received stat event: {:?}", event);
             Ok(())
         })
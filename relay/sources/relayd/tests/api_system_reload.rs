@@ -57,9 +57,10 @@ mod tests {
         )
         .unwrap();
 
-        let reference: serde_json::Value =
-            serde_json::from_str("{\"result\":\"success\",\"action\":\"reloadConfiguration\"}")
-                .unwrap();
+        let reference: serde_json::Value = serde_json::from_str(
+            "{\"data\":{\"restart-required\":[]},\"result\":\"success\",\"action\":\"reloadConfiguration\"}",
+        )
+        .unwrap();
 
         assert_eq!(reference, response);
     }
@@ -136,4 +136,14 @@ fn it_reads_and_inserts_a_runlog() {
         inventory_sent: 0,
     };
     assert_eq!(reference, answer);
+
+    let metrics = reqwest::get("http://localhost:3030/metrics")
+        .unwrap()
+        .text()
+        .unwrap();
+    assert!(metrics.contains("rudder_relayd_reports_total{status=\"received\"} 4"));
+    assert!(metrics.contains("rudder_relayd_reports_total{status=\"inserted\"} 2"));
+    assert!(
+        metrics.contains("rudder_relayd_directory_files{directory=\"failed\",type=\"reports\"} 2")
+    );
 }
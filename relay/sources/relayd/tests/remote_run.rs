@@ -154,6 +154,42 @@ mod tests {
             read_to_string("target/tmp/api_test.txt").unwrap()
         );
 
+        // Job results
+
+        let job_id = response
+            .headers()
+            .get("X-Rudder-Remote-Run-Job-Id")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let job: serde_json::Value = serde_json::from_str(
+            &client
+                .get(&format!(
+                    "http://localhost:3030/rudder/relay-api/1/remote-run/jobs/{}",
+                    job_id
+                ))
+                .send()
+                .unwrap()
+                .text()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(job["data"]["status"], "finished");
+        assert_eq!(job["data"]["runs"][0]["nodes"][0], "server.rudder.local");
+        assert_eq!(job["data"]["runs"][0]["output"], "OK\nEND\n");
+        assert_eq!(job["data"]["runs"][0]["exit_code"], 0);
+        assert_eq!(
+            job["data"]["runs"][0]["http_status"],
+            serde_json::Value::Null
+        );
+
+        let response = client
+            .get("http://localhost:3030/rudder/relay-api/1/remote-run/jobs/unknown")
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::NOT_FOUND);
+
         // Sync & no keep
 
         let _ = remove_file("target/tmp/api_test.txt");
@@ -189,5 +225,39 @@ mod tests {
             .send();
 
         assert_eq!(res.unwrap().text().unwrap(), "Unhandled rejection: invalid condition: clas~1, should match ^[a-zA-Z0-9][a-zA-Z0-9_]*$".to_string());
+
+        // Cancellation
+
+        let params_async = [
+            ("asynchronous", "true"),
+            ("keep_output", "false"),
+            ("classes", "class2,class7"),
+            ("nodes", "root"),
+        ];
+        let response = client
+            .post("http://localhost:3030/rudder/relay-api/1/remote-run/nodes")
+            .form(&params_async)
+            .send()
+            .unwrap();
+        let job_id = response
+            .headers()
+            .get("X-Rudder-Remote-Run-Job-Id")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let job: serde_json::Value = serde_json::from_str(
+            &client
+                .delete(&format!(
+                    "http://localhost:3030/rudder/relay-api/1/remote-run/jobs/{}",
+                    job_id
+                ))
+                .send()
+                .unwrap()
+                .text()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(job["data"]["status"], "cancelled");
     }
 }
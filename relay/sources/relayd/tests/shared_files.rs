@@ -72,5 +72,38 @@ mod tests {
             .unwrap();
 
         assert_eq!(404, no_hash_sent.status());
+
+        let mut shared_file = client
+            .get("http://127.0.0.1:3030/rudder/relay-api/1/shared-files/37817c4d-fbf7-4850-a985-50021f4e8f41/37817c4d-fbf7-4850-a985-50021f4e8f41/file5")
+            .header("X-Rudder-Node-Id", "37817c4d-fbf7-4850-a985-50021f4e8f41")
+            .send()
+            .unwrap();
+
+        assert_eq!(200, shared_file.status());
+        assert_eq!(
+            "sha512",
+            shared_file.headers()["X-Rudder-Algorithm"].to_str().unwrap()
+        );
+        assert_eq!(
+            "4102444800",
+            shared_file.headers()["X-Rudder-Expires"].to_str().unwrap()
+        );
+        assert_eq!("shared file content\n", shared_file.text().unwrap());
+
+        let other_node = client
+            .get("http://127.0.0.1:3030/rudder/relay-api/1/shared-files/37817c4d-fbf7-4850-a985-50021f4e8f41/37817c4d-fbf7-4850-a985-50021f4e8f41/file5")
+            .header("X-Rudder-Node-Id", "c745a140-40bc-4b86-b6dc-084488fc906b")
+            .send()
+            .unwrap();
+
+        assert_eq!(404, other_node.status());
+
+        let expired = client
+            .get("http://127.0.0.1:3030/rudder/relay-api/1/shared-files/37817c4d-fbf7-4850-a985-50021f4e8f41/37817c4d-fbf7-4850-a985-50021f4e8f41/file4")
+            .header("X-Rudder-Node-Id", "37817c4d-fbf7-4850-a985-50021f4e8f41")
+            .send()
+            .unwrap();
+
+        assert_eq!(404, expired.status());
     }
 }
@@ -5,7 +5,12 @@ create user rudderreports with encrypted password 'PASSWORD';
 grant usage on sequence serial to rudderreports;
 grant select on table ruddersysevents to rudderreports;
 grant insert on table ruddersysevents to rudderreports;
+grant select, insert, update on table nodes_last_run to rudderreports;
+grant usage on sequence agent_logs_id_seq to rudderreports;
+grant select, insert on table agent_logs to rudderreports;
 /* only for test databases
 grant delete on table ruddersysevents to rudderreports;
 grant truncate on table ruddersysevents to rudderreports;
+grant delete on table nodes_last_run to rudderreports;
+grant delete on table agent_logs to rudderreports;
 */
@@ -130,6 +130,42 @@ CREATE INDEX reportsexecution_nodeid_nodeconfigid_idx ON ReportsExecution (nodeI
 
 ALTER TABLE reportsexecution set (autovacuum_vacuum_scale_factor = 0.05);
 
+/*
+ * Last run of each node, updated by relayd in the same transaction
+ * as the insertion of its reports. It allows knowing when nodes were
+ * last seen without scanning RudderSysEvents.
+ */
+CREATE TABLE nodes_last_run (
+  node_id        text PRIMARY KEY CHECK (node_id <> '')
+, run_timestamp  timestamp with time zone NOT NULL
+, serial         integer NOT NULL
+, node_config_id text
+, insertion_date timestamp with time zone NOT NULL
+);
+
+CREATE INDEX nodes_last_run_run_timestamp_idx ON nodes_last_run (run_timestamp);
+
+/*
+ * Logs written by the agent during a run (log_info, log_warn, log_debug),
+ * inserted by relayd along with the reports of the run. Each log is linked
+ * to the report it precedes in the run log.
+ */
+CREATE TABLE agent_logs (
+  id                  bigserial PRIMARY KEY
+, execution_timestamp timestamp with time zone NOT NULL
+, rule_id             text NOT NULL
+, directive_id        text NOT NULL
+, component           text NOT NULL
+, key_value           text NOT NULL
+, serial              integer NOT NULL
+, node_id             text NOT NULL CHECK (node_id <> '')
+, event_type          text NOT NULL
+, msg                 text NOT NULL
+, execution_date      timestamp with time zone NOT NULL
+);
+
+CREATE INDEX agent_logs_node_id_execution_timestamp_idx ON agent_logs (node_id, execution_timestamp);
+
 /*
  *************************************************************************************
  * The following tables store what Rudder expects from agent.